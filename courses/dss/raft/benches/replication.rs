@@ -0,0 +1,105 @@
+use std::thread;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::executor::block_on;
+use futures::StreamExt;
+use prost_derive::Message;
+
+use labrpc::{Network, ServerBuilder};
+use raft::raft::persister::SimplePersister;
+use raft::raft::storage::{PersisterStorage, RaftLogStorage, WalStorage};
+use raft::raft::{add_raft_service, ApplyMsg, Node, Raft, RaftClient, RaftConfig};
+
+const SERVERS: usize = 3;
+const BATCH: u64 = 100;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Command {
+    #[prost(uint64, tag = "1")]
+    pub x: u64,
+}
+
+// Starts a reliable cluster whose peers keep their logs in `storage(i)`, and
+// returns its nodes with their apply channels, and the index of the leader.
+fn start_cluster(
+    net: &Network,
+    storage: impl Fn(usize) -> Box<dyn RaftLogStorage>,
+) -> (Vec<(Node, UnboundedReceiver<ApplyMsg>)>, usize) {
+    let mut nodes = Vec::with_capacity(SERVERS);
+    for i in 0..SERVERS {
+        let mut clients = Vec::with_capacity(SERVERS);
+        for j in 0..SERVERS {
+            let name = format!("{}-{}", i, j);
+            clients.push(RaftClient::new(net.create_client(name.clone())));
+            net.connect(&name, &format!("{}", j));
+            net.enable(&name, true);
+        }
+        let (tx, apply_ch) = unbounded();
+        let rf = Raft::with_storage(clients, i, storage(i), tx, RaftConfig::default());
+        let node = Node::new(rf);
+        let mut builder = ServerBuilder::new(format!("{}", i));
+        add_raft_service(node.clone(), &mut builder).unwrap();
+        net.add_server(builder.build());
+        nodes.push((node, apply_ch));
+    }
+    loop {
+        if let Some(i) = nodes.iter().position(|(node, _)| node.is_leader()) {
+            return (nodes, i);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Proposes batches of commands to the leader of a new cluster, and waits for
+// each batch to be applied.
+fn bench_propose(
+    c: &mut Criterion,
+    name: &str,
+    storage: impl Fn(usize) -> Box<dyn RaftLogStorage>,
+) {
+    let net = Network::new();
+    let (mut nodes, leader) = start_cluster(&net, storage);
+    let (leader, apply_ch) = &mut nodes[leader];
+
+    let mut group = c.benchmark_group("replication");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function(name, |b| {
+        let mut x = 0;
+        b.iter(|| {
+            let mut last = 0;
+            for _ in 0..BATCH {
+                x += 1;
+                last = leader.start(&Command { x }).unwrap().0;
+            }
+            block_on(async {
+                while let Some(msg) = apply_ch.next().await {
+                    match msg {
+                        ApplyMsg::Command { index, .. } if index >= last => break,
+                        _ => {}
+                    }
+                }
+            });
+        })
+    });
+    group.finish();
+    for (node, _) in &nodes {
+        node.kill();
+    }
+}
+
+fn bench_replication(c: &mut Criterion) {
+    bench_propose(c, "propose", |_| {
+        Box::new(PersisterStorage::new(Box::new(SimplePersister::new())))
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    bench_propose(c, "propose_wal", |i| {
+        Box::new(WalStorage::open(dir.path().join(i.to_string())).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_replication);
+criterion_main!(benches);
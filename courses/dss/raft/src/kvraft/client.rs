@@ -1,6 +1,32 @@
 use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-use crate::proto::kvraftpb::*;
+use futures::executor::ThreadPool;
+use futures::future::{self, Either};
+use futures_timer::Delay;
+use lazy_static::lazy_static;
+
+use crate::proto::kvraftpb::{self, *};
+
+// how long to wait for a reply, a dropped request may otherwise take seconds
+// to time out.
+const RPC_TIMEOUT: Duration = Duration::from_millis(400);
+// how long to wait after every server failed before trying them again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    // runs the RPCs of every clerk, as a clerk may itself be called on an
+    // executor. A clerk owns no thread, so nothing outlives its caller.
+    static ref EXECUTOR: ThreadPool = ThreadPool::builder()
+        .pool_size(1)
+        .name_prefix("clerk")
+        .create()
+        .unwrap();
+}
 
 enum Op {
     Put(String, String),
@@ -11,6 +37,12 @@ pub struct Clerk {
     pub name: String,
     pub servers: Vec<KvClient>,
     // You will have to modify this struct.
+    // identifies this clerk, so that the servers can detect retried requests.
+    client_id: u64,
+    // the seq of the last request.
+    seq: AtomicU64,
+    // the server which answered last, likely the leader.
+    leader: AtomicUsize,
 }
 
 impl fmt::Debug for Clerk {
@@ -22,8 +54,13 @@ impl fmt::Debug for Clerk {
 impl Clerk {
     pub fn new(name: String, servers: Vec<KvClient>) -> Clerk {
         // You'll have to add code here.
-        // Clerk { name, servers }
-        crate::your_code_here((name, servers))
+        Clerk {
+            name,
+            servers,
+            client_id: rand::random(),
+            seq: AtomicU64::new(0),
+            leader: AtomicUsize::new(0),
+        }
     }
 
     /// fetch the current value for a key.
@@ -33,8 +70,15 @@ impl Clerk {
     // you can send an RPC with code like this:
     // if let Some(reply) = self.servers[i].get(args).wait() { /* do something */ }
     pub fn get(&self, key: String) -> String {
-        // You will have to modify this function.
-        crate::your_code_here(key)
+        let args = GetRequest {
+            key,
+            client_id: self.client_id,
+            seq: self.seq.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+        self.call(|server| match self.wait(server.get(&args)) {
+            Some(Ok(reply)) if !reply.wrong_leader && reply.err.is_empty() => Some(reply.value),
+            _ => None,
+        })
     }
 
     /// shared by Put and Append.
@@ -42,8 +86,54 @@ impl Clerk {
     // you can send an RPC with code like this:
     // let reply = self.servers[i].put_append(args).unwrap();
     fn put_append(&self, op: Op) {
-        // You will have to modify this function.
-        crate::your_code_here(op)
+        let (op, key, value) = match op {
+            Op::Put(key, value) => (kvraftpb::Op::Put, key, value),
+            Op::Append(key, value) => (kvraftpb::Op::Append, key, value),
+        };
+        let args = PutAppendRequest {
+            key,
+            value,
+            op: op as i32,
+            client_id: self.client_id,
+            seq: self.seq.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+        self.call(|server| match self.wait(server.put_append(&args)) {
+            Some(Ok(reply)) if !reply.wrong_leader && reply.err.is_empty() => Some(()),
+            _ => None,
+        })
+    }
+
+    /// Sends a request to the servers, starting at the last known leader,
+    /// until one of them answers it.
+    fn call<T>(&self, send: impl Fn(&KvClient) -> Option<T>) -> T {
+        let mut i = self.leader.load(Ordering::SeqCst);
+        loop {
+            for _ in 0..self.servers.len() {
+                if let Some(reply) = send(&self.servers[i]) {
+                    self.leader.store(i, Ordering::SeqCst);
+                    return reply;
+                }
+                i = (i + 1) % self.servers.len();
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// Blocks until `fut` completes, or returns `None` after `RPC_TIMEOUT`.
+    fn wait<F>(&self, fut: F) -> Option<F::Output>
+    where
+        F: Future + Send + Unpin + 'static,
+        F::Output: Send,
+    {
+        let (tx, rx) = mpsc::channel();
+        EXECUTOR.spawn_ok(async move {
+            let res = match future::select(fut, Delay::new(RPC_TIMEOUT)).await {
+                Either::Left((res, _)) => Some(res),
+                Either::Right(_) => None,
+            };
+            let _ = tx.send(res);
+        });
+        rx.recv().expect("the RPC was dropped")
     }
 
     pub fn put(&self, key: String, value: String) {
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use rand::seq::SliceRandom;
@@ -9,8 +9,7 @@ use crate::kvraft::errors::{Error, Result};
 use crate::kvraft::{client, server};
 use crate::proto::kvraftpb::*;
 use crate::proto::raftpb::*;
-use crate::raft;
-use crate::raft::persister::*;
+use crate::raft::config::Saved;
 
 static ID: AtomicUsize = AtomicUsize::new(300_000);
 
@@ -20,7 +19,7 @@ fn uniqstring() -> String {
 
 struct Servers {
     kvservers: Vec<Option<server::Node>>,
-    saved: Vec<Arc<SimplePersister>>,
+    saved: Vec<Saved>,
     endnames: Vec<Vec<String>>,
 }
 
@@ -48,15 +47,19 @@ pub struct Config {
     rpcs0: AtomicUsize,
     // number of agreements
     ops: AtomicUsize,
+
+    // the directory the servers keep a `WalStorage` in.
+    _dir: tempfile::TempDir,
 }
 
 impl Config {
     pub fn new(n: usize, unreliable: bool, maxraftstate: Option<usize>) -> Config {
         init_logger();
 
+        let dir = tempfile::tempdir().unwrap();
         let servers = Servers {
             kvservers: vec![None; n],
-            saved: (0..n).map(|_| Arc::new(SimplePersister::new())).collect(),
+            saved: (0..n).map(|_| Saved::new(dir.path())).collect(),
             endnames: vec![vec![String::new(); n]; n],
         };
         let cfg = Config {
@@ -71,6 +74,7 @@ impl Config {
             t0: Mutex::new(Instant::now()),
             rpcs0: AtomicUsize::new(0),
             ops: AtomicUsize::new(0),
+            _dir: dir,
         };
 
         // create a full set of KV servers.
@@ -105,7 +109,7 @@ impl Config {
         let servers = self.servers.lock().unwrap();
         let mut logsize = 0;
         for save in &servers.saved {
-            let n = save.raft_state().len();
+            let n = save.raft_state_size();
             if n > logsize {
                 logsize = n;
             }
@@ -246,9 +250,7 @@ impl Config {
         // continues to update the Persister.
         // but copy old persister's content so that we always
         // pass Make() the last persisted state.
-        let p = raft::persister::SimplePersister::new();
-        p.save_state_and_snapshot(servers.saved[i].raft_state(), servers.saved[i].snapshot());
-        servers.saved[i] = Arc::new(p);
+        servers.saved[i] = servers.saved[i].copy();
 
         if let Some(kv) = servers.kvservers[i].take() {
             kv.kill();
@@ -275,12 +277,10 @@ impl Config {
         // give the fresh persister a copy of the old persister's
         // state, so that the spec is that we pass StartKVServer()
         // the last persisted state.
-        let sp = raft::persister::SimplePersister::new();
-        sp.save_state_and_snapshot(servers.saved[i].raft_state(), servers.saved[i].snapshot());
-        let p = Arc::new(sp);
-        servers.saved[i] = p.clone();
+        servers.saved[i] = servers.saved[i].copy();
 
-        let kv = server::KvServer::new(ends, i, Box::new(p), self.maxraftstate);
+        let storage = servers.saved[i].storage();
+        let kv = server::KvServer::with_storage(ends, i, storage, self.maxraftstate);
         let rf_node = kv.rf.clone();
         let kv_node = server::Node::new(kv);
         servers.kvservers[i] = Some(kv_node.clone());
@@ -3,6 +3,8 @@ use std::{error, fmt, result};
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
     NoLeader,
+    // the request was not applied in time, it may still be later.
+    Timeout,
 }
 
 impl fmt::Display for Error {
@@ -14,7 +16,7 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            Error::NoLeader => None,
+            Error::NoLeader | Error::Timeout => None,
         }
     }
 }
@@ -1,14 +1,60 @@
-use futures::channel::mpsc::unbounded;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use futures::future::{self, Either};
+use futures::{select, StreamExt};
+use futures_timer::Delay;
+
+use crate::kvraft::errors::{Error, Result};
 use crate::proto::kvraftpb::*;
 use crate::raft;
 
+// how long a request waits to be applied before the clerk is told to retry.
+const APPLY_TIMEOUT: Duration = Duration::from_millis(300);
+
 pub struct KvServer {
     pub rf: raft::Node,
     me: usize,
     // snapshot if log grows this big
     maxraftstate: Option<usize>,
     // Your definitions here.
+    apply_ch: Option<UnboundedReceiver<raft::ApplyMsg>>,
+    state: KvState,
+    // the requests waiting for the command at each index to be applied.
+    pending: HashMap<u64, Pending>,
+    // the Gets waiting for the state to catch up with their read index.
+    reads: Vec<PendingRead>,
+}
+
+/// The state machine, which is also the snapshot handed to raft.
+#[derive(Message)]
+struct KvState {
+    #[prost(map = "string, string", tag = "1")]
+    data: HashMap<String, String>,
+    // the seq of the last Put or Append applied for each clerk.
+    #[prost(map = "uint64, uint64", tag = "2")]
+    last_seqs: HashMap<u64, u64>,
+    // the index of the last command applied.
+    #[prost(uint64, tag = "3")]
+    last_applied: u64,
+}
+
+struct Pending {
+    client_id: u64,
+    seq: u64,
+    tx: oneshot::Sender<Result<String>>,
+}
+
+struct PendingRead {
+    index: u64,
+    key: String,
+    tx: oneshot::Sender<Result<String>>,
 }
 
 impl KvServer {
@@ -19,11 +65,160 @@ impl KvServer {
         maxraftstate: Option<usize>,
     ) -> KvServer {
         // You may need initialization code here.
+        let storage = Box::new(raft::storage::PersisterStorage::new(persister));
+        KvServer::with_storage(servers, me, storage, maxraftstate)
+    }
+
+    /// Like [`KvServer::new`], but keeps the Raft log in `storage`.
+    pub fn with_storage(
+        servers: Vec<crate::proto::raftpb::RaftClient>,
+        me: usize,
+        storage: Box<dyn raft::storage::RaftLogStorage>,
+        maxraftstate: Option<usize>,
+    ) -> KvServer {
+        let snapshot = storage.snapshot();
 
         let (tx, apply_ch) = unbounded();
-        let rf = raft::Raft::new(servers, me, persister, tx);
+        let rf = raft::Raft::with_storage(servers, me, storage, tx, raft::RaftConfig::default());
+
+        let mut kv = KvServer {
+            rf: raft::Node::new(rf),
+            me,
+            maxraftstate,
+            apply_ch: Some(apply_ch),
+            state: KvState {
+                data: HashMap::new(),
+                last_seqs: HashMap::new(),
+                last_applied: 0,
+            },
+            pending: HashMap::new(),
+            reads: Vec::new(),
+        };
+        kv.restore(&snapshot);
+        kv
+    }
+
+    /// Proposes a command, and returns where to wait for its result.
+    fn start(&mut self, cmd: Command) -> Result<oneshot::Receiver<Result<String>>> {
+        let (tx, rx) = oneshot::channel();
+        if cmd.op() != Op::Get && self.is_duplicate(&cmd) {
+            // applied already, the reply was lost.
+            let _ = tx.send(Ok(String::new()));
+            return Ok(rx);
+        }
+        let (index, _) = self.rf.start(&cmd).map_err(|_| Error::NoLeader)?;
+        let pending = Pending {
+            client_id: cmd.client_id,
+            seq: cmd.seq,
+            tx,
+        };
+        if let Some(old) = self.pending.insert(index, pending) {
+            // the command proposed at this index by a former leader is lost.
+            let _ = old.tx.send(Err(Error::NoLeader));
+        }
+        Ok(rx)
+    }
+
+    /// Returns where to wait for the value of `key`, once the commands up to
+    /// `index` are applied.
+    fn read(&mut self, index: u64, key: String) -> oneshot::Receiver<Result<String>> {
+        let (tx, rx) = oneshot::channel();
+        self.reads.push(PendingRead { index, key, tx });
+        self.answer_reads();
+        rx
+    }
+
+    fn answer_reads(&mut self) {
+        let last_applied = self.state.last_applied;
+        let (ready, waiting) = mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|read| read.index <= last_applied);
+        self.reads = waiting;
+        for read in ready {
+            let value = self.state.data.get(&read.key).cloned().unwrap_or_default();
+            let _ = read.tx.send(Ok(value));
+        }
+    }
+
+    fn is_duplicate(&self, cmd: &Command) -> bool {
+        self.state.last_seqs.get(&cmd.client_id) >= Some(&cmd.seq)
+    }
 
-        crate::your_code_here((rf, maxraftstate, apply_ch))
+    fn apply(&mut self, msg: raft::ApplyMsg) {
+        match msg {
+            raft::ApplyMsg::Command { data, index } => {
+                if index <= self.state.last_applied {
+                    return;
+                }
+                self.state.last_applied = index;
+                let cmd: Command = labcodec::decode(&data).expect("invalid command in the log");
+                let value = self.execute(&cmd);
+                if let Some(pending) = self.pending.remove(&index) {
+                    let res = if (pending.client_id, pending.seq) == (cmd.client_id, cmd.seq) {
+                        Ok(value)
+                    } else {
+                        Err(Error::NoLeader)
+                    };
+                    let _ = pending.tx.send(res);
+                }
+                self.answer_reads();
+                self.maybe_snapshot(index);
+            }
+            raft::ApplyMsg::Membership { index, .. } => {
+                if index > self.state.last_applied {
+                    self.state.last_applied = index;
+                    self.answer_reads();
+                    self.maybe_snapshot(index);
+                }
+            }
+            raft::ApplyMsg::Snapshot { data, term, index } => {
+                if index > self.state.last_applied
+                    && self.rf.cond_install_snapshot(term, index, &data)
+                {
+                    self.restore(&data);
+                    self.answer_reads();
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, cmd: &Command) -> String {
+        match cmd.op() {
+            Op::Get => {
+                return self.state.data.get(&cmd.key).cloned().unwrap_or_default();
+            }
+            _ if self.is_duplicate(cmd) => {}
+            Op::Put => {
+                self.state.data.insert(cmd.key.clone(), cmd.value.clone());
+            }
+            Op::Append => {
+                let value = self.state.data.entry(cmd.key.clone()).or_default();
+                value.push_str(&cmd.value);
+            }
+            Op::Unknown => {}
+        }
+        self.state.last_seqs.insert(cmd.client_id, cmd.seq);
+        String::new()
+    }
+
+    fn maybe_snapshot(&mut self, index: u64) {
+        match self.maxraftstate {
+            Some(maxraftstate) if self.rf.raft_state_size() >= maxraftstate => {
+                let mut data = Vec::new();
+                labcodec::encode(&self.state, &mut data).unwrap();
+                self.rf.snapshot(index, &data);
+            }
+            _ => {}
+        }
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        if snapshot.is_empty() {
+            return;
+        }
+        self.state = labcodec::decode(snapshot).expect("invalid snapshot");
+        // the requests in flight are answered again on retry.
+        self.pending.clear();
     }
 }
 
@@ -53,12 +248,69 @@ impl KvServer {
 #[derive(Clone)]
 pub struct Node {
     // Your definitions here.
+    server: Arc<Mutex<KvServer>>,
+    rf: raft::Node,
+    shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
 }
 
 impl Node {
-    pub fn new(kv: KvServer) -> Node {
+    pub fn new(mut kv: KvServer) -> Node {
         // Your code here.
-        crate::your_code_here(kv);
+        let apply_ch = kv
+            .apply_ch
+            .take()
+            .expect("the kv server is already running");
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let node = Node {
+            rf: kv.rf.clone(),
+            server: Arc::new(Mutex::new(kv)),
+            shutdown_tx: Arc::new(Mutex::new(Some(shutdown_tx))),
+        };
+        node.start_apply_loop(apply_ch, shutdown_rx);
+        node
+    }
+
+    /// Applies the commands committed by raft, until the node is killed.
+    fn start_apply_loop(
+        &self,
+        mut apply_ch: UnboundedReceiver<raft::ApplyMsg>,
+        mut shutdown_rx: oneshot::Receiver<()>,
+    ) {
+        let server = Arc::clone(&self.server);
+        thread::spawn(move || {
+            block_on(async {
+                loop {
+                    select! {
+                        msg = apply_ch.next() => match msg {
+                            Some(msg) => server.lock().unwrap().apply(msg),
+                            None => break,
+                        },
+                        _ = shutdown_rx => break,
+                    }
+                }
+            })
+        });
+    }
+
+    /// Proposes a command and waits for it to be applied.
+    async fn propose(&self, cmd: Command) -> Result<String> {
+        let rx = self.server.lock().unwrap().start(cmd)?;
+        wait(rx).await
+    }
+
+    /// Serves a Get from the state once it has caught up with the leader's
+    /// read index, without writing to the log.
+    async fn read(&self, cmd: Command) -> Result<String> {
+        let index = match future::select(self.rf.read_index(), Delay::new(APPLY_TIMEOUT)).await {
+            Either::Left((Ok(index), _)) => index,
+            Either::Left((Err(raft::errors::Error::NotReady), _)) => {
+                return self.propose(cmd).await;
+            }
+            Either::Left((Err(_), _)) => return Err(Error::NoLeader),
+            Either::Right(_) => return Err(Error::Timeout),
+        };
+        let rx = self.server.lock().unwrap().read(index, cmd.key);
+        wait(rx).await
     }
 
     /// the tester calls kill() when a KVServer instance won't
@@ -69,9 +321,10 @@ impl Node {
         // If you want to free some resources by `raft::Node::kill` method,
         // you should call `raft::Node::kill` here also to prevent resource leaking.
         // Since the test framework will call kvraft::Node::kill only.
-        // self.server.kill();
-
-        // Your code here, if desired.
+        self.rf.kill();
+        if let Some(tx) = self.shutdown_tx.lock().unwrap().take() {
+            let _ = tx.send(());
+        }
     }
 
     /// The current term of this peer.
@@ -85,10 +338,16 @@ impl Node {
     }
 
     pub fn get_state(&self) -> raft::State {
-        // Your code here.
-        raft::State {
-            ..Default::default()
-        }
+        self.rf.get_state()
+    }
+}
+
+/// Waits for a request to be answered, until the clerk is about to retry.
+async fn wait(rx: oneshot::Receiver<Result<String>>) -> Result<String> {
+    match future::select(rx, Delay::new(APPLY_TIMEOUT)).await {
+        Either::Left((Ok(res), _)) => res,
+        Either::Left((Err(_), _)) => Err(Error::NoLeader),
+        Either::Right(_) => Err(Error::Timeout),
     }
 }
 
@@ -96,13 +355,45 @@ impl Node {
 impl KvService for Node {
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
     async fn get(&self, arg: GetRequest) -> labrpc::Result<GetReply> {
-        // Your code here.
-        crate::your_code_here(arg)
+        let cmd = Command {
+            op: Op::Get as i32,
+            key: arg.key,
+            value: String::new(),
+            client_id: arg.client_id,
+            seq: arg.seq,
+        };
+        Ok(match self.read(cmd).await {
+            Ok(value) => GetReply {
+                wrong_leader: false,
+                err: String::new(),
+                value,
+            },
+            Err(e) => GetReply {
+                wrong_leader: e == Error::NoLeader,
+                err: e.to_string(),
+                value: String::new(),
+            },
+        })
     }
 
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
     async fn put_append(&self, arg: PutAppendRequest) -> labrpc::Result<PutAppendReply> {
-        // Your code here.
-        crate::your_code_here(arg)
+        let cmd = Command {
+            op: arg.op,
+            key: arg.key,
+            value: arg.value,
+            client_id: arg.client_id,
+            seq: arg.seq,
+        };
+        Ok(match self.propose(cmd).await {
+            Ok(_) => PutAppendReply {
+                wrong_leader: false,
+                err: String::new(),
+            },
+            Err(e) => PutAppendReply {
+                wrong_leader: e == Error::NoLeader,
+                err: e.to_string(),
+            },
+        })
     }
 }
@@ -5,6 +5,8 @@ pub mod raftpb {
         service raft {
             rpc request_vote(RequestVoteArgs) returns (RequestVoteReply);
             rpc append_entries(AppendEntriesArgs) returns (AppendEntriesReply);
+            rpc install_snapshot(InstallSnapshotArgs) returns (InstallSnapshotReply);
+            rpc timeout_now(TimeoutNowArgs) returns (TimeoutNowReply);
 
             // Your code here if more rpc desired.
             // rpc xxx(yyy) returns (zzz)
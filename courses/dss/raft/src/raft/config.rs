@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -12,6 +15,7 @@ use rand::Rng;
 use crate::proto::raftpb::*;
 use crate::raft;
 use crate::raft::persister::*;
+use crate::raft::storage::{PersisterStorage, RaftLogStorage, WalStorage};
 
 pub const SNAPSHOT_INTERVAL: u64 = 10;
 
@@ -20,6 +24,72 @@ fn uniqstring() -> String {
     format!("{}", ID.fetch_add(1, Ordering::Relaxed))
 }
 
+/// The persistent state of a server: a `SimplePersister`, or a `WalStorage`
+/// if the `RAFT_STORAGE` environment variable is `wal`.
+#[derive(Clone)]
+pub enum Saved {
+    Persister(Arc<SimplePersister>),
+    Wal(Arc<Mutex<WalStorage>>),
+}
+
+impl Saved {
+    /// An empty state. A `WalStorage` keeps it in a new directory in `root`.
+    pub fn new(root: &Path) -> Saved {
+        match env::var("RAFT_STORAGE") {
+            Ok(storage) if storage == "wal" => {
+                let dir = root.join(format!("wal-{}", uniqstring()));
+                Saved::Wal(Arc::new(Mutex::new(WalStorage::open(dir).unwrap())))
+            }
+            _ => Saved::Persister(Arc::new(SimplePersister::new())),
+        }
+    }
+
+    /// A copy of the state, so that an old instance which keeps running can
+    /// not change what a new one starts from.
+    pub fn copy(&self) -> Saved {
+        match self {
+            Saved::Persister(p) => {
+                let copy = SimplePersister::new();
+                copy.save_state_and_snapshot(p.raft_state(), p.snapshot());
+                Saved::Persister(Arc::new(copy))
+            }
+            Saved::Wal(wal) => {
+                let wal = wal.lock().unwrap();
+                let dir = wal.dir().with_file_name(format!("wal-{}", uniqstring()));
+                fs::create_dir(&dir).unwrap();
+                for entry in fs::read_dir(wal.dir()).unwrap() {
+                    let entry = entry.unwrap();
+                    fs::copy(entry.path(), dir.join(entry.file_name())).unwrap();
+                }
+                Saved::Wal(Arc::new(Mutex::new(WalStorage::open(dir).unwrap())))
+            }
+        }
+    }
+
+    /// The storage a new instance keeps its log in.
+    pub fn storage(&self) -> Box<dyn RaftLogStorage> {
+        match self {
+            Saved::Persister(p) => Box::new(PersisterStorage::new(Box::new(Arc::clone(p)))),
+            Saved::Wal(wal) => Box::new(Arc::clone(wal)),
+        }
+    }
+
+    /// The size of the log, which snapshots keep small.
+    pub fn raft_state_size(&self) -> usize {
+        match self {
+            Saved::Persister(p) => p.raft_state().len(),
+            Saved::Wal(wal) => wal.lock().unwrap().size(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        match self {
+            Saved::Persister(p) => p.snapshot(),
+            Saved::Wal(wal) => wal.lock().unwrap().snapshot(),
+        }
+    }
+}
+
 /// A log entry.
 #[derive(Clone, PartialEq, Message)]
 pub struct Entry {
@@ -30,6 +100,8 @@ pub struct Entry {
 pub struct Storage {
     // copy of each server's committed entries
     logs: Vec<HashMap<u64, Entry>>,
+    // the indexes of the membership entries each server applied
+    memberships: Vec<HashSet<u64>>,
     max_index: u64,
     max_index0: u64,
 }
@@ -69,12 +141,22 @@ pub struct Config {
     pub rafts: Arc<Mutex<Box<[Option<raft::Node>]>>>,
     // whether each server is on the net
     pub connected: Box<[bool]>,
-    saved: Box<[Arc<SimplePersister>]>,
+    saved: Box<[Saved]>,
     // the port file names each sends to
     endnames: Box<[Box<[String]>]>,
+    // the ClientEnds each sends with.
+    ends: Box<[Vec<labrpc::Client>]>,
 
     pub storage: Arc<Mutex<Storage>>,
 
+    // the voters the servers start with.
+    initial_voters: Vec<usize>,
+    // the clock drift bound the servers started serve lease reads with.
+    lease_clock_drift: Option<f64>,
+    // the config the servers start with. A logical clock is seeded with
+    // its seed plus the index of the server.
+    raft_config: raft::RaftConfig,
+
     // time at which make_config() was called
     start: Instant,
 
@@ -86,6 +168,9 @@ pub struct Config {
     rpcs0: usize,
     // number of agreements
     cmds0: usize,
+
+    // the directory the servers keep a `WalStorage` in.
+    _dir: tempfile::TempDir,
 }
 
 impl Config {
@@ -94,6 +179,16 @@ impl Config {
     }
 
     pub fn new_with(n: usize, unreliable: bool, snapshot: bool) -> Config {
+        Config::make(n, unreliable, snapshot, n)
+    }
+
+    /// only the first `voters` servers form the cluster at first, the others
+    /// wait to be added.
+    pub fn new_with_voters(n: usize, voters: usize) -> Config {
+        Config::make(n, false, false, voters)
+    }
+
+    fn make(n: usize, unreliable: bool, snapshot: bool, voters: usize) -> Config {
         init_logger();
 
         let net = labrpc::Network::new();
@@ -101,14 +196,16 @@ impl Config {
         net.set_long_delays(true);
         let storage = Storage {
             logs: vec![HashMap::new(); n],
+            memberships: vec![HashSet::new(); n],
             max_index: 0,
             max_index0: 0,
         };
+        let dir = tempfile::tempdir().unwrap();
         let mut saved = vec![];
         let mut endnames = vec![];
         for _ in 0..n {
             endnames.push(vec![String::new(); n].into_boxed_slice());
-            saved.push(Arc::new(SimplePersister::new()));
+            saved.push(Saved::new(dir.path()));
         }
         let mut cfg = Config {
             net,
@@ -117,12 +214,19 @@ impl Config {
             connected: vec![true; n].into_boxed_slice(),
             saved: saved.into_boxed_slice(),
             endnames: endnames.into_boxed_slice(),
+            ends: vec![Vec::new(); n].into_boxed_slice(),
             storage: Arc::new(Mutex::new(storage)),
 
+            initial_voters: (0..voters).collect(),
+            lease_clock_drift: None,
+            raft_config: raft::RaftConfig::default(),
+
             start: Instant::now(),
             t0: Instant::now(),
             rpcs0: 0,
             cmds0: 0,
+
+            _dir: dir,
         };
 
         for i in 0..n {
@@ -148,7 +252,7 @@ impl Config {
     pub fn log_size(&self) -> usize {
         self.saved
             .iter()
-            .map(|s| s.raft_state().len())
+            .map(|s| s.raft_state_size())
             .max()
             .unwrap()
     }
@@ -372,6 +476,37 @@ impl Config {
         self.start1_ext(i, false);
     }
 
+    /// restart all the servers with lease reads on or off.
+    pub fn set_lease_read(&mut self, clock_drift_bound: Option<f64>) {
+        self.lease_clock_drift = clock_drift_bound;
+        for i in 0..self.n {
+            self.start1(i);
+            self.connect(i);
+        }
+    }
+
+    /// restart all the servers with `config`.
+    pub fn set_raft_config(&mut self, config: raft::RaftConfig) {
+        self.raft_config = config;
+        for i in 0..self.n {
+            self.start1(i);
+            self.connect(i);
+        }
+    }
+
+    /// intercept the messages server `i` sends to server `j`, until `i`
+    /// restarts.
+    pub fn set_hooks(&self, i: usize, j: usize, hooks: Arc<dyn labrpc::RpcHooks>) {
+        self.ends[i][j].set_hooks(hooks);
+    }
+
+    /// move the logical clocks of all the running servers forward.
+    pub fn tick(&self, elapsed: Duration) {
+        for rf in self.rafts.lock().unwrap().iter().flatten() {
+            rf.tick(elapsed);
+        }
+    }
+
     pub fn start1_snapshot(&mut self, i: usize) {
         self.start1_ext(i, true);
     }
@@ -388,15 +523,26 @@ impl Config {
 
         // a fresh set of ClientEnds.
         let mut clients = Vec::with_capacity(self.n);
+        self.ends[i].clear();
         for (j, name) in self.endnames[i].iter().enumerate() {
             let cli = self.net.create_client(name.to_string());
+            self.ends[i].push(cli.clone());
             let client = RaftClient::new(cli);
             clients.push(client);
             self.net.connect(name, &format!("{}", j));
         }
 
         let (tx, apply_ch) = unbounded();
-        let rf = raft::Raft::new(clients, i, Box::new(self.saved[i].clone()), tx);
+        let mut config = self.raft_config.clone();
+        if let raft::Clock::Logical { seed } = config.clock {
+            config.clock = raft::Clock::Logical {
+                seed: seed + i as u64,
+            };
+        }
+        let storage = self.saved[i].storage();
+        let mut rf = raft::Raft::with_storage(clients, i, storage, tx, config);
+        rf.set_initial_voters(&self.initial_voters);
+        rf.set_lease_read(self.lease_clock_drift);
         let node = raft::Node::new(rf);
         self.rafts.lock().unwrap()[i] = Some(node.clone());
 
@@ -420,11 +566,13 @@ impl Config {
                         }
                     }
                 }
-                let log = &mut s.logs[i];
-                if index > 1 && log.get(&(index - 1)).is_none() {
+                if index > 1
+                    && !s.logs[i].contains_key(&(index - 1))
+                    && !s.memberships[i].contains(&(index - 1))
+                {
                     panic!("server {} apply out of order {}", i, index);
                 }
-                log.insert(index, entry);
+                s.logs[i].insert(index, entry);
                 if index > s.max_index {
                     s.max_index = index;
                 }
@@ -436,6 +584,10 @@ impl Config {
                 }
                 future::ready(())
             }
+            raft::ApplyMsg::Membership { index, .. } => {
+                storage.lock().unwrap().memberships[i].insert(index);
+                future::ready(())
+            }
             raft::ApplyMsg::Snapshot { data, index, term } if snapshot => {
                 // debug!("install snapshot {}", index);
                 if rafts.lock().unwrap()[i]
@@ -472,11 +624,7 @@ impl Config {
         // continues to update the Persister.
         // but copy old persister's content so that we always
         // pass Make() the last persisted state.
-        let raft_state = self.saved[i].raft_state();
-        let snapshot = self.saved[i].snapshot();
-        let p = SimplePersister::new();
-        p.save_state_and_snapshot(raft_state, snapshot);
-        self.saved[i] = Arc::new(p);
+        self.saved[i] = self.saved[i].copy();
 
         if let Some(rf) = self.rafts.lock().unwrap()[i].take() {
             rf.kill();
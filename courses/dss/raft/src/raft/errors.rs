@@ -6,6 +6,15 @@ pub enum Error {
     Decode(labcodec::DecodeError),
     Rpc(labrpc::Error),
     NotLeader,
+    // the leader has not committed an entry of its term yet, so it can not
+    // tell whether its commit index is up to date.
+    NotReady,
+    // the peer is not one which may join the cluster, or it is the last voter.
+    InvalidPeer(usize),
+    // another membership change is still in progress.
+    MembershipChanging,
+    // the leader is handing over its leadership, and takes no proposals.
+    TransferringLeader,
 }
 
 impl fmt::Display for Error {
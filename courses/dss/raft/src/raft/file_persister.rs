@@ -0,0 +1,297 @@
+//! A persister keeping the raft state and the snapshot in files.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::persister::Persister;
+
+const STATE_FILE: &str = "raft_state";
+pub(super) const SNAPSHOT_PREFIX: &str = "snapshot.";
+pub(super) const TEMP_SUFFIX: &str = ".tmp";
+
+/// A persister keeping the raft state and the snapshot in files of a
+/// directory, so they survive a restart of the process.
+///
+/// Every file is written to a temporary file which is synced, then renamed
+/// over the old one. The snapshots are numbered, and the raft state file
+/// names the snapshot it goes with, so renaming the raft state file commits
+/// both of them at once. Each file starts with a CRC-32 of its contents,
+/// checked when the directory is opened.
+///
+/// The data is also kept in memory, so reading it is cheap. As the trait
+/// methods cannot fail, an I/O error while saving panics.
+pub struct FilePersister {
+    dir: PathBuf,
+    states: Mutex<FileStates>,
+}
+
+struct FileStates {
+    raft_state: Vec<u8>,
+    snapshot: Vec<u8>,
+    // the number of the snapshot file, 0 if there is none.
+    snapshot_id: u64,
+}
+
+impl FilePersister {
+    /// Opens the persister stored in `dir`, creating the directory if needed.
+    ///
+    /// The files left by a save which did not complete are removed. It
+    /// fails with `InvalidData` if a file does not match its checksum.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<FilePersister> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut states = FileStates {
+            raft_state: Vec::new(),
+            snapshot: Vec::new(),
+            snapshot_id: 0,
+        };
+        match read_file(&dir.join(STATE_FILE)) {
+            Ok(data) => {
+                if data.len() < 8 {
+                    return Err(invalid_data(&dir.join(STATE_FILE)));
+                }
+                let mut id = [0; 8];
+                id.copy_from_slice(&data[..8]);
+                states.snapshot_id = u64::from_le_bytes(id);
+                states.raft_state = data[8..].to_vec();
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if states.snapshot_id != 0 {
+            states.snapshot = read_file(&dir.join(snapshot_file(states.snapshot_id)))?;
+        }
+
+        // remove what an interrupted save left behind.
+        let current = snapshot_file(states.snapshot_id);
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(TEMP_SUFFIX)
+                || name.starts_with(SNAPSHOT_PREFIX) && name != current.as_str()
+            {
+                fs::remove_file(dir.join(&*name))?;
+            }
+        }
+
+        Ok(FilePersister {
+            dir,
+            states: Mutex::new(states),
+        })
+    }
+
+    /// Returns the directory the files are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn write_raft_state(&self, state: &[u8], snapshot_id: u64) -> io::Result<()> {
+        let mut data = Vec::with_capacity(8 + state.len());
+        data.extend_from_slice(&snapshot_id.to_le_bytes());
+        data.extend_from_slice(state);
+        write_file(&self.dir, STATE_FILE, &data)
+    }
+}
+
+impl Persister for FilePersister {
+    fn raft_state(&self) -> Vec<u8> {
+        self.states.lock().unwrap().raft_state.clone()
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) {
+        let mut states = self.states.lock().unwrap();
+        self.write_raft_state(&state, states.snapshot_id)
+            .unwrap_or_else(|e| panic!("failed to save the raft state: {}", e));
+        states.raft_state = state;
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+        let mut states = self.states.lock().unwrap();
+        let old_id = states.snapshot_id;
+        let new_id = old_id + 1;
+        write_file(&self.dir, &snapshot_file(new_id), &snapshot)
+            .and_then(|_| self.write_raft_state(&state, new_id))
+            .unwrap_or_else(|e| panic!("failed to save the snapshot: {}", e));
+        if old_id != 0 {
+            // a leftover is removed on open anyway.
+            let _ = fs::remove_file(self.dir.join(snapshot_file(old_id)));
+        }
+        *states = FileStates {
+            raft_state: state,
+            snapshot,
+            snapshot_id: new_id,
+        };
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.states.lock().unwrap().snapshot.clone()
+    }
+}
+
+pub(super) fn snapshot_file(id: u64) -> String {
+    format!("{}{}", SNAPSHOT_PREFIX, id)
+}
+
+/// Atomically replaces `dir/name` with the checksum and `data`.
+pub(super) fn write_file(dir: &Path, name: &str, data: &[u8]) -> io::Result<()> {
+    let path = dir.join(name);
+    let temp = dir.join(format!("{}{}", name, TEMP_SUFFIX));
+    let mut file = File::create(&temp)?;
+    file.write_all(&crc32(data).to_le_bytes())?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp, &path)?;
+    // make the rename durable.
+    File::open(dir)?.sync_all()
+}
+
+/// Reads a file written by `write_file`, checking its checksum.
+pub(super) fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = fs::read(path)?;
+    if data.len() < 4 {
+        return Err(invalid_data(path));
+    }
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&data[..4]);
+    data.drain(..4);
+    if crc32(&data) != u32::from_le_bytes(crc) {
+        return Err(invalid_data(path));
+    }
+    Ok(data)
+}
+
+pub(super) fn invalid_data(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is corrupted", path.display()),
+    )
+}
+
+/// The CRC-32 (IEEE) of `data`.
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_file_persister_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert!(fp.raft_state().is_empty());
+        assert!(fp.snapshot().is_empty());
+
+        fp.save_raft_state(vec![1, 2]);
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![1, 2]);
+        assert!(fp.snapshot().is_empty());
+
+        fp.save_state_and_snapshot(vec![3], vec![4, 5]);
+        fp.save_state_and_snapshot(vec![6], vec![7, 8]);
+        fp.save_raft_state(vec![9]);
+        // the older snapshots are removed.
+        assert_eq!(file_names(dir.path()), ["raft_state", "snapshot.2"]);
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![9]);
+        assert_eq!(fp.snapshot(), vec![7, 8]);
+
+        let obj: Box<dyn Persister> = Box::new(fp);
+        obj.save_state_and_snapshot(vec![], vec![10]);
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert!(fp.raft_state().is_empty());
+        assert_eq!(fp.snapshot(), vec![10]);
+    }
+
+    #[test]
+    fn test_file_persister_crash_mid_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = FilePersister::open(dir.path()).unwrap();
+        fp.save_state_and_snapshot(vec![1], vec![2]);
+        drop(fp);
+
+        // crashed while writing the temporary files.
+        fs::write(dir.path().join("raft_state.tmp"), [0xff; 3]).unwrap();
+        fs::write(dir.path().join("snapshot.2.tmp"), [0xff; 9]).unwrap();
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![1]);
+        assert_eq!(fp.snapshot(), vec![2]);
+        assert_eq!(file_names(dir.path()), ["raft_state", "snapshot.1"]);
+        drop(fp);
+
+        // crashed after the new snapshot, before the raft state naming it.
+        write_file(dir.path(), "snapshot.2", &[3]).unwrap();
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![1]);
+        assert_eq!(fp.snapshot(), vec![2]);
+        assert_eq!(file_names(dir.path()), ["raft_state", "snapshot.1"]);
+
+        // saving again after recovering picks the next snapshot number.
+        fp.save_state_and_snapshot(vec![4], vec![5]);
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![4]);
+        assert_eq!(fp.snapshot(), vec![5]);
+        drop(fp);
+
+        // crashed after the raft state, before removing the old snapshot.
+        write_file(dir.path(), "snapshot.1", &[2]).unwrap();
+        let fp = FilePersister::open(dir.path()).unwrap();
+        assert_eq!(fp.snapshot(), vec![5]);
+        assert_eq!(file_names(dir.path()), ["raft_state", "snapshot.2"]);
+    }
+
+    #[test]
+    fn test_file_persister_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = FilePersister::open(dir.path()).unwrap();
+        fp.save_state_and_snapshot(vec![1, 2, 3], vec![4, 5, 6]);
+        drop(fp);
+
+        let snapshot = dir.path().join("snapshot.1");
+        let mut data = fs::read(&snapshot).unwrap();
+        data[5] ^= 1;
+        fs::write(&snapshot, &data).unwrap();
+        let err = FilePersister::open(dir.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a torn write of the raft state.
+        let state = dir.path().join("raft_state");
+        let data = fs::read(&state).unwrap();
+        fs::write(&state, &data[..data.len() - 1]).unwrap();
+        let err = FilePersister::open(dir.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::write(&state, &data[..2]).unwrap();
+        let err = FilePersister::open(dir.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,27 +1,42 @@
+use std::collections::HashSet;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::channel::oneshot;
 use futures::executor::ThreadPool;
+use futures::future::Fuse;
 use futures::task::SpawnExt;
-use futures::{select, FutureExt, StreamExt};
-use rand::Rng;
+use futures::{select, Future, FutureExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 #[cfg(test)]
 pub mod config;
 pub mod errors;
+pub mod file_persister;
 pub mod persister;
 mod states;
+pub mod storage;
 #[cfg(test)]
 mod tests;
 
 use self::errors::*;
 use self::persister::*;
 use self::states::*;
+use self::storage::*;
 use crate::proto::raftpb::*;
 
 pub use self::states::State;
+pub use crate::proto::raftpb::{add_raft_service, Entry, Membership, RaftClient};
+
+// the most bytes of commands sent in one AppendEntries. A single entry is
+// sent whatever its size.
+const MAX_BYTES_PER_MESSAGE: usize = 64 * 1024;
+// the most AppendEntries in flight to a peer before new entries wait for
+// the replies.
+const MAX_INFLIGHT: usize = 4;
 
 /// As each Raft peer becomes aware that successive log entries are committed,
 /// the peer should send an `ApplyMsg` to the service (or tester) on the same
@@ -38,6 +53,69 @@ pub enum ApplyMsg {
         term: u64,
         index: u64,
     },
+    // an entry changing the membership, which carries no command.
+    Membership {
+        membership: Membership,
+        index: u64,
+    },
+}
+
+/// How a peer keeps time, which drives its election and heartbeat timers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clock {
+    /// The timers run on the real clock.
+    Real,
+    /// The time only moves when the caller calls [`Node::tick`], and the
+    /// election timeouts are drawn from an RNG seeded with `seed`, so a run
+    /// can be replayed. Each peer should have its own seed, or they all
+    /// time out at once.
+    Logical { seed: u64 },
+}
+
+/// The timing of a Raft peer and the features it runs with, given to
+/// `Raft::new`. Every peer of a cluster should use the same one, but for
+/// the seed of a logical clock.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// The range each election timeout is picked from at random. A peer
+    /// which heard from a leader within its start believes it is alive, and
+    /// refuses pre-votes, and votes too if lease reads are on.
+    pub election_timeout: Range<Duration>,
+    /// How often a leader sends heartbeats, which must be shorter than the
+    /// election timeout.
+    pub heartbeat_interval: Duration,
+    /// The most entries sent in one AppendEntries.
+    pub max_entries_per_message: usize,
+    /// The size of the chunks a snapshot is sent in.
+    pub snapshot_chunk_size: usize,
+    /// Whether to ask the others whether they would vote for this peer
+    /// before starting an election, which it only does if a quorum would.
+    ///
+    /// A peer cut off from the others then keeps its term, and does not
+    /// make the leader step down when it comes back.
+    pub pre_vote: bool,
+    /// Whether a leader which has not heard from a quorum for an election
+    /// timeout steps down, so that it stops taking commands it cannot
+    /// commit.
+    pub check_quorum: bool,
+    /// How the peer keeps time, the real clock by default.
+    pub clock: Clock,
+}
+
+impl Default for RaftConfig {
+    fn default() -> RaftConfig {
+        RaftConfig {
+            // We suggest ElectionTick = 10 * HeartbeatTick to avoid
+            // unnecessary leader switching.
+            election_timeout: Duration::from_millis(1000)..Duration::from_millis(1500),
+            heartbeat_interval: Duration::from_millis(100),
+            max_entries_per_message: 64,
+            snapshot_chunk_size: 16 * 1024,
+            pre_vote: true,
+            check_quorum: true,
+            clock: Clock::Real,
+        }
+    }
 }
 
 // A single Raft peer.
@@ -45,7 +123,9 @@ pub struct Raft {
     // RPC end points of all peers
     peers: Vec<RaftClient>,
     // Object to hold this peer's persisted state
-    persister: Box<dyn Persister>,
+    storage: Box<dyn RaftLogStorage>,
+    // the term and the vote last saved to the storage.
+    saved_hard_state: (u64, Option<u64>),
     // this peer's index into peers[]
     me: usize,
     // Your data here (2A, 2B, 2C).
@@ -54,6 +134,14 @@ pub struct Raft {
     role: RoleState,
     hard_state: PersistentState,
     soft_state: SoftState,
+    config: RaftConfig,
+    // picks the election timeouts.
+    rng: StdRng,
+    // the time and the timers if the clock is logical.
+    clock: Option<LogicalClock>,
+    // the bound on the clock drift between peers if a leader serves reads
+    // from its lease, otherwise each read waits for a round of heartbeats.
+    lease_clock_drift: Option<f64>,
 
     // channels
     event_loop_tx: Option<UnboundedSender<Event>>, // should always be Some
@@ -71,48 +159,131 @@ impl Raft {
     /// recent saved state, if any. apply_ch is a channel on which the
     /// tester or service expects Raft to send ApplyMsg messages.
     /// This method must return quickly.
+    ///
+    /// `peers` holds every server which may ever join the cluster, and only
+    /// the members are sent RPCs. All of them vote at first, unless
+    /// `set_initial_voters` says otherwise.
     pub fn new(
         peers: Vec<RaftClient>,
         me: usize,
         persister: Box<dyn Persister>,
         apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
+    ) -> Raft {
+        let storage = Box::new(PersisterStorage::new(persister));
+        Raft::with_storage(peers, me, storage, apply_ch, config)
+    }
+
+    /// Like [`Raft::new`], but keeps the log in `storage`, which only writes
+    /// what changed, e.g. a [`WalStorage`].
+    pub fn with_storage(
+        peers: Vec<RaftClient>,
+        me: usize,
+        storage: Box<dyn RaftLogStorage>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
     ) -> Raft {
-        let raft_state = persister.raft_state();
+        assert!(
+            config.heartbeat_interval > Duration::from_millis(0)
+                && config.heartbeat_interval < config.election_timeout.start
+                && config.election_timeout.start < config.election_timeout.end
+                && config.max_entries_per_message > 0
+                && config.snapshot_chunk_size > 0,
+            "invalid raft config: {:?}",
+            config
+        );
+        let initial_state = storage.initial_state();
+        let rng = match config.clock {
+            Clock::Real => StdRng::from_entropy(),
+            Clock::Logical { seed } => StdRng::seed_from_u64(seed),
+        };
 
         // Your initialization code here (2A, 2B, 2C).
         let mut rf = Raft {
             peers,
-            persister,
+            storage,
+            saved_hard_state: (0, None),
             me,
             role: RoleState::Follower,
             hard_state: PersistentState::new(),
             soft_state: SoftState::new(),
+            config,
+            rng,
+            clock: None,
+            lease_clock_drift: None,
             event_loop_tx: None,
             executor: ThreadPool::new().unwrap(),
             apply_ch,
         };
 
+        if let Clock::Logical { .. } = rf.config.clock {
+            let now = Instant::now();
+            rf.clock = Some(LogicalClock {
+                now,
+                election_at: now + rf.random_election_timeout(),
+                heartbeat_at: now + rf.config.heartbeat_interval,
+            });
+        }
+        rf.hard_state.membership = Some(Membership::with_voters(0..rf.peers.len()));
+
         // initialize from state persisted before a crash
-        rf.restore(&raft_state);
+        if initial_state.membership.is_some() {
+            rf.restore(initial_state);
+        } else {
+            rf.persist_with_snapshot(Vec::new());
+        }
+        rf.reload_membership();
 
         rf.turn_follower();
 
         rf
     }
 
+    /// Sets the voters the cluster starts with, which are all the peers by
+    /// default. A peer left out, e.g. one to be added later with
+    /// [`Node::add_peer`], waits to hear from a leader. It is ignored if this
+    /// peer restarts from a persisted state.
+    pub fn set_initial_voters(&mut self, voters: &[usize]) {
+        if self.hard_state.current_term == 0 && self.hard_state.last_index() == 0 {
+            self.hard_state.membership = Some(Membership::with_voters(voters.iter().cloned()));
+            self.reload_membership();
+            self.persist_with_snapshot(Vec::new());
+        }
+    }
+
+    /// Sets whether a leader serves reads from a lease instead of confirming
+    /// its leadership with a round of heartbeats for each one.
+    ///
+    /// A peer which heard from the leader within the shortest election
+    /// timeout refuses to vote for anyone else, so once a quorum replied to a
+    /// heartbeat, the leader is sure to lead for that long after sending it.
+    /// `clock_drift_bound` is how much faster the clock of a peer may run
+    /// than the leader's, e.g. `1.1`, and the lease is shortened by it. Every
+    /// peer of the cluster must agree on it. It is off by default.
+    pub fn set_lease_read(&mut self, clock_drift_bound: Option<f64>) {
+        assert!(clock_drift_bound.map_or(true, |bound| bound >= 1.0));
+        self.lease_clock_drift = clock_drift_bound;
+    }
+
     fn start<M>(&mut self, command: &M) -> Result<(u64, u64)>
     where
         M: labcodec::Message,
     {
         match &self.role {
+            RoleState::Leader {
+                transferee: Some(_),
+                ..
+            } => Err(Error::TransferringLeader),
             RoleState::Leader { .. } => {
                 let mut data = Vec::new();
                 labcodec::encode(command, &mut data).map_err(Error::Encode)?;
                 let entry = Entry {
                     term: self.hard_state.current_term,
                     data,
+                    membership: None,
                 };
-                self.hard_state.log.push(entry);
+                self.append_log(vec![entry]);
+                self.replicate_to_all();
                 Ok((self.last_log_index(), self.last_log_term()))
             }
             _ => Err(Error::NotLeader),
@@ -123,32 +294,297 @@ impl Raft {
         self.event_loop_tx.as_ref().expect("no event loop sender")
     }
 
+    /// The current time, on the logical clock if this peer has one.
+    fn now(&self) -> Instant {
+        self.clock
+            .as_ref()
+            .map_or_else(Instant::now, |clock| clock.now)
+    }
+
+    /// The time since `at`.
+    fn elapsed(&self, at: Instant) -> Duration {
+        self.now().saturating_duration_since(at)
+    }
+
+    fn random_election_timeout(&mut self) -> Duration {
+        let Range { start, end } = self.config.election_timeout;
+        self.rng.gen_range(start, end)
+    }
+
+    /// Starts the election timeout over.
+    fn reset_election_timer(&mut self) {
+        if self.clock.is_none() {
+            self.schedule_event(Event::ResetTimeout);
+            return;
+        }
+        let timeout = self.random_election_timeout();
+        if let Some(clock) = &mut self.clock {
+            clock.election_at = clock.now + timeout;
+        }
+    }
+
+    /// Moves the logical clock forward, and fires the timers which are due.
+    fn tick(&mut self, elapsed: Duration) {
+        let heartbeat_interval = self.config.heartbeat_interval;
+        let clock = self.clock.as_mut().expect("the clock of this peer is real");
+        clock.now += elapsed;
+        let now = clock.now;
+        let election_due = now >= clock.election_at;
+        let heartbeat_due = now >= clock.heartbeat_at;
+        if heartbeat_due {
+            clock.heartbeat_at = now + heartbeat_interval;
+        }
+        if election_due {
+            self.reset_election_timer();
+            self.handle_election_timeout();
+        }
+        if heartbeat_due {
+            self.handle_heartbeat();
+        }
+    }
+
     fn cond_install_snapshot(
         &mut self,
         last_included_term: u64,
         last_included_index: u64,
         snapshot: &[u8],
     ) -> bool {
-        // Your code here (2D).
-        crate::your_code_here((last_included_term, last_included_index, snapshot));
+        // A snapshot is installed as soon as it is received, before any later
+        // command is sent on `apply_ch`, so the service can always switch to it.
+        let _ = (last_included_term, snapshot);
+        last_included_index <= self.hard_state.last_included_index
     }
 
     fn snapshot(&mut self, index: u64, snapshot: &[u8]) {
-        // Your code here (2D).
-        crate::your_code_here((index, snapshot));
+        if index <= self.hard_state.last_included_index || index > self.soft_state.commit_index {
+            return;
+        }
+        let term = self.hard_state.term(index).unwrap();
+        self.hard_state.compact(index, term);
+        self.persist_with_snapshot(snapshot.to_vec());
+    }
+
+    /// Registers a read, which is answered with the commit index once the
+    /// leadership is confirmed.
+    fn read_index(&mut self) -> oneshot::Receiver<Result<u64>> {
+        let (tx, rx) = oneshot::channel();
+        let index = self.soft_state.commit_index;
+        let now = self.now();
+        if !matches!(self.role, RoleState::Leader { .. }) {
+            let _ = tx.send(Err(Error::NotLeader));
+        } else if self.hard_state.term(index) != Some(self.hard_state.current_term) {
+            let _ = tx.send(Err(Error::NotReady));
+        } else if self.has_lease() {
+            let _ = tx.send(Ok(index));
+        } else if let RoleState::Leader { reads, .. } = &mut self.role {
+            reads.push(PendingRead {
+                index,
+                registered_at: now,
+                tx,
+            });
+            self.heart_beat_sync_log();
+            self.advance_reads();
+        }
+        rx
+    }
+
+    /// Answers the reads registered before a quorum last replied.
+    fn advance_reads(&mut self) {
+        let quorum_ack = match self.quorum_ack() {
+            Some(at) => at,
+            None => return,
+        };
+        if let RoleState::Leader { reads, .. } = &mut self.role {
+            let (ready, waiting) = std::mem::take(reads)
+                .into_iter()
+                .partition(|read| read.registered_at <= quorum_ack);
+            *reads = waiting;
+            for read in ready {
+                let _ = read.tx.send(Ok(read.index));
+            }
+        }
+    }
+
+    /// Proposes to add a peer, as a learner which becomes a voter once it
+    /// has caught up.
+    fn add_peer(&mut self, id: usize) -> Result<()> {
+        self.check_membership_change(id)?;
+        let mut membership = self.soft_state.membership.clone();
+        let id = id as u64;
+        if membership.voters.contains(&id) || membership.promoting.contains(&id) {
+            return Ok(());
+        }
+        if !membership.learners.contains(&id) {
+            membership.learners.push(id);
+        }
+        membership.promoting.push(id);
+        self.propose_membership(membership);
+        Ok(())
+    }
+
+    /// Proposes to remove a peer, through joint consensus if it votes.
+    fn remove_peer(&mut self, id: usize) -> Result<()> {
+        self.check_membership_change(id)?;
+        let mut membership = self.soft_state.membership.clone();
+        let id = id as u64;
+        if membership.voters.contains(&id) {
+            if membership.voters.len() == 1 {
+                return Err(Error::InvalidPeer(id as usize));
+            }
+            membership.outgoing_voters = membership.voters.clone();
+            membership.voters.retain(|&v| v != id);
+        } else if !membership.learners.contains(&id) {
+            return Ok(());
+        }
+        membership.learners.retain(|&v| v != id);
+        membership.promoting.retain(|&v| v != id);
+        self.propose_membership(membership);
+        Ok(())
+    }
+
+    /// Only a leader changes the membership, one change at a time.
+    fn check_membership_change(&self, id: usize) -> Result<()> {
+        match self.role {
+            RoleState::Leader {
+                transferee: Some(_),
+                ..
+            } => return Err(Error::TransferringLeader),
+            RoleState::Leader { .. } => {}
+            _ => return Err(Error::NotLeader),
+        }
+        if id >= self.peers.len() {
+            return Err(Error::InvalidPeer(id));
+        }
+        if self.soft_state.membership_index > self.soft_state.commit_index
+            || self.soft_state.membership.is_joint()
+        {
+            return Err(Error::MembershipChanging);
+        }
+        Ok(())
+    }
+
+    /// Appends an entry changing the membership, which is in effect as soon
+    /// as it is in the log.
+    fn propose_membership(&mut self, membership: Membership) {
+        debug!("[propose_membership! {}] {:?}", self.me, membership);
+        self.append_log(vec![Entry {
+            term: self.hard_state.current_term,
+            data: vec![],
+            membership: Some(membership),
+        }]);
+        self.reload_membership();
+        self.replicate_to_all();
+    }
+
+    /// Moves a committed membership change along: leaves the joint
+    /// configuration, or starts promoting the learners which caught up. A
+    /// leader which is no longer a voter steps down.
+    ///
+    /// A change left uncommitted by an earlier leader is proposed again.
+    fn advance_membership(&mut self) {
+        let match_index = match &self.role {
+            // the transferee must stay up to date.
+            RoleState::Leader {
+                transferee: Some(_),
+                ..
+            } => return,
+            RoleState::Leader { match_index, .. } => match_index,
+            _ => return,
+        };
+        if self.soft_state.membership_index > self.soft_state.commit_index {
+            // a change from an earlier term only commits along with an entry
+            // of this term.
+            if self.hard_state.last_term() != self.hard_state.current_term {
+                self.propose_membership(self.soft_state.membership.clone());
+            }
+            return;
+        }
+        let mut membership = self.soft_state.membership.clone();
+        if membership.is_joint() {
+            membership.outgoing_voters.clear();
+        } else {
+            let commit_index = self.soft_state.commit_index;
+            let caught_up: Vec<u64> = membership
+                .promoting
+                .iter()
+                .cloned()
+                .filter(|&id| match_index[id as usize] as u64 >= commit_index)
+                .collect();
+            if caught_up.is_empty() {
+                if !membership.is_voter(self.me) {
+                    debug!("[advance_membership! {}] removed, stepping down", self.me);
+                    self.turn_follower();
+                }
+                return;
+            }
+            membership.outgoing_voters = membership.voters.clone();
+            membership.voters.extend(&caught_up);
+            membership.learners.retain(|id| !caught_up.contains(id));
+            membership.promoting.retain(|id| !caught_up.contains(id));
+        }
+        self.propose_membership(membership);
     }
 
-    fn start_election(&mut self) {
-        println!("[start_election! {}]", self.me);
+    /// Starts handing over the leadership to `target`, once it has caught up.
+    fn transfer_leader(&mut self, target: usize) -> Result<()> {
+        if !matches!(self.role, RoleState::Leader { .. }) {
+            return Err(Error::NotLeader);
+        }
+        if target == self.me {
+            return Ok(());
+        }
+        if !self.soft_state.membership.voters.contains(&(target as u64)) {
+            return Err(Error::InvalidPeer(target));
+        }
+        let now = self.now();
+        if let RoleState::Leader { transferee, .. } = &mut self.role {
+            *transferee = Some((target, now));
+        }
+        self.heart_beat_sync_log();
+        Ok(())
+    }
+
+    /// Tells the transferee to campaign at once.
+    fn send_timeout_now(&self, to: usize) {
+        debug!("[send_timeout_now! {}] to: {}", self.me, to);
+        let args = TimeoutNowArgs {
+            term: self.hard_state.current_term,
+            leader_id: self.me as u64,
+        };
+        let fut = self.peers[to].timeout_now(&args);
+        self.executor
+            .spawn(async move {
+                // a higher term comes back with the votes it asks for.
+                let _ = fut.await;
+            })
+            .unwrap();
+    }
+
+    /// Asks the other peers for their votes, or for their pre-votes.
+    fn start_election(&mut self, campaign_type: CampaignType) {
+        debug!("[start_election! {}] {:?}", self.me, campaign_type);
+        let mut args = self.request_vote_args();
+        match campaign_type {
+            CampaignType::PreVote => {
+                // the term the election would be in.
+                args.term += 1;
+                args.pre_vote = true;
+            }
+            CampaignType::Election => {}
+            CampaignType::Transfer => args.leader_transfer = true,
+        }
         for (i, peer) in self.other_peers() {
             let tx = self.event_loop_tx().clone();
-            let fut = peer.request_vote(&self.request_vote_args());
+            let fut = peer.request_vote(&args);
             self.executor
                 .spawn(async move {
                     let reply = fut.await;
-                    let _ = tx
-                        .unbounded_send(Event::RequestVoteReply(i, reply))
-                        .unwrap();
+                    let event = if campaign_type == CampaignType::PreVote {
+                        Event::PreVoteReply(i, reply)
+                    } else {
+                        Event::RequestVoteReply(i, reply)
+                    };
+                    let _ = tx.unbounded_send(event);
                 })
                 .unwrap();
         }
@@ -163,14 +599,26 @@ impl Raft {
     }
 
     fn turn_leader(&mut self) {
-        let next_index = vec![self.hard_state.log.len(); self.peers.len()];
+        let next_index = vec![self.last_log_index() as usize + 1; self.peers.len()];
         let match_index = vec![0; self.peers.len()];
         self.role = RoleState::Leader {
             next_index,
             match_index,
+            inflight: vec![0; self.peers.len()],
+            snapshot_inflight: vec![None; self.peers.len()],
+            active: HashSet::new(),
+            acks: vec![None; self.peers.len()],
+            reads: Vec::new(),
+            transferee: None,
+            transfer_aborted: None,
         }
     }
 
+    fn turn_pre_candidate(&mut self) {
+        let votes = [self.me].iter().cloned().collect();
+        self.role = RoleState::PreCandidate { votes };
+    }
+
     fn turn_candidate(&mut self) {
         let votes = [self.me].iter().cloned().collect();
         self.role = RoleState::Candidate { votes };
@@ -183,28 +631,81 @@ impl Raft {
     /// save Raft's persistent state to stable storage,
     /// where it can later be retrieved after a crash and restart.
     /// see paper's Figure 2 for a description of what should be persistent.
+    ///
+    /// The log is saved as it changes, by `append_log` and `truncate_log`,
+    /// so only the term and the vote are left, if they changed.
     fn persist(&mut self) {
         // Your code here (2C).
-        let mut data = Vec::new();
-        labcodec::encode(&self.hard_state, &mut data).unwrap();
-        self.persister.save_raft_state(data);
+        let hard_state = (self.hard_state.current_term, self.hard_state.voted_for);
+        if hard_state != self.saved_hard_state {
+            self.storage.save_hard_state(hard_state.0, hard_state.1);
+            self.saved_hard_state = hard_state;
+        }
+        self.storage.sync();
+    }
+
+    /// save Raft's persistent state along with the snapshot covering the
+    /// entries it no longer holds.
+    fn persist_with_snapshot(&mut self, snapshot: Vec<u8>) {
+        self.storage.compact_prefix(
+            self.hard_state.last_included_index,
+            self.hard_state.last_included_term(),
+            self.hard_state.membership.clone(),
+            snapshot,
+        );
+        self.persist();
+    }
+
+    /// Appends `entries` to the log, and saves them.
+    fn append_log(&mut self, entries: Vec<Entry>) {
+        self.storage.append(&entries);
+        self.hard_state.log.extend(entries);
+        self.persist();
+    }
+
+    /// Deletes the entries after `index`, in the log and in the storage,
+    /// which are saved with the next `persist`.
+    fn truncate_log(&mut self, index: u64) {
+        self.hard_state.truncate(index);
+        self.storage.truncate_suffix(index);
     }
 
     /// restore previously persisted state.
-    fn restore(&mut self, data: &[u8]) {
-        if data.is_empty() {
-            return;
-        }
+    fn restore(&mut self, state: InitialState) {
         // Your code here (2C).
-        self.hard_state = labcodec::decode(data).unwrap();
+        let mut log = Vec::with_capacity(state.entries.len() + 1);
+        log.push(Entry {
+            term: state.last_included_term,
+            ..Default::default()
+        });
+        log.extend(state.entries);
+        self.hard_state = PersistentState {
+            current_term: state.term,
+            voted_for: state.voted_for,
+            log,
+            last_included_index: state.last_included_index,
+            membership: state.membership,
+        };
+        self.saved_hard_state = (state.term, state.voted_for);
+        // the entries in the snapshot are committed, and the service restores
+        // its state from the snapshot.
+        self.soft_state.commit_index = self.hard_state.last_included_index;
+        self.soft_state.last_applied = self.hard_state.last_included_index;
+    }
+
+    /// Picks up the latest membership in the log, after it changed.
+    fn reload_membership(&mut self) {
+        let (index, membership) = self.hard_state.membership_at(self.last_log_index());
+        self.soft_state.membership_index = index;
+        self.soft_state.membership = membership;
     }
 
     fn last_log_term(&self) -> u64 {
-        self.hard_state.log.last().unwrap().term
+        self.hard_state.last_term()
     }
 
     fn last_log_index(&self) -> u64 {
-        (self.hard_state.log.len() - 1) as u64
+        self.hard_state.last_index()
     }
 
     /// Determine whether the prev log of the AppendEntries RPC Caller
@@ -214,22 +715,77 @@ impl Raft {
     /// `args_prev_term` is the log term that Caller wants to match
     /// `args_prev_index` is the log index that Caller wants to match
     fn is_match(&self, args_prev_term: u64, args_prev_index: u64) -> bool {
-        return self
-            .hard_state
-            .log
-            .get(args_prev_index as usize)
-            .map(|e| e.term)
-            == Some(args_prev_term);
+        self.hard_state.term(args_prev_index) == Some(args_prev_term)
+    }
+
+    /// Whether a candidate's log is at least as up-to-date as this peer's.
+    fn is_up_to_date(&self, last_log_term: u64, last_log_index: u64) -> bool {
+        (last_log_term, last_log_index) >= (self.last_log_term(), self.last_log_index())
+    }
+
+    /// Whether this peer believes the leader of its term is alive.
+    fn has_live_leader(&self) -> bool {
+        matches!(self.role, RoleState::Leader { .. })
+            || self.soft_state.leader_contact.map_or(false, |at| {
+                self.elapsed(at) < self.config.election_timeout.start
+            })
+    }
+
+    /// The latest time a quorum, counting the leader itself, was known to
+    /// follow it: when the oldest of the heartbeats they replied to was sent.
+    fn quorum_ack(&self) -> Option<Instant> {
+        if let RoleState::Leader { acks, .. } = &self.role {
+            let now = self.now();
+            let ack = |id: usize| if id == self.me { Some(now) } else { acks[id] };
+            let mut acked: Vec<Instant> = acks.iter().flatten().cloned().collect();
+            acked.push(now);
+            acked.sort_unstable_by(|a, b| b.cmp(a));
+            acked.into_iter().find(|&at| {
+                self.soft_state
+                    .membership
+                    .has_quorum(|id| ack(id) >= Some(at))
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the leader has a lease, so no other leader can be elected yet.
+    ///
+    /// A transfer lets the transferee be elected at once, so there is no lease
+    /// while one is pending, nor for an election timeout after it is aborted.
+    fn has_lease(&self) -> bool {
+        if let RoleState::Leader {
+            transferee,
+            transfer_aborted,
+            ..
+        } = &self.role
+        {
+            if transferee.is_some()
+                || transfer_aborted.map_or(false, |at| {
+                    self.elapsed(at) < self.config.election_timeout.end
+                })
+            {
+                return false;
+            }
+        }
+        match (self.lease_clock_drift, self.quorum_ack()) {
+            (Some(bound), Some(at)) => {
+                self.elapsed(at) < self.config.election_timeout.start.div_f64(bound)
+            }
+            _ => false,
+        }
     }
 }
 
 // assit functions
 impl Raft {
+    /// The other members, voting or not.
     fn other_peers(&self) -> impl Iterator<Item = (usize, &RaftClient)> {
         self.peers
             .iter()
             .enumerate()
-            .filter(move |(i, _)| i != &self.me)
+            .filter(move |(i, _)| i != &self.me && self.soft_state.membership.contains(*i))
     }
 
     fn commit_to_new_index(&mut self, new_index: u64) {
@@ -238,13 +794,22 @@ impl Raft {
         }
 
         for i in self.soft_state.commit_index + 1..=new_index {
-            let msg = ApplyMsg::Command {
-                data: self.hard_state.log[i as usize].data.clone(),
-                index: i,
+            let entry = self.hard_state.entry(i).unwrap();
+            let msg = match &entry.membership {
+                Some(membership) => ApplyMsg::Membership {
+                    membership: membership.clone(),
+                    index: i,
+                },
+                None => ApplyMsg::Command {
+                    data: entry.data.clone(),
+                    index: i,
+                },
             };
-            self.apply_ch.unbounded_send(msg).unwrap();
+            // the service may be gone once the node is killed.
+            let _ = self.apply_ch.unbounded_send(msg);
         }
-        self.soft_state.commit_index = new_index as u64;
+        self.soft_state.commit_index = new_index;
+        self.soft_state.last_applied = new_index;
     }
 
     // maybe_commit attempts to advance the commit index. Returns true if
@@ -253,26 +818,20 @@ impl Raft {
         if let RoleState::Leader { match_index, .. } = &self.role {
             let mut new_commit_index = self.soft_state.commit_index;
 
-            // Find the max match index.
-            for cur_index in self.soft_state.commit_index..self.hard_state.log.len() as u64 {
-                let quorum_cnt = self
-                    .other_peers()
-                    .filter(|(i, _)| match_index[*i] >= cur_index as usize)
-                    .count()
-                    + 1;
-
-                let is_current_term =
-                    self.hard_state.log[cur_index as usize].term == self.hard_state.current_term;
-                if is_current_term {
-                    if quorum_cnt > self.peers.len() / 2 {
-                        new_commit_index = cur_index;
-                    } else {
-                        break;
-                    }
+            // Find the max index replicated on a quorum. Only entries of the
+            // current term are committed by counting replicas.
+            for cur_index in (self.soft_state.commit_index + 1..=self.last_log_index()).rev() {
+                if self.hard_state.term(cur_index) != Some(self.hard_state.current_term) {
+                    break;
+                }
+                let replicated = |id: usize| id == self.me || match_index[id] as u64 >= cur_index;
+                if self.soft_state.membership.has_quorum(replicated) {
+                    new_commit_index = cur_index;
+                    break;
                 }
             }
 
-            println!("[maybe_commit], new commit index is: {}", new_commit_index);
+            debug!("[maybe_commit], new commit index is: {}", new_commit_index);
             self.commit_to_new_index(new_commit_index);
         }
     }
@@ -285,20 +844,32 @@ impl Raft {
             candidate_id: self.me as u64,
             last_log_index: self.last_log_index(),
             last_log_term: self.last_log_term(),
+            pre_vote: false,
+            leader_transfer: false,
         }
     }
 
-    fn append_entries_args(&self, start_at: usize) -> AppendEntriesArgs {
-        let entries = self.hard_state.log[start_at..].iter().cloned().collect();
+    /// `start_at` must be after the last included index.
+    fn append_entries_args(&self, start_at: u64) -> AppendEntriesArgs {
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        let batch = self.hard_state.entries_from(start_at);
+        for entry in batch.iter().take(self.config.max_entries_per_message) {
+            bytes += entry.data.len();
+            if !entries.is_empty() && bytes > MAX_BYTES_PER_MESSAGE {
+                break;
+            }
+            entries.push(entry.clone());
+        }
         let prev_log_index = start_at - 1;
 
         AppendEntriesArgs {
             term: self.hard_state.current_term,
             leader_id: self.me as u64,
-            prev_log_index: prev_log_index as u64,
-            prev_log_term: self.hard_state.log[prev_log_index].term,
+            prev_log_index,
+            prev_log_term: self.hard_state.term(prev_log_index).unwrap(),
             entries,
-            leader_commit_index: self.soft_state.commit_index as u64,
+            leader_commit_index: self.soft_state.commit_index,
         }
     }
 }
@@ -317,32 +888,86 @@ impl Raft {
             Event::ElectionTimeout => self.handle_election_timeout(),
             Event::HeartBeat => self.handle_heartbeat(),
             Event::RequestVoteReply(from, reply) => self.handle_request_vote_reply(from, reply),
+            Event::PreVoteReply(from, reply) => self.handle_pre_vote_reply(from, reply),
             Event::AppendEntriesReply {
                 from,
                 reply,
                 new_next_index,
-            } => self.handle_append_entries_reply(from, reply, new_next_index),
+                term,
+                sent_at,
+            } => self.handle_append_entries_reply(from, reply, new_next_index, term, sent_at),
+            Event::InstallSnapshotReply {
+                from,
+                reply,
+                last_included_index,
+            } => self.handle_install_snapshot_reply(from, reply, last_included_index),
             Event::ForcePersist => self.persist(),
         }
     }
 
     fn handle_election_timeout(&mut self) {
         match self.role {
-            RoleState::Follower | RoleState::Candidate { .. } => {
-                // start new election
-                self.turn_candidate();
-                self.update_term(self.hard_state.current_term + 1);
-                self.hard_state.voted_for = Some(self.me as u64);
-
-                self.schedule_event(Event::ResetTimeout);
-                self.start_election();
+            RoleState::Leader { .. } => self.check_quorum(),
+            // learners and removed peers never campaign.
+            _ if !self.soft_state.membership.is_voter(self.me) => {}
+            _ if self.config.pre_vote => {
+                self.turn_pre_candidate();
+                self.reset_election_timer();
+                self.start_election(CampaignType::PreVote);
+            }
+            _ => self.campaign(CampaignType::Election),
+        }
+    }
+
+    // start new election
+    fn campaign(&mut self, campaign_type: CampaignType) {
+        self.turn_candidate();
+        self.update_term(self.hard_state.current_term + 1);
+        self.hard_state.voted_for = Some(self.me as u64);
+        // the vote must be saved before asking for the others', or this peer
+        // could vote again in the same term after a restart.
+        self.persist();
+
+        self.reset_election_timer();
+        self.start_election(campaign_type);
+    }
+
+    // for leader to step down when it is cut off from a quorum
+    fn check_quorum(&mut self) {
+        if !self.config.check_quorum {
+            return;
+        }
+        let me = self.me;
+        if let RoleState::Leader { active, .. } = &mut self.role {
+            let membership = &self.soft_state.membership;
+            if membership.has_quorum(|id| id == me || active.contains(&id)) {
+                active.clear();
+            } else {
+                debug!("[check_quorum! {}] lost the quorum, stepping down", self.me);
+                self.turn_follower();
             }
-            _ => {} // no timeout for leader
         }
     }
 
     // for leader to make sure peers are still alive
     fn handle_heartbeat(&mut self) {
+        let now = self.now();
+        let timeout = self.config.election_timeout.start;
+        if let RoleState::Leader {
+            transferee,
+            transfer_aborted,
+            ..
+        } = &mut self.role
+        {
+            if transferee.map_or(false, |(_, at)| {
+                now.saturating_duration_since(at) >= timeout
+            }) {
+                debug!("[handle_heartbeat! {}] leader transfer timed out", self.me);
+                *transferee = None;
+                *transfer_aborted = Some(now);
+            }
+        }
+        self.advance_membership();
         match self.role {
             RoleState::Leader { .. } => self.heart_beat_sync_log(),
             _ => {} // no heartbeat for follower and candidate
@@ -351,36 +976,176 @@ impl Raft {
     }
 
     // sync log from leader to follower when heartbeat
+    //
+    // Every peer is sent a message, whatever is in flight to it, which
+    // also brings back a peer whose next index ran ahead of its log.
     fn heart_beat_sync_log(&mut self) {
-        if let RoleState::Leader { next_index, .. } = &self.role {
-            for (i, peer) in self.other_peers() {
-                let tx = self.event_loop_tx().clone();
-                let args = self.append_entries_args(next_index[i]);
-                let fut = peer.append_entries(&args);
-                let new_next_index = self.hard_state.log.len();
-
-                self.executor
-                    .spawn(async move {
-                        let reply = fut.await;
-                        let _ = tx.unbounded_send(Event::AppendEntriesReply {
-                            from: i,
-                            reply,
-                            new_next_index,
-                        });
-                    })
-                    .unwrap();
+        if !matches!(self.role, RoleState::Leader { .. }) {
+            return;
+        }
+        let peers: Vec<usize> = self.other_peers().map(|(i, _)| i).collect();
+        let now = self.now();
+        let timeout = self.config.election_timeout.start;
+        let mut snapshot = None;
+        for i in peers {
+            if self.next_index(i) <= self.hard_state.last_included_index {
+                // the entries the peer lacks are compacted.
+                if let RoleState::Leader {
+                    snapshot_inflight, ..
+                } = &mut self.role
+                {
+                    // a chunk lost to a partition may take long to fail, so
+                    // a transfer which has not finished for a while is
+                    // started over.
+                    if snapshot_inflight[i]
+                        .map_or(false, |at| now.saturating_duration_since(at) < timeout)
+                    {
+                        continue;
+                    }
+                    snapshot_inflight[i] = Some(now);
+                }
+                let snapshot = snapshot.get_or_insert_with(|| Arc::new(self.storage.snapshot()));
+                self.send_snapshot(i, &self.peers[i], Arc::clone(snapshot));
+            } else {
+                self.send_append_entries(i);
+            }
+        }
+    }
+
+    /// Sends the new entries to every other member whose window has room.
+    fn replicate_to_all(&mut self) {
+        let peers: Vec<usize> = self.other_peers().map(|(i, _)| i).collect();
+        for i in peers {
+            self.replicate(i);
+        }
+    }
+
+    /// Keeps sending batches of new entries to a peer, while fewer than
+    /// `MAX_INFLIGHT` are in flight.
+    fn replicate(&mut self, to: usize) {
+        loop {
+            let next = self.next_index(to);
+            match &self.role {
+                RoleState::Leader { inflight, .. }
+                    if inflight[to] < MAX_INFLIGHT
+                        && next <= self.last_log_index()
+                        && next > self.hard_state.last_included_index => {}
+                _ => return,
             }
+            self.send_append_entries(to);
         }
     }
 
+    /// Sends a batch of entries from the next index of a peer, and moves the
+    /// next index past them without waiting for the reply.
+    fn send_append_entries(&mut self, to: usize) {
+        let args = self.append_entries_args(self.next_index(to));
+        let new_next_index = (args.prev_log_index + args.entries.len() as u64) as usize + 1;
+        if let RoleState::Leader {
+            next_index,
+            inflight,
+            ..
+        } = &mut self.role
+        {
+            next_index[to] = new_next_index;
+            inflight[to] += 1;
+        }
+
+        let tx = self.event_loop_tx().clone();
+        let term = self.hard_state.current_term;
+        let sent_at = self.now();
+        let fut = self.peers[to].append_entries(&args);
+        self.executor
+            .spawn(async move {
+                let reply = fut.await;
+                let _ = tx.unbounded_send(Event::AppendEntriesReply {
+                    from: to,
+                    reply,
+                    new_next_index,
+                    term,
+                    sent_at,
+                });
+            })
+            .unwrap();
+    }
+
+    fn next_index(&self, peer: usize) -> u64 {
+        match &self.role {
+            RoleState::Leader { next_index, .. } => next_index[peer] as u64,
+            _ => unreachable!("only a leader tracks the next index of its peers"),
+        }
+    }
+
+    /// Sends the snapshot to a peer lagging behind it, one chunk at a time.
+    ///
+    /// Only one transfer runs per peer. It stops at the first chunk which
+    /// fails or meets a higher term, and the next heartbeat starts it over.
+    fn send_snapshot(&self, to: usize, peer: &RaftClient, snapshot: Arc<Vec<u8>>) {
+        let tx = self.event_loop_tx().clone();
+        let peer = peer.clone();
+        let term = self.hard_state.current_term;
+        let leader_id = self.me as u64;
+        let last_included_index = self.hard_state.last_included_index;
+        let last_included_term = self.hard_state.last_included_term();
+        let membership = self.hard_state.membership.clone();
+        let chunk_size = self.config.snapshot_chunk_size;
+
+        self.executor
+            .spawn(async move {
+                let mut offset = 0;
+                loop {
+                    let end = snapshot.len().min(offset + chunk_size);
+                    let args = InstallSnapshotArgs {
+                        term,
+                        leader_id,
+                        last_included_index,
+                        last_included_term,
+                        offset: offset as u64,
+                        data: snapshot[offset..end].to_vec(),
+                        done: end == snapshot.len(),
+                        membership: membership.clone(),
+                    };
+                    let reply = peer.install_snapshot(&args).await;
+                    match &reply {
+                        Ok(reply) if !args.done && reply.term <= term => offset = end,
+                        _ => {
+                            let _ = tx.unbounded_send(Event::InstallSnapshotReply {
+                                from: to,
+                                reply,
+                                last_included_index,
+                            });
+                            break;
+                        }
+                    }
+                }
+            })
+            .unwrap();
+    }
+
     fn handle_request_vote_request(
         &mut self,
         args: RequestVoteArgs,
     ) -> labrpc::Result<RequestVoteReply> {
-        println!("[handle_request_vote_request! {}] {:?}", self.me, args);
+        debug!("[handle_request_vote_request! {}] {:?}", self.me, args);
+        if args.pre_vote {
+            // nothing changes, the candidate only learns whether it could win.
+            let vote_granted = args.term > self.hard_state.current_term
+                && self.is_up_to_date(args.last_log_term, args.last_log_index)
+                && !self.has_live_leader();
+            return Ok(RequestVoteReply {
+                term: self.hard_state.current_term,
+                vote_granted,
+            });
+        }
         let vote_granted = {
             if self.hard_state.current_term > args.term {
                 None
+            } else if self.lease_clock_drift.is_some()
+                && self.has_live_leader()
+                && !args.leader_transfer
+            {
+                // the leader may be serving reads from its lease.
+                None
             } else {
                 if args.term > self.hard_state.current_term {
                     self.update_term(args.term);
@@ -391,16 +1156,18 @@ impl Raft {
                 let not_voted_other =
                     self.hard_state.voted_for.map(|v| v == voted_id) != Some(false);
                 // cand's log must be more up-to-date
-                let cand_up_to_date = (args.last_log_term, args.last_log_index)
-                    >= (self.last_log_term(), self.last_log_index());
+                let cand_up_to_date = self.is_up_to_date(args.last_log_term, args.last_log_index);
 
                 if not_voted_other && cand_up_to_date {
+                    self.hard_state.voted_for = Some(voted_id);
+                    self.reset_election_timer();
                     Some(voted_id)
                 } else {
                     None
                 }
             }
         };
+        self.persist();
 
         Ok(RequestVoteReply {
             term: self.hard_state.current_term,
@@ -409,18 +1176,23 @@ impl Raft {
     }
 
     fn handle_request_vote_reply(&mut self, from: usize, reply: labrpc::Result<RequestVoteReply>) {
-        println!("[handle_request_vote_reply! {}] {:?}", self.me, reply);
+        debug!("[handle_request_vote_reply! {}] {:?}", self.me, reply);
         match reply {
             Ok(reply) => {
                 if reply.term > self.hard_state.current_term {
                     self.update_term(reply.term);
                     self.turn_follower();
+                    self.persist();
                 }
 
                 if let RoleState::Candidate { votes } = &mut self.role {
                     if reply.vote_granted && reply.term == self.hard_state.current_term {
                         votes.insert(from);
-                        if votes.len() > self.peers.len() / 2 {
+                        if self
+                            .soft_state
+                            .membership
+                            .has_quorum(|id| votes.contains(&id))
+                        {
                             self.turn_leader();
                             self.schedule_event(Event::HeartBeat);
                         }
@@ -428,7 +1200,36 @@ impl Raft {
                 }
             }
             Err(err) => {
-                println!("request vote -> err: {}", err)
+                debug!("request vote -> err: {}", err)
+            }
+        }
+    }
+
+    fn handle_pre_vote_reply(&mut self, from: usize, reply: labrpc::Result<RequestVoteReply>) {
+        debug!("[handle_pre_vote_reply! {}] {:?}", self.me, reply);
+        match reply {
+            Ok(reply) => {
+                if reply.term > self.hard_state.current_term {
+                    self.update_term(reply.term);
+                    self.turn_follower();
+                    self.persist();
+                }
+
+                if let RoleState::PreCandidate { votes } = &mut self.role {
+                    if reply.vote_granted {
+                        votes.insert(from);
+                        if self
+                            .soft_state
+                            .membership
+                            .has_quorum(|id| votes.contains(&id))
+                        {
+                            self.campaign(CampaignType::Election);
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                debug!("pre vote -> err: {}", err)
             }
         }
     }
@@ -437,16 +1238,20 @@ impl Raft {
         &mut self,
         args: AppendEntriesArgs,
     ) -> labrpc::Result<AppendEntriesReply> {
-        println!("[handle_append_entries! id: {}] {:?}", self.me, args);
+        debug!("[handle_append_entries! id: {}] {:?}", self.me, args);
         // let mut index = self.hard_state.log.len() as u64;
+        let mut conflict_term = 0;
+        let mut conflict_index = 0;
         let success = {
             if self.hard_state.current_term > args.term {
                 // index = self.soft_state.commit_index;
                 false
             } else {
                 if args.term > self.hard_state.current_term
-                    || matches!(self.role, RoleState::Candidate { .. })
-                        && args.term == self.hard_state.current_term
+                    || matches!(
+                        self.role,
+                        RoleState::PreCandidate { .. } | RoleState::Candidate { .. }
+                    ) && args.term == self.hard_state.current_term
                 {
                     self.update_term(args.term);
                     self.turn_follower();
@@ -454,35 +1259,67 @@ impl Raft {
 
                 match self.role {
                     RoleState::Follower => {
-                        self.schedule_event(Event::ResetTimeout);
+                        self.reset_election_timer();
+                        self.soft_state.leader_contact = Some(self.now());
 
                         // log replication
+                        // the entries covered by the snapshot are committed,
+                        // so they match the leader's.
+                        let mut args = args;
+                        let last_included_index = self.hard_state.last_included_index;
+                        if args.prev_log_index < last_included_index {
+                            let skipped = (last_included_index - args.prev_log_index) as usize;
+                            args.entries.drain(..skipped.min(args.entries.len()));
+                            args.prev_log_index = last_included_index;
+                            args.prev_log_term = self.hard_state.last_included_term();
+                        }
+
                         // if there is no conflict, append entries
                         // firstly make sure prevLogTerm and prevLogIndex match
                         if !self.is_match(args.prev_log_term, args.prev_log_index) {
+                            if args.prev_log_index > self.last_log_index() {
+                                conflict_index = self.last_log_index() + 1;
+                            } else {
+                                conflict_term = self.hard_state.term(args.prev_log_index).unwrap();
+                                conflict_index =
+                                    self.hard_state.first_index_of_term(args.prev_log_index);
+                            }
                             false
                         } else {
-                            // delete all entries after prevLogIndex
-                            while self.hard_state.log.len() as u64 > args.prev_log_index + 1 {
-                                self.hard_state.log.pop();
+                            let last_new_index = args.prev_log_index + args.entries.len() as u64;
+                            // delete the entries from the first conflicting one,
+                            // and append the new ones. A stale request must not
+                            // drop the entries after it.
+                            let mut membership_changed = false;
+                            let mut new_entries = Vec::new();
+                            for (i, entry) in args.entries.into_iter().enumerate() {
+                                let index = args.prev_log_index + 1 + i as u64;
+                                match self.hard_state.term(index) {
+                                    Some(term) if term == entry.term => continue,
+                                    Some(_) => {
+                                        self.truncate_log(index - 1);
+                                        membership_changed = true;
+                                    }
+                                    None => {}
+                                }
+                                membership_changed |= entry.membership.is_some();
+                                new_entries.push(entry);
+                            }
+                            if !new_entries.is_empty() {
+                                self.append_log(new_entries);
+                            }
+                            if membership_changed {
+                                self.reload_membership();
                             }
-                            // append entries
-                            self.hard_state.log.extend(args.entries);
 
-                            println!(
-                                "[handle_append_entries need to commit! id: {}]  now log is: {:?}",
-                                self.me, self.hard_state.log
-                            );
                             if args.leader_commit_index > self.soft_state.commit_index {
-                                let new_commit_index = args
-                                    .leader_commit_index
-                                    .min(self.hard_state.log.len() as u64 - 1);
+                                let new_commit_index = args.leader_commit_index.min(last_new_index);
                                 self.commit_to_new_index(new_commit_index);
                             }
                             true
                         }
                     }
-                    RoleState::Candidate { .. } => {
+                    RoleState::PreCandidate { .. } | RoleState::Candidate { .. } => {
                         unreachable!("candidate should turn into follower before")
                     }
                     RoleState::Leader { .. } => unreachable!("another leader with same term found"),
@@ -490,9 +1327,13 @@ impl Raft {
             }
         };
 
+        self.persist();
+
         Ok(AppendEntriesReply {
             term: self.hard_state.current_term,
             success,
+            conflict_term,
+            conflict_index,
         })
     }
 
@@ -501,39 +1342,240 @@ impl Raft {
         from: usize,
         reply: labrpc::Result<AppendEntriesReply>,
         index: usize,
+        term: u64,
+        sent_at: Instant,
     ) {
-        println!(
+        debug!(
             "[handle_append_entries_reply! id: {}] index: {}, reply: {:?}",
             self.me, index, reply
         );
+        if let RoleState::Leader { inflight, .. } = &mut self.role {
+            if term == self.hard_state.current_term {
+                inflight[from] = inflight[from].saturating_sub(1);
+            }
+        }
         match reply {
             Ok(reply) => {
                 if reply.term > self.hard_state.current_term {
                     self.update_term(reply.term);
                     self.turn_follower();
+                    self.persist();
                 }
 
                 if let RoleState::Leader {
                     next_index,
                     match_index,
+                    active,
+                    acks,
+                    transferee,
+                    ..
                 } = &mut self.role
                 {
+                    active.insert(from);
+                    if term == self.hard_state.current_term {
+                        // the peer still follows this leader.
+                        acks[from] = acks[from].max(Some(sent_at));
+                    }
                     if reply.success {
-                        match_index[from] = index - 1;
-                        next_index[from] = index;
+                        // replies may come out of order, and the next index
+                        // may be ahead of the entries in flight.
+                        match_index[from] = match_index[from].max(index - 1);
+                        next_index[from] = next_index[from].max(match_index[from] + 1);
+                        let caught_up = match_index[from] as u64 == self.hard_state.last_index();
+                        if caught_up && matches!(transferee, Some((to, _)) if *to == from) {
+                            self.send_timeout_now(from);
+                        }
                         self.maybe_commit();
-                    } else {
-                        next_index[from] = next_index[from].saturating_sub(1);
+                        self.replicate(from);
+                    } else if reply.term == self.hard_state.current_term
+                        && term == self.hard_state.current_term
+                    {
+                        // skip the whole conflicting term, or go back to the
+                        // end of the follower's log.
+                        let next = match self.hard_state.last_index_of_term(reply.conflict_term) {
+                            Some(index) if reply.conflict_term != 0 => index + 1,
+                            _ => reply.conflict_index,
+                        };
+                        next_index[from] = (next as usize).max(match_index[from] + 1);
+                        // retry from there without waiting for a heartbeat.
+                        self.replicate(from);
                     }
+                    self.advance_reads();
                 };
             }
             Err(err) => {
-                println!("[handle_append_entries_reply] err is: {}", err);
+                debug!("[handle_append_entries_reply] err is: {}", err);
             }
         }
     }
 }
 
+impl Raft {
+    fn handle_install_snapshot_request(
+        &mut self,
+        args: InstallSnapshotArgs,
+    ) -> labrpc::Result<InstallSnapshotReply> {
+        debug!(
+            "[handle_install_snapshot! id: {}] term: {}, index: {}, offset: {}",
+            self.me, args.term, args.last_included_index, args.offset
+        );
+        if args.term < self.hard_state.current_term {
+            return Ok(InstallSnapshotReply {
+                term: self.hard_state.current_term,
+                installed: false,
+            });
+        }
+        if args.term > self.hard_state.current_term
+            || matches!(
+                self.role,
+                RoleState::PreCandidate { .. } | RoleState::Candidate { .. }
+            )
+        {
+            self.update_term(args.term);
+            self.turn_follower();
+            self.persist();
+        }
+        self.reset_election_timer();
+        self.soft_state.leader_contact = Some(self.now());
+
+        // gather the chunks. A chunk sent again, e.g. by a transfer started
+        // over, replaces the data from its offset on.
+        let offset = args.offset as usize;
+        let end = offset + args.data.len();
+        let incoming = &mut self.soft_state.incoming_snapshot;
+        match incoming {
+            Some(snapshot)
+                if snapshot.last_included_index == args.last_included_index
+                    && snapshot.last_included_term == args.last_included_term
+                    && offset <= snapshot.data.len() =>
+            {
+                snapshot.data.truncate(offset);
+                snapshot.data.extend_from_slice(&args.data);
+            }
+            _ if offset == 0 => {
+                *incoming = Some(IncomingSnapshot {
+                    last_included_index: args.last_included_index,
+                    last_included_term: args.last_included_term,
+                    membership: args.membership,
+                    data: args.data,
+                })
+            }
+            // a chunk is missing, wait for the transfer to start over.
+            _ => {}
+        }
+        let mut installed = false;
+        if args.done {
+            // the last chunk was only added if none is missing before it.
+            match self.soft_state.incoming_snapshot.take() {
+                Some(snapshot)
+                    if snapshot.last_included_index == args.last_included_index
+                        && snapshot.data.len() == end =>
+                {
+                    self.install_snapshot(snapshot);
+                    installed = true;
+                }
+                incoming => self.soft_state.incoming_snapshot = incoming,
+            }
+        }
+
+        Ok(InstallSnapshotReply {
+            term: self.hard_state.current_term,
+            installed,
+        })
+    }
+
+    /// Replaces the log up to the snapshot, and hands the snapshot to the
+    /// service.
+    fn install_snapshot(&mut self, snapshot: IncomingSnapshot) {
+        let IncomingSnapshot {
+            last_included_index,
+            last_included_term,
+            membership,
+            data,
+        } = snapshot;
+        // the entries are already committed, and sent to the service.
+        if last_included_index <= self.soft_state.commit_index {
+            return;
+        }
+        self.hard_state
+            .compact(last_included_index, last_included_term);
+        self.hard_state.membership = membership;
+        self.reload_membership();
+        self.soft_state.commit_index = last_included_index;
+        self.soft_state.last_applied = last_included_index;
+        self.persist_with_snapshot(data.clone());
+
+        let msg = ApplyMsg::Snapshot {
+            data,
+            term: last_included_term,
+            index: last_included_index,
+        };
+        let _ = self.apply_ch.unbounded_send(msg);
+    }
+
+    fn handle_install_snapshot_reply(
+        &mut self,
+        from: usize,
+        reply: labrpc::Result<InstallSnapshotReply>,
+        last_included_index: u64,
+    ) {
+        if let RoleState::Leader {
+            snapshot_inflight, ..
+        } = &mut self.role
+        {
+            snapshot_inflight[from] = None;
+        }
+        match reply {
+            Ok(reply) => {
+                if reply.term > self.hard_state.current_term {
+                    self.update_term(reply.term);
+                    self.turn_follower();
+                    self.persist();
+                    return;
+                }
+                if let RoleState::Leader {
+                    next_index,
+                    match_index,
+                    active,
+                    ..
+                } = &mut self.role
+                {
+                    active.insert(from);
+                    // otherwise a chunk went missing, and the next heartbeat
+                    // sends the snapshot again.
+                    if reply.installed {
+                        match_index[from] = match_index[from].max(last_included_index as usize);
+                        next_index[from] = next_index[from].max(match_index[from] + 1);
+                    }
+                }
+            }
+            Err(err) => {
+                debug!("[handle_install_snapshot_reply] err is: {}", err);
+            }
+        }
+    }
+}
+
+impl Raft {
+    fn handle_timeout_now_request(
+        &mut self,
+        args: TimeoutNowArgs,
+    ) -> labrpc::Result<TimeoutNowReply> {
+        debug!("[handle_timeout_now! {}] {:?}", self.me, args);
+        if args.term >= self.hard_state.current_term && self.soft_state.membership.is_voter(self.me)
+        {
+            if args.term > self.hard_state.current_term {
+                self.update_term(args.term);
+            }
+            self.campaign(CampaignType::Transfer);
+            self.persist();
+        }
+        Ok(TimeoutNowReply {
+            term: self.hard_state.current_term,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct Node {
     // Your code here.
@@ -573,16 +1615,21 @@ impl Node {
 
         self.executor
             .spawn(async move {
-                // We suggest ElectionTick = 10 * HeartbeatTick to avoid
-                // unnecessary leader switching.
-                let build_rand_timeout_timer = || {
-                    futures_timer::Delay::new(Duration::from_millis(
-                        rand::thread_rng().gen_range(1000, 1500),
-                    ))
-                    .fuse()
+                let (logical, heartbeat_interval) = {
+                    let raft = raft.lock().unwrap();
+                    (raft.clock.is_some(), raft.config.heartbeat_interval)
+                };
+                // the timers of a logical clock fire on `Node::tick` instead.
+                let build_timer = move |duration| {
+                    if logical {
+                        Fuse::terminated()
+                    } else {
+                        futures_timer::Delay::new(duration).fuse()
+                    }
                 };
-                let build_heartbeat_timer =
-                    || futures_timer::Delay::new(Duration::from_millis(100)).fuse();
+                let build_rand_timeout_timer =
+                    || build_timer(raft.lock().unwrap().random_election_timeout());
+                let build_heartbeat_timer = || build_timer(heartbeat_interval);
 
                 let mut timeout_timer = build_rand_timeout_timer();
                 let mut heartbeat_timer = build_heartbeat_timer();
@@ -606,6 +1653,7 @@ impl Node {
                         _ = shutdown_rx => {
                             let mut raft = raft.lock().unwrap();
                             raft.handle_event(Event::ForcePersist);
+                            break;
                         }
                     }
                 }
@@ -651,6 +1699,76 @@ impl Node {
         matches!(self.raft.lock().unwrap().role, RoleState::Leader { .. })
     }
 
+    /// Asks the leader for the index a linearizable read has to wait for.
+    ///
+    /// The leader confirms it still leads with a round of heartbeats, or by
+    /// its lease if lease reads are on, and resolves with its commit index
+    /// from when the read was asked. Once the service has applied up to it,
+    /// it can serve the read from its state without writing to the log.
+    ///
+    /// It fails with [`Error::NotLeader`] if this peer is not the leader or
+    /// loses the leadership, and with [`Error::NotReady`] if the leader has
+    /// not committed an entry of its term yet; the read should then go
+    /// through the log.
+    pub fn read_index(&self) -> impl Future<Output = Result<u64>> {
+        let rx = self.raft.lock().unwrap().read_index();
+        rx.map(|res| res.unwrap_or(Err(Error::NotLeader)))
+    }
+
+    /// Adds peer `id`, an index into the `peers` given to `Raft::new`, to the
+    /// cluster.
+    ///
+    /// It joins as a learner, which is sent the log but does not vote, and
+    /// the leader makes it a voter through joint consensus once it has caught
+    /// up. This returns once the change is proposed, and fails with
+    /// [`Error::MembershipChanging`] while another change is in progress.
+    /// Adding a member again does nothing.
+    pub fn add_peer(&self, id: usize) -> Result<()> {
+        self.raft.lock().unwrap().add_peer(id)
+    }
+
+    /// Removes peer `id` from the cluster, through joint consensus if it
+    /// votes. A leader removing itself steps down once the change commits.
+    ///
+    /// Like `add_peer`, this returns once the change is proposed, and
+    /// removing a peer which is not a member does nothing.
+    pub fn remove_peer(&self, id: usize) -> Result<()> {
+        self.raft.lock().unwrap().remove_peer(id)
+    }
+
+    /// Hands over the leadership to `target`, e.g. before restarting this
+    /// peer, without waiting for an election timeout.
+    ///
+    /// The leader stops taking proposals, brings the target up to date, and
+    /// tells it to campaign at once. It returns once the transfer started,
+    /// which is aborted if the target has not taken over after an election
+    /// timeout.
+    pub fn transfer_leader(&self, target: usize) -> Result<()> {
+        self.raft.lock().unwrap().transfer_leader(target)
+    }
+
+    /// The latest membership in this peer's log, which may not be committed
+    /// yet.
+    pub fn membership(&self) -> Membership {
+        self.raft.lock().unwrap().soft_state.membership.clone()
+    }
+
+    /// The size of the persisted raft state, which the service bounds by
+    /// taking snapshots.
+    pub fn raft_state_size(&self) -> usize {
+        self.raft.lock().unwrap().storage.size()
+    }
+
+    /// Moves the logical clock of this peer forward by `elapsed`, and runs
+    /// the elections and heartbeats which are due before returning.
+    ///
+    /// # Panics
+    ///
+    /// It panics if the peer was not created with [`Clock::Logical`].
+    pub fn tick(&self, elapsed: Duration) {
+        self.raft.lock().unwrap().tick(elapsed)
+    }
+
     /// The current state of this peer.
     pub fn get_state(&self) -> State {
         State {
@@ -684,10 +1802,11 @@ impl Node {
         last_included_index: u64,
         snapshot: &[u8],
     ) -> bool {
-        // Your code here.
-        // Example:
-        // self.raft.cond_install_snapshot(last_included_term, last_included_index, snapshot)
-        crate::your_code_here((last_included_term, last_included_index, snapshot));
+        self.raft.lock().unwrap().cond_install_snapshot(
+            last_included_term,
+            last_included_index,
+            snapshot,
+        )
     }
 
     /// The service says it has created a snapshot that has all info up to and
@@ -695,10 +1814,7 @@ impl Node {
     /// (and including) that index. Raft should now trim its log as much as
     /// possible.
     pub fn snapshot(&self, index: u64, snapshot: &[u8]) {
-        // Your code here.
-        // Example:
-        // self.raft.snapshot(index, snapshot)
-        crate::your_code_here((index, snapshot));
+        self.raft.lock().unwrap().snapshot(index, snapshot)
     }
 }
 
@@ -719,4 +1835,19 @@ impl RaftService for Node {
         let mut raft = self.raft.lock().unwrap();
         raft.handle_append_entries_request(args)
     }
+
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn install_snapshot(
+        &self,
+        args: InstallSnapshotArgs,
+    ) -> labrpc::Result<InstallSnapshotReply> {
+        let mut raft = self.raft.lock().unwrap();
+        raft.handle_install_snapshot_request(args)
+    }
+
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> labrpc::Result<TimeoutNowReply> {
+        let mut raft = self.raft.lock().unwrap();
+        raft.handle_timeout_now_request(args)
+    }
 }
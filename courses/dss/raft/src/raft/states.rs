@@ -1,5 +1,8 @@
 use crate::proto::raftpb::*;
+use crate::raft::errors::Result;
+use futures::channel::oneshot;
 use std::collections::HashSet;
+use std::time::Instant;
 /// State of a raft peer.
 #[derive(Default, Clone, Debug)]
 pub struct State {
@@ -24,10 +27,19 @@ pub enum Event {
     ElectionTimeout,
     HeartBeat,
     RequestVoteReply(usize, labrpc::Result<RequestVoteReply>),
+    PreVoteReply(usize, labrpc::Result<RequestVoteReply>),
     AppendEntriesReply {
         from: usize,
         reply: labrpc::Result<AppendEntriesReply>,
         new_next_index: usize,
+        // the term and time the request was sent at.
+        term: u64,
+        sent_at: Instant,
+    },
+    InstallSnapshotReply {
+        from: usize,
+        reply: labrpc::Result<InstallSnapshotReply>,
+        last_included_index: u64,
     },
     ForcePersist,
 }
@@ -35,23 +47,70 @@ pub enum Event {
 #[derive(Debug)]
 pub enum RoleState {
     Follower,
+    // asking for pre-votes, before starting an election.
+    PreCandidate {
+        votes: HashSet<usize>,
+    },
     Candidate {
         votes: HashSet<usize>,
     },
     Leader {
         next_index: Vec<usize>,
         match_index: Vec<usize>,
+        // the AppendEntries sent to each peer and not answered yet.
+        inflight: Vec<usize>,
+        // when the snapshot being sent to each peer was sent, if any.
+        snapshot_inflight: Vec<Option<Instant>>,
+        // the peers which replied since the last quorum check.
+        active: HashSet<usize>,
+        // when the latest heartbeat of this term each peer replied to was
+        // sent.
+        acks: Vec<Option<Instant>>,
+        // the reads waiting for a quorum to confirm the leadership.
+        reads: Vec<PendingRead>,
+        // the peer the leadership is being handed over to, and since when.
+        transferee: Option<(usize, Instant)>,
+        // when the last transfer timed out. The transferee may still be
+        // campaigning, so the lease is not trusted for an election timeout.
+        transfer_aborted: Option<Instant>,
     },
 }
 
+/// Why a peer asks the others for votes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CampaignType {
+    // asking whether it could win, before starting an election.
+    PreVote,
+    Election,
+    // an election the leader asked for, to hand over its leadership.
+    Transfer,
+}
+
+/// A read waiting for the leader to confirm it still leads. It is answered
+/// with the commit index it was registered at.
+#[derive(Debug)]
+pub struct PendingRead {
+    pub index: u64,
+    pub registered_at: Instant,
+    pub tx: oneshot::Sender<Result<u64>>,
+}
+
 #[derive(Message)]
 pub struct PersistentState {
     #[prost(uint64, tag = "1")]
     pub current_term: u64,
     #[prost(uint64, optional, tag = "2")]
     pub voted_for: Option<u64>,
+    // log[0] is a dummy entry standing for the last entry included in the
+    // snapshot: its term is the last included term.
     #[prost(message, repeated, tag = "3")]
     pub log: Vec<Entry>,
+    // the index of log[0], the offset of the log.
+    #[prost(uint64, tag = "4")]
+    pub last_included_index: u64,
+    // the membership as of the last included entry.
+    #[prost(message, optional, tag = "5")]
+    pub membership: Option<Membership>,
 }
 
 impl PersistentState {
@@ -60,14 +119,126 @@ impl PersistentState {
             current_term: 0,
             voted_for: None,
             log: vec![Default::default()], // dummy entry at index 0
+            last_included_index: 0,
+            membership: None,
+        }
+    }
+
+    pub fn last_included_term(&self) -> u64 {
+        self.log[0].term
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.last_included_index + self.log.len() as u64 - 1
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.log.last().unwrap().term
+    }
+
+    /// The entry at `index`, if it is neither compacted nor beyond the log.
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.last_included_index {
+            return None;
+        }
+        self.log.get((index - self.last_included_index) as usize)
+    }
+
+    /// The term of the entry at `index`, including the last included one.
+    pub fn term(&self, index: u64) -> Option<u64> {
+        if index < self.last_included_index {
+            return None;
+        }
+        self.log
+            .get((index - self.last_included_index) as usize)
+            .map(|e| e.term)
+    }
+
+    /// The first index of the term of the entry at `index`, after the last
+    /// included index.
+    pub fn first_index_of_term(&self, index: u64) -> u64 {
+        let term = self.term(index);
+        let mut first = index;
+        while first > self.last_included_index + 1 && self.term(first - 1) == term {
+            first -= 1;
+        }
+        first
+    }
+
+    /// The index of the last entry of `term`, including the last included one.
+    pub fn last_index_of_term(&self, term: u64) -> Option<u64> {
+        let pos = self.log.iter().rposition(|e| e.term == term)?;
+        Some(self.last_included_index + pos as u64)
+    }
+
+    /// The latest membership at or before `index`, and the index of the
+    /// entry it is from.
+    pub fn membership_at(&self, index: u64) -> (u64, Membership) {
+        let last = (index.min(self.last_index()).max(self.last_included_index)
+            - self.last_included_index) as usize;
+        match self.log[..=last]
+            .iter()
+            .rposition(|e| e.membership.is_some())
+        {
+            Some(pos) if pos > 0 => (
+                self.last_included_index + pos as u64,
+                self.log[pos].membership.clone().unwrap(),
+            ),
+            _ => (
+                self.last_included_index,
+                self.membership.clone().unwrap_or_default(),
+            ),
         }
     }
+
+    /// The entries from `index` to the end of the log.
+    ///
+    /// `index` must be after the last included index.
+    pub fn entries_from(&self, index: u64) -> &[Entry] {
+        let start = (index - self.last_included_index) as usize;
+        &self.log[start.min(self.log.len())..]
+    }
+
+    /// Deletes the entries after `index`.
+    pub fn truncate(&mut self, index: u64) {
+        self.log
+            .truncate((index - self.last_included_index) as usize + 1);
+    }
+
+    /// Discards the entries up to `index`, which is now covered by a snapshot.
+    ///
+    /// The entries after it are kept if the log holds it with the same term,
+    /// otherwise the whole log is discarded, and the caller sets the
+    /// membership the snapshot was taken with.
+    pub fn compact(&mut self, index: u64, term: u64) {
+        if self.term(index) == Some(term) {
+            self.membership = Some(self.membership_at(index).1);
+            self.log
+                .drain(..(index - self.last_included_index) as usize);
+            self.log[0].data.clear();
+            self.log[0].membership = None;
+        } else {
+            self.log = vec![Entry {
+                term,
+                ..Default::default()
+            }];
+        }
+        self.last_included_index = index;
+    }
 }
 
 // SoftState provides state that is volatile and does not need to be persisted to the WAL.
 pub struct SoftState {
     pub commit_index: u64,
     pub last_applied: u64,
+    // the snapshot being received from the leader, chunk by chunk.
+    pub incoming_snapshot: Option<IncomingSnapshot>,
+    // when this peer last heard from a leader of its term.
+    pub leader_contact: Option<Instant>,
+    // the latest membership in the log, which is in effect even before it is
+    // committed, and the index of its entry.
+    pub membership: Membership,
+    pub membership_index: u64,
 }
 
 impl SoftState {
@@ -75,6 +246,60 @@ impl SoftState {
         Self {
             commit_index: 0,
             last_applied: 0,
+            incoming_snapshot: None,
+            leader_contact: None,
+            membership: Membership::default(),
+            membership_index: 0,
+        }
+    }
+}
+
+// the time of a peer whose clock only moves by ticks, and when its timers
+// fire next.
+pub struct LogicalClock {
+    pub now: Instant,
+    pub election_at: Instant,
+    pub heartbeat_at: Instant,
+}
+
+pub struct IncomingSnapshot {
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+    pub membership: Option<Membership>,
+    pub data: Vec<u8>,
+}
+
+impl Membership {
+    /// A membership of `voters` alone.
+    pub fn with_voters(voters: impl IntoIterator<Item = usize>) -> Membership {
+        Membership {
+            voters: voters.into_iter().map(|id| id as u64).collect(),
+            ..Default::default()
         }
     }
+
+    /// Whether `id` is a member, voting or not.
+    pub fn contains(&self, id: usize) -> bool {
+        self.is_voter(id) || self.learners.contains(&(id as u64))
+    }
+
+    /// Whether `id` votes, in the new or the old voters.
+    pub fn is_voter(&self, id: usize) -> bool {
+        let id = id as u64;
+        self.voters.contains(&id) || self.outgoing_voters.contains(&id)
+    }
+
+    /// Whether the membership is being changed with joint consensus.
+    pub fn is_joint(&self) -> bool {
+        !self.outgoing_voters.is_empty()
+    }
+
+    /// Whether the peers `acked` holds for make up a majority of the voters,
+    /// and of the outgoing voters too during a change.
+    pub fn has_quorum(&self, acked: impl Fn(usize) -> bool) -> bool {
+        let majority = |voters: &[u64]| {
+            voters.iter().filter(|&&id| acked(id as usize)).count() > voters.len() / 2
+        };
+        majority(&self.voters) && (!self.is_joint() || majority(&self.outgoing_voters))
+    }
 }
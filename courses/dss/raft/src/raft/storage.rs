@@ -0,0 +1,896 @@
+//! Where a Raft peer keeps its log, its term and its vote, and the snapshot
+//! covering the start of its log.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::file_persister::*;
+use super::persister::*;
+use super::states::PersistentState;
+use crate::proto::raftpb::{Entry, Membership};
+
+/// What a storage holds when a peer starts.
+#[derive(Debug, Default)]
+pub struct InitialState {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+    /// The last entry the snapshot covers, and its term.
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+    /// The membership as of the last included entry, `None` if nothing was
+    /// ever saved.
+    pub membership: Option<Membership>,
+    /// The entries after the last included one.
+    pub entries: Vec<Entry>,
+}
+
+/// Keeps the log of a Raft peer, its term and its vote, and the snapshot
+/// covering the start of the log.
+///
+/// Raft tells it about each change as it happens, so that it can write only
+/// what changed, and calls `sync` before it relies on them. Like the methods
+/// of [`Persister`], the methods cannot fail, and an I/O error panics.
+pub trait RaftLogStorage: Send + 'static {
+    /// The state saved before the peer restarted.
+    fn initial_state(&self) -> InitialState;
+    /// Saves the current term and the vote in it.
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>);
+    /// Appends `entries` right after the last entry.
+    fn append(&mut self, entries: &[Entry]);
+    /// Deletes the entries after `index`.
+    fn truncate_suffix(&mut self, index: u64);
+    /// Discards the entries up to `index`, which `snapshot` now covers, and
+    /// saves the membership as of `index`.
+    ///
+    /// The entries after it are kept if the log holds `index` with `term`,
+    /// otherwise the whole log is discarded.
+    fn compact_prefix(
+        &mut self,
+        index: u64,
+        term: u64,
+        membership: Option<Membership>,
+        snapshot: Vec<u8>,
+    );
+    /// Makes the changes since the last call durable. A storage which writes
+    /// each change as it happens has nothing to do.
+    fn sync(&mut self) {}
+    /// The latest snapshot.
+    fn snapshot(&self) -> Vec<u8>;
+    /// The bytes the entries after the snapshot take up, which the service
+    /// bounds by taking snapshots.
+    fn size(&self) -> usize;
+}
+
+/// Keeps the log as the raft state of a [`Persister`], the way `Raft::new`
+/// does.
+///
+/// A persister only saves the raft state whole, so the changes are gathered
+/// until `sync`, which encodes the whole log again.
+pub struct PersisterStorage {
+    persister: Box<dyn Persister>,
+    state: PersistentState,
+    // whether the state changed since it was last saved.
+    dirty: bool,
+    // the length of the raft state last saved.
+    size: usize,
+}
+
+impl PersisterStorage {
+    pub fn new(persister: Box<dyn Persister>) -> PersisterStorage {
+        let data = persister.raft_state();
+        let state = if data.is_empty() {
+            PersistentState::new()
+        } else {
+            labcodec::decode(&data).expect("invalid raft state")
+        };
+        PersisterStorage {
+            persister,
+            state,
+            dirty: false,
+            size: data.len(),
+        }
+    }
+
+    fn encode(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        labcodec::encode(&self.state, &mut data).unwrap();
+        self.size = data.len();
+        self.dirty = false;
+        data
+    }
+}
+
+impl RaftLogStorage for PersisterStorage {
+    fn initial_state(&self) -> InitialState {
+        InitialState {
+            term: self.state.current_term,
+            voted_for: self.state.voted_for,
+            last_included_index: self.state.last_included_index,
+            last_included_term: self.state.last_included_term(),
+            membership: self.state.membership.clone(),
+            entries: self.state.log[1..].to_vec(),
+        }
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) {
+        self.state.current_term = term;
+        self.state.voted_for = voted_for;
+        self.dirty = true;
+    }
+
+    fn append(&mut self, entries: &[Entry]) {
+        self.state.log.extend_from_slice(entries);
+        self.dirty = true;
+    }
+
+    fn truncate_suffix(&mut self, index: u64) {
+        self.state.truncate(index);
+        self.dirty = true;
+    }
+
+    fn compact_prefix(
+        &mut self,
+        index: u64,
+        term: u64,
+        membership: Option<Membership>,
+        snapshot: Vec<u8>,
+    ) {
+        self.state.compact(index, term);
+        self.state.membership = membership;
+        let data = self.encode();
+        self.persister.save_state_and_snapshot(data, snapshot);
+    }
+
+    fn sync(&mut self) {
+        if self.dirty {
+            let data = self.encode();
+            self.persister.save_raft_state(data);
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.persister.snapshot()
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Shares a storage with its owner, e.g. to look at its size while the peer
+/// runs.
+impl<S: RaftLogStorage> RaftLogStorage for Arc<Mutex<S>> {
+    fn initial_state(&self) -> InitialState {
+        self.lock().unwrap().initial_state()
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) {
+        self.lock().unwrap().save_hard_state(term, voted_for)
+    }
+
+    fn append(&mut self, entries: &[Entry]) {
+        self.lock().unwrap().append(entries)
+    }
+
+    fn truncate_suffix(&mut self, index: u64) {
+        self.lock().unwrap().truncate_suffix(index)
+    }
+
+    fn compact_prefix(
+        &mut self,
+        index: u64,
+        term: u64,
+        membership: Option<Membership>,
+        snapshot: Vec<u8>,
+    ) {
+        self.lock()
+            .unwrap()
+            .compact_prefix(index, term, membership, snapshot)
+    }
+
+    fn sync(&mut self) {
+        self.lock().unwrap().sync()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.lock().unwrap().snapshot()
+    }
+
+    fn size(&self) -> usize {
+        self.lock().unwrap().size()
+    }
+}
+
+const HARD_STATE_FILE: &str = "hard_state";
+const META_FILE: &str = "log_meta";
+const SEGMENT_PREFIX: &str = "segment.";
+// a new segment is started once the last one is this big.
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+// a segment starts with the index of its first entry.
+const SEGMENT_HEADER: u64 = 8;
+// a record starts with the length and the CRC-32 of the entry.
+const RECORD_HEADER: usize = 8;
+
+#[derive(Message)]
+struct HardState {
+    #[prost(uint64, tag = "1")]
+    term: u64,
+    #[prost(uint64, optional, tag = "2")]
+    voted_for: Option<u64>,
+}
+
+// where the log starts.
+#[derive(Message)]
+struct LogMeta {
+    #[prost(uint64, tag = "1")]
+    last_included_index: u64,
+    #[prost(uint64, tag = "2")]
+    last_included_term: u64,
+    #[prost(message, optional, tag = "3")]
+    membership: Option<Membership>,
+    // the number of the snapshot file, 0 if there is none.
+    #[prost(uint64, tag = "4")]
+    snapshot_id: u64,
+    // the segments numbered below it are discarded.
+    #[prost(uint64, tag = "5")]
+    first_segment: u64,
+}
+
+struct Segment {
+    id: u64,
+    first_index: u64,
+    // the term and the offset of each entry.
+    entries: Vec<(u64, u64)>,
+    len: u64,
+}
+
+impl Segment {
+    fn last_index(&self) -> u64 {
+        self.first_index + self.entries.len() as u64 - 1
+    }
+}
+
+/// A write-ahead log in the files of a directory, which writes only what
+/// changed.
+///
+/// The entries are appended to numbered segment files, and a new segment is
+/// started once the last one is 4 MiB. Each entry is a record starting with
+/// its length and CRC-32, and is synced before `append` returns, so a
+/// record torn by a crash is found and cut off when the directory is opened.
+///
+/// The term and the vote are kept in their own file. Compacting the log
+/// writes the snapshot, then the file saying where the log starts, which
+/// names the snapshot and the first segment still in use, then removes the
+/// older segments and snapshot. These files are replaced atomically like the
+/// ones of `FilePersister`.
+pub struct WalStorage {
+    dir: PathBuf,
+    meta: LogMeta,
+    hard_state: HardState,
+    snapshot: Vec<u8>,
+    segments: Vec<Segment>,
+    // the last segment, opened to append to it.
+    active: Option<File>,
+    next_segment: u64,
+    segment_size: u64,
+}
+
+impl WalStorage {
+    /// Opens the log stored in `dir`, creating the directory if needed.
+    ///
+    /// A record torn at the end of the last segment is cut off, and the files
+    /// left by a compaction which did not complete are removed. It fails with
+    /// `InvalidData` if any other record or file does not match its checksum.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<WalStorage> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let hard_state = match read_file(&dir.join(HARD_STATE_FILE)) {
+            Ok(data) => decode(&dir.join(HARD_STATE_FILE), &data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e),
+        };
+        let meta: LogMeta = match read_file(&dir.join(META_FILE)) {
+            Ok(data) => decode(&dir.join(META_FILE), &data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => LogMeta::default(),
+            Err(e) => return Err(e),
+        };
+        let snapshot = if meta.snapshot_id != 0 {
+            read_file(&dir.join(snapshot_file(meta.snapshot_id)))?
+        } else {
+            Vec::new()
+        };
+
+        // remove what an interrupted compaction left behind.
+        let current = snapshot_file(meta.snapshot_id);
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            let segment = name
+                .strip_prefix(SEGMENT_PREFIX)
+                .and_then(|id| id.parse::<u64>().ok());
+            match segment {
+                Some(id) if id >= meta.first_segment => ids.push(id),
+                Some(_) => fs::remove_file(dir.join(&*name))?,
+                None if name.ends_with(TEMP_SUFFIX)
+                    || name.starts_with(SNAPSHOT_PREFIX) && name != current.as_str() =>
+                {
+                    fs::remove_file(dir.join(&*name))?
+                }
+                None => {}
+            }
+        }
+        ids.sort_unstable();
+
+        let mut wal = WalStorage {
+            next_segment: ids.last().map_or(1, |id| id + 1).max(meta.first_segment),
+            dir,
+            meta,
+            hard_state,
+            snapshot,
+            segments: Vec::new(),
+            active: None,
+            segment_size: SEGMENT_SIZE,
+        };
+        for (i, &id) in ids.iter().enumerate() {
+            wal.load_segment(id, i + 1 == ids.len())?;
+        }
+        if let Some(segment) = wal.segments.last() {
+            let path = wal.dir.join(segment_file(segment.id));
+            wal.active = Some(OpenOptions::new().append(true).open(path)?);
+        }
+        Ok(wal)
+    }
+
+    /// Returns the directory the files are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Reads the entries of a segment. Only the last one may end with a torn
+    /// record, which is cut off.
+    fn load_segment(&mut self, id: u64, last: bool) -> io::Result<()> {
+        let path = self.dir.join(segment_file(id));
+        let data = fs::read(&path)?;
+        if data.len() < SEGMENT_HEADER as usize {
+            if !last {
+                return Err(invalid_data(&path));
+            }
+            // crashed while starting it.
+            return remove_file(&self.dir, &path);
+        }
+        let mut first_index = [0; 8];
+        first_index.copy_from_slice(&data[..8]);
+        let first_index = u64::from_le_bytes(first_index);
+        let expected = match self.segments.last() {
+            Some(segment) => first_index == segment.last_index() + 1,
+            None => first_index <= self.meta.last_included_index + 1,
+        };
+        if !expected {
+            return Err(invalid_data(&path));
+        }
+
+        let mut segment = Segment {
+            id,
+            first_index,
+            entries: Vec::new(),
+            len: SEGMENT_HEADER,
+        };
+        while let Some((entry, next)) = read_record(&data, segment.len as usize) {
+            segment.entries.push((entry.term, segment.len));
+            segment.len = next as u64;
+        }
+        if segment.len < data.len() as u64 {
+            if !last {
+                return Err(invalid_data(&path));
+            }
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(segment.len)?;
+            file.sync_all()?;
+        }
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    fn last_index(&self) -> u64 {
+        match self.segments.last() {
+            Some(segment) => segment.last_index(),
+            None => self.meta.last_included_index,
+        }
+    }
+
+    /// The term of the entry at `index`, including the last included one.
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.meta.last_included_index {
+            return Some(self.meta.last_included_term);
+        }
+        if index < self.meta.last_included_index {
+            return None;
+        }
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| (segment.first_index..=segment.last_index()).contains(&index))?;
+        Some(segment.entries[(index - segment.first_index) as usize].0)
+    }
+
+    fn start_segment(&mut self) -> io::Result<()> {
+        let id = self.next_segment;
+        let first_index = self.last_index() + 1;
+        let path = self.dir.join(segment_file(id));
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        file.write_all(&first_index.to_le_bytes())?;
+        file.sync_all()?;
+        File::open(&self.dir)?.sync_all()?;
+        self.next_segment += 1;
+        self.segments.push(Segment {
+            id,
+            first_index,
+            entries: Vec::new(),
+            len: SEGMENT_HEADER,
+        });
+        self.active = Some(file);
+        Ok(())
+    }
+
+    /// Writes the records in `buf` to the last segment.
+    fn flush(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        if let (Some(file), Some(segment)) = (&mut self.active, self.segments.last_mut()) {
+            if !buf.is_empty() {
+                file.write_all(buf)?;
+                file.sync_data()?;
+                segment.len += buf.len() as u64;
+                buf.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn try_append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            let full = match self.segments.last() {
+                Some(segment) => {
+                    !segment.entries.is_empty()
+                        && segment.len + buf.len() as u64 >= self.segment_size
+                }
+                None => true,
+            };
+            if full {
+                self.flush(&mut buf)?;
+                self.start_segment()?;
+            }
+            let segment = self.segments.last_mut().unwrap();
+            segment
+                .entries
+                .push((entry.term, segment.len + buf.len() as u64));
+            write_record(entry, &mut buf);
+        }
+        self.flush(&mut buf)
+    }
+
+    fn try_truncate_suffix(&mut self, index: u64) -> io::Result<()> {
+        if index >= self.last_index() {
+            return Ok(());
+        }
+        // the later segments go first, so the log stays contiguous.
+        while let Some(segment) = self.segments.last() {
+            if segment.first_index <= index {
+                break;
+            }
+            self.active = None;
+            remove_file(&self.dir, &self.dir.join(segment_file(segment.id)))?;
+            self.segments.pop();
+        }
+        if let Some(segment) = self.segments.last_mut() {
+            let path = self.dir.join(segment_file(segment.id));
+            let keep = (index + 1 - segment.first_index) as usize;
+            if keep < segment.entries.len() {
+                segment.len = segment.entries[keep].1;
+                segment.entries.truncate(keep);
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(segment.len)?;
+                file.sync_all()?;
+            }
+            if self.active.is_none() {
+                self.active = Some(OpenOptions::new().append(true).open(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn try_compact_prefix(
+        &mut self,
+        index: u64,
+        term: u64,
+        membership: Option<Membership>,
+        snapshot: Vec<u8>,
+    ) -> io::Result<()> {
+        // the segments from the one holding the next entry are kept.
+        let first_segment = match self.term(index) {
+            Some(t) if t == term => self
+                .segments
+                .iter()
+                .find(|segment| segment.last_index() > index)
+                .map_or(self.next_segment, |segment| segment.id),
+            _ => self.next_segment,
+        };
+        let old_snapshot_id = self.meta.snapshot_id;
+        let meta = LogMeta {
+            last_included_index: index,
+            last_included_term: term,
+            membership,
+            snapshot_id: old_snapshot_id + 1,
+            first_segment,
+        };
+        write_file(&self.dir, &snapshot_file(meta.snapshot_id), &snapshot)?;
+        let mut data = Vec::new();
+        labcodec::encode(&meta, &mut data).unwrap();
+        write_file(&self.dir, META_FILE, &data)?;
+        self.meta = meta;
+        self.snapshot = snapshot;
+
+        // the leftovers are removed on open anyway.
+        if old_snapshot_id != 0 {
+            let _ = fs::remove_file(self.dir.join(snapshot_file(old_snapshot_id)));
+        }
+        let dead = self
+            .segments
+            .iter()
+            .take_while(|segment| segment.id < first_segment)
+            .count();
+        for segment in self.segments.drain(..dead) {
+            let _ = fs::remove_file(self.dir.join(segment_file(segment.id)));
+        }
+        if self.segments.is_empty() {
+            self.active = None;
+        }
+        Ok(())
+    }
+}
+
+impl RaftLogStorage for WalStorage {
+    fn initial_state(&self) -> InitialState {
+        let mut entries = Vec::new();
+        for segment in &self.segments {
+            let path = self.dir.join(segment_file(segment.id));
+            let data = fs::read(&path)
+                .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+            for (i, &(_, offset)) in segment.entries.iter().enumerate() {
+                if segment.first_index + i as u64 > self.meta.last_included_index {
+                    let (entry, _) = read_record(&data, offset as usize)
+                        .unwrap_or_else(|| panic!("{} changed", path.display()));
+                    entries.push(entry);
+                }
+            }
+        }
+        InitialState {
+            term: self.hard_state.term,
+            voted_for: self.hard_state.voted_for,
+            last_included_index: self.meta.last_included_index,
+            last_included_term: self.meta.last_included_term,
+            membership: self.meta.membership.clone(),
+            entries,
+        }
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) {
+        let hard_state = HardState { term, voted_for };
+        let mut data = Vec::new();
+        labcodec::encode(&hard_state, &mut data).unwrap();
+        write_file(&self.dir, HARD_STATE_FILE, &data)
+            .unwrap_or_else(|e| panic!("failed to save the hard state: {}", e));
+        self.hard_state = hard_state;
+    }
+
+    fn append(&mut self, entries: &[Entry]) {
+        self.try_append(entries)
+            .unwrap_or_else(|e| panic!("failed to append to the log: {}", e))
+    }
+
+    fn truncate_suffix(&mut self, index: u64) {
+        self.try_truncate_suffix(index)
+            .unwrap_or_else(|e| panic!("failed to truncate the log: {}", e))
+    }
+
+    fn compact_prefix(
+        &mut self,
+        index: u64,
+        term: u64,
+        membership: Option<Membership>,
+        snapshot: Vec<u8>,
+    ) {
+        self.try_compact_prefix(index, term, membership, snapshot)
+            .unwrap_or_else(|e| panic!("failed to compact the log: {}", e))
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.snapshot.clone()
+    }
+
+    fn size(&self) -> usize {
+        let last_included_index = self.meta.last_included_index;
+        let size: u64 = self
+            .segments
+            .iter()
+            .map(|segment| {
+                let compacted = (last_included_index + 1).saturating_sub(segment.first_index);
+                let start = segment
+                    .entries
+                    .get(compacted as usize)
+                    .map_or(segment.len, |&(_, offset)| offset);
+                segment.len - start
+            })
+            .sum();
+        size as usize
+    }
+}
+
+fn segment_file(id: u64) -> String {
+    format!("{}{}", SEGMENT_PREFIX, id)
+}
+
+fn decode<M: labcodec::Message + Default>(path: &Path, data: &[u8]) -> io::Result<M> {
+    labcodec::decode(data).map_err(|_| invalid_data(path))
+}
+
+/// Removes a file, and makes it durable.
+fn remove_file(dir: &Path, path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    File::open(dir)?.sync_all()
+}
+
+fn write_record(entry: &Entry, buf: &mut Vec<u8>) {
+    let mut data = Vec::new();
+    labcodec::encode(entry, &mut data).unwrap();
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(&data).to_le_bytes());
+    buf.extend_from_slice(&data);
+}
+
+/// Reads the record at `offset`, and returns its entry and the offset of the
+/// next one, or `None` if it is torn or corrupted.
+fn read_record(data: &[u8], offset: usize) -> Option<(Entry, usize)> {
+    let header = data.get(offset..offset + RECORD_HEADER)?;
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[4..]);
+    let start = offset + RECORD_HEADER;
+    let record = data.get(start..start + u32::from_le_bytes(len) as usize)?;
+    if crc32(record) != u32::from_le_bytes(crc) {
+        return None;
+    }
+    let entry = labcodec::decode(record).ok()?;
+    Some((entry, start + record.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(terms: &[u64]) -> Vec<Entry> {
+        terms
+            .iter()
+            .map(|&term| Entry {
+                term,
+                data: vec![term as u8; 10],
+                membership: None,
+            })
+            .collect()
+    }
+
+    fn terms(storage: &dyn RaftLogStorage) -> Vec<u64> {
+        let state = storage.initial_state();
+        state.entries.iter().map(|entry| entry.term).collect()
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_persister_storage() {
+        let persister = Arc::new(SimplePersister::new());
+        let mut storage = PersisterStorage::new(Box::new(Arc::clone(&persister)));
+        assert!(storage.initial_state().membership.is_none());
+        storage.save_hard_state(2, Some(1));
+        storage.append(&entries(&[1, 1, 2]));
+        storage.truncate_suffix(2);
+        storage.append(&entries(&[2]));
+        // nothing is encoded before the changes are synced.
+        assert!(persister.raft_state().is_empty());
+        storage.sync();
+        assert_eq!(storage.size(), persister.raft_state().len());
+
+        let membership = Membership::with_voters(0..3);
+        storage.compact_prefix(1, 1, Some(membership.clone()), vec![7]);
+        let storage = PersisterStorage::new(Box::new(persister));
+        let state = storage.initial_state();
+        assert_eq!((state.term, state.voted_for), (2, Some(1)));
+        assert_eq!(state.last_included_index, 1);
+        assert_eq!(state.last_included_term, 1);
+        assert_eq!(state.membership, Some(membership));
+        assert_eq!(terms(&storage), [1, 2]);
+        assert_eq!(storage.snapshot(), vec![7]);
+    }
+
+    #[test]
+    fn test_wal_storage_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        let state = wal.initial_state();
+        assert_eq!((state.term, state.voted_for), (0, None));
+        assert!(state.membership.is_none());
+        assert!(state.entries.is_empty());
+        assert_eq!(wal.size(), 0);
+
+        wal.save_hard_state(3, Some(2));
+        wal.append(&entries(&[1, 2]));
+        wal.append(&entries(&[3]));
+        let wal = WalStorage::open(dir.path()).unwrap();
+        let state = wal.initial_state();
+        assert_eq!((state.term, state.voted_for), (3, Some(2)));
+        assert_eq!(state.entries, entries(&[1, 2, 3]));
+        assert_eq!(wal.size(), 3 * (RECORD_HEADER + 14));
+        assert!(wal.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_wal_storage_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        wal.segment_size = 50;
+        wal.append(&entries(&[1, 1, 1, 1, 1]));
+        // two entries fit in a segment.
+        assert_eq!(
+            file_names(dir.path()),
+            ["segment.1", "segment.2", "segment.3"]
+        );
+
+        wal.truncate_suffix(2);
+        assert_eq!(file_names(dir.path()), ["segment.1"]);
+        wal.truncate_suffix(1);
+        wal.append(&entries(&[2, 2]));
+        assert_eq!(terms(&wal), [1, 2, 2]);
+        // truncating after the last entry does nothing.
+        wal.truncate_suffix(5);
+
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(terms(&wal), [1, 2, 2]);
+        wal.truncate_suffix(0);
+        assert!(file_names(dir.path()).is_empty());
+        wal.append(&entries(&[3]));
+        let wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(terms(&wal), [3]);
+    }
+
+    #[test]
+    fn test_wal_storage_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        wal.segment_size = 50;
+        wal.append(&entries(&[1, 1, 2, 2, 2]));
+        let size = wal.size();
+
+        // the segment holding entry 4 is kept.
+        let membership = Membership::with_voters(0..3);
+        wal.compact_prefix(3, 2, Some(membership.clone()), vec![1]);
+        assert_eq!(wal.size(), size * 2 / 5);
+        assert_eq!(
+            file_names(dir.path()),
+            ["log_meta", "segment.2", "segment.3", "snapshot.1"]
+        );
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        let state = wal.initial_state();
+        assert_eq!(state.last_included_index, 3);
+        assert_eq!(state.last_included_term, 2);
+        assert_eq!(state.membership, Some(membership.clone()));
+        assert_eq!(terms(&wal), [2, 2]);
+        assert_eq!(wal.snapshot(), vec![1]);
+
+        // a snapshot the log disagrees with replaces the whole log.
+        wal.compact_prefix(4, 3, Some(membership), vec![2]);
+        assert_eq!(file_names(dir.path()), ["log_meta", "snapshot.2"]);
+        wal.append(&entries(&[3]));
+        let wal = WalStorage::open(dir.path()).unwrap();
+        let state = wal.initial_state();
+        assert_eq!(state.last_included_index, 4);
+        assert_eq!(state.entries, entries(&[3]));
+        assert_eq!(wal.snapshot(), vec![2]);
+    }
+
+    #[test]
+    fn test_wal_storage_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        wal.append(&entries(&[1, 1, 1]));
+        drop(wal);
+
+        // crashed while appending the last entry.
+        let segment = dir.path().join("segment.1");
+        let data = fs::read(&segment).unwrap();
+        fs::write(&segment, &data[..data.len() - 3]).unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(terms(&wal), [1, 1]);
+        wal.append(&entries(&[2]));
+        let wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(terms(&wal), [1, 1, 2]);
+        drop(wal);
+
+        // crashed while starting a segment.
+        fs::write(dir.path().join("segment.2"), [3, 0]).unwrap();
+        let wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(terms(&wal), [1, 1, 2]);
+        assert_eq!(file_names(dir.path()), ["segment.1"]);
+    }
+
+    #[test]
+    fn test_wal_storage_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        wal.segment_size = 50;
+        wal.append(&entries(&[1, 1, 1]));
+        drop(wal);
+
+        // only the end of the last segment may be torn.
+        let segment = dir.path().join("segment.1");
+        let mut data = fs::read(&segment).unwrap();
+        data[SEGMENT_HEADER as usize + RECORD_HEADER] ^= 1;
+        fs::write(&segment, &data).unwrap();
+        let err = WalStorage::open(dir.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a segment is missing.
+        fs::remove_file(&segment).unwrap();
+        fs::write(dir.path().join("segment.3"), 5u64.to_le_bytes()).unwrap();
+        let err = WalStorage::open(dir.path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_wal_storage_crash_mid_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        wal.segment_size = 50;
+        wal.append(&entries(&[1, 1, 1, 1]));
+        wal.compact_prefix(1, 1, None, vec![1]);
+        drop(wal);
+
+        // crashed after the new snapshot, before the meta naming it.
+        write_file(dir.path(), "snapshot.2", &[2]).unwrap();
+        fs::write(dir.path().join("log_meta.tmp"), [0xff; 3]).unwrap();
+        let wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(wal.snapshot(), vec![1]);
+        assert_eq!(terms(&wal), [1, 1, 1]);
+        assert_eq!(
+            file_names(dir.path()),
+            ["log_meta", "segment.1", "segment.2", "snapshot.1"]
+        );
+        drop(wal);
+
+        // crashed after the meta, before removing the old files.
+        let mut wal = WalStorage::open(dir.path()).unwrap();
+        wal.compact_prefix(3, 1, None, vec![3]);
+        write_file(dir.path(), "snapshot.1", &[1]).unwrap();
+        fs::write(dir.path().join("segment.1"), 1u64.to_le_bytes()).unwrap();
+        let wal = WalStorage::open(dir.path()).unwrap();
+        assert_eq!(wal.snapshot(), vec![3]);
+        assert_eq!(terms(&wal), [1]);
+        assert_eq!(
+            file_names(dir.path()),
+            ["log_meta", "segment.2", "snapshot.2"]
+        );
+    }
+}
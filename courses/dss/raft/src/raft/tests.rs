@@ -1,18 +1,22 @@
 #![allow(clippy::identity_op)]
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::channel::oneshot;
 use futures::executor::block_on;
 use futures::future;
 use rand::{rngs::ThreadRng, Rng};
 
+use labrpc::RpcHooks;
+
+use crate::proto::raftpb::InstallSnapshotArgs;
 use crate::raft::config::{Config, Entry, Storage, SNAPSHOT_INTERVAL};
-use crate::raft::Node;
+use crate::raft::errors::{Error, Result};
+use crate::raft::{Clock, Node, RaftConfig};
 
 /// The tester generously allows solutions to complete elections in one second
 /// (much more than the paper's range of timeouts).
@@ -120,6 +124,120 @@ fn test_many_election_2a() {
     cfg.end();
 }
 
+#[test]
+fn test_pre_vote_rejoin_2a() {
+    let servers = 3;
+    let mut cfg = Config::new(servers);
+
+    cfg.begin("Test (2A): partitioned follower rejoins without disrupting the leader");
+
+    cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+    let term1 = cfg.check_terms();
+
+    // the follower can not get pre-votes alone, so it never starts an election
+    let follower = (leader1 + 1) % servers;
+    cfg.disconnect(follower);
+    thread::sleep(3 * RAFT_ELECTION_TIMEOUT);
+    let term = cfg.rafts.lock().unwrap()[follower].as_ref().unwrap().term();
+    if term != term1 {
+        panic!("partitioned follower moved from term {} to {}", term1, term);
+    }
+
+    // and the leader keeps its place when it comes back
+    cfg.connect(follower);
+    cfg.one(Entry { x: 102 }, servers, true);
+    let leader2 = cfg.check_one_leader();
+    if leader2 != leader1 {
+        panic!("leader changed from {} to {} on rejoin", leader1, leader2);
+    }
+    let term2 = cfg.check_terms();
+    if term2 != term1 {
+        panic!("term changed from {} to {} on rejoin", term1, term2);
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_check_quorum_2a() {
+    let servers = 5;
+    let mut cfg = Config::new(servers);
+
+    cfg.begin("Test (2A): leader cut off from a quorum steps down");
+
+    cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+
+    // the leader keeps a follower, but no quorum
+    for i in 2..servers {
+        cfg.disconnect((leader1 + i) % servers);
+    }
+    thread::sleep(3 * RAFT_ELECTION_TIMEOUT);
+    cfg.check_no_leader();
+    let _ = cfg.rafts.lock().unwrap()[leader1]
+        .as_ref()
+        .unwrap()
+        .start(&Entry { x: 102 })
+        .expect_err("deposed leader accepted a command");
+
+    // the cluster recovers once healed
+    for i in 2..servers {
+        cfg.connect((leader1 + i) % servers);
+    }
+    cfg.check_one_leader();
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
+// moves the logical clocks forward by `elapsed` in small ticks, leaving the
+// RPCs sent on each tick the time to be answered.
+fn run_ticks(cfg: &Config, elapsed: Duration) {
+    let tick = Duration::from_millis(10);
+    for _ in 0..elapsed.as_millis() / tick.as_millis() {
+        cfg.tick(tick);
+        thread::sleep(Duration::from_millis(2));
+    }
+}
+
+#[test]
+fn test_logical_clock_2a() {
+    let servers = 3;
+    let config = RaftConfig {
+        clock: Clock::Logical { seed: 1 },
+        ..RaftConfig::default()
+    };
+    let mut cfg = Config::new(servers);
+    cfg.set_raft_config(config.clone());
+
+    cfg.begin("Test (2A): elections driven by a logical clock");
+
+    // the timers only fire on ticks
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    cfg.check_no_leader();
+
+    run_ticks(&cfg, 2 * RAFT_ELECTION_TIMEOUT);
+    let leader1 = cfg.check_one_leader();
+    let term1 = cfg.check_terms();
+
+    // the heartbeats keep the leader in place
+    run_ticks(&cfg, 4 * RAFT_ELECTION_TIMEOUT);
+    assert_eq!(cfg.check_one_leader(), leader1);
+    assert_eq!(cfg.check_terms(), term1);
+
+    // the same seeds elect the same leader
+    let mut cfg2 = Config::new(servers);
+    cfg2.set_raft_config(config);
+    run_ticks(&cfg2, 2 * RAFT_ELECTION_TIMEOUT);
+    assert_eq!(
+        (cfg2.check_one_leader(), cfg2.check_terms()),
+        (leader1, term1)
+    );
+
+    cfg.end();
+}
+
 #[test]
 fn test_basic_agree_2b() {
     let servers = 5;
@@ -454,6 +572,56 @@ fn test_backup_2b() {
     cfg.end();
 }
 
+#[test]
+fn test_backup_rpc_count_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers);
+
+    cfg.begin("Test (2B): leader backs up over a long conflicting term in few RPCs");
+
+    let mut random = rand::thread_rng();
+    cfg.one(random_entry(&mut random), servers, true);
+
+    // the leader appends lots of entries alone
+    let leader1 = cfg.check_one_leader();
+    cfg.disconnect((leader1 + 1) % servers);
+    cfg.disconnect((leader1 + 2) % servers);
+    for _i in 0..100 {
+        let _ = cfg.rafts.lock().unwrap()[leader1]
+            .as_ref()
+            .unwrap()
+            .start(&random_entry(&mut random));
+    }
+
+    // the others move on without it
+    cfg.disconnect(leader1);
+    cfg.connect((leader1 + 1) % servers);
+    cfg.connect((leader1 + 2) % servers);
+    for _i in 0..5 {
+        cfg.one(random_entry(&mut random), servers - 1, true);
+    }
+    // and the old leader notices it lost the quorum, so no command is
+    // proposed to it once it is back
+    while cfg.rafts.lock().unwrap()[leader1]
+        .as_ref()
+        .unwrap()
+        .is_leader()
+    {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // a round trip per conflicting entry would take 100 RPCs
+    let before = cfg.rpc_count(leader1);
+    cfg.connect(leader1);
+    cfg.one(random_entry(&mut random), servers, true);
+    let rpcs = cfg.rpc_count(leader1) - before;
+    if rpcs > 20 {
+        panic!("too many RPCs ({}) to back up over 100 entries", rpcs);
+    }
+
+    cfg.end();
+}
+
 #[test]
 fn test_count_2b() {
     const SERVERS: usize = 3;
@@ -583,6 +751,329 @@ fn test_count_2b() {
     cfg.end();
 }
 
+#[test]
+fn test_replicate_at_once_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers);
+
+    cfg.begin("Test (2B): commands are replicated without waiting for heartbeats");
+
+    cfg.one(Entry { x: 100 }, servers, true);
+    let leader = cfg.check_one_leader();
+    let node = cfg.rafts.lock().unwrap()[leader].clone().unwrap();
+
+    // one heartbeat interval per command would take 2 seconds for the
+    // leader to commit them
+    let iters = 20;
+    let start = Instant::now();
+    for x in 0..iters {
+        let (index, _) = node.start(&Entry { x }).unwrap();
+        while cfg.n_committed(index).0 == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    if start.elapsed() > Duration::from_secs(1) {
+        panic!("{} commands took {:?} to commit", iters, start.elapsed());
+    }
+
+    // and a backlog is sent in bounded batches
+    let follower = (leader + 1) % servers;
+    cfg.disconnect(follower);
+    for x in 0..500 {
+        node.start(&Entry { x }).unwrap();
+    }
+    let (index, _) = node.start(&Entry { x: 500 }).unwrap();
+    cfg.wait(index, servers - 1, None);
+    cfg.connect(follower);
+    cfg.wait(index, servers, None);
+
+    cfg.end();
+}
+
+#[test]
+fn test_read_index_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers);
+
+    cfg.begin("Test (2B): read index");
+
+    let index = cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+    let node = |cfg: &Config, i: usize| cfg.rafts.lock().unwrap()[i].clone().unwrap();
+
+    // the leader answers with its commit index, the others refuse
+    assert_eq!(block_on(node(&cfg, leader1).read_index()), Ok(index));
+    let follower = (leader1 + 1) % servers;
+    assert_eq!(
+        block_on(node(&cfg, follower).read_index()),
+        Err(Error::NotLeader)
+    );
+
+    // a partitioned leader can not confirm its leadership
+    cfg.disconnect(leader1);
+    let stale_read = node(&cfg, leader1).read_index();
+    let leader2 = cfg.check_one_leader();
+
+    // nor can a new leader before it commits an entry of its term
+    assert_eq!(
+        block_on(node(&cfg, leader2).read_index()),
+        Err(Error::NotReady)
+    );
+    let index = cfg.one(Entry { x: 102 }, servers - 1, true);
+    assert_eq!(block_on(node(&cfg, leader2).read_index()), Ok(index));
+
+    // the old leader gives up once it steps down
+    assert_eq!(block_on(stale_read), Err(Error::NotLeader));
+
+    cfg.connect(leader1);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_lease_read_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers);
+    cfg.set_lease_read(Some(1.1));
+
+    cfg.begin("Test (2B): lease read");
+
+    let index = cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+    let node = cfg.rafts.lock().unwrap()[leader1].clone().unwrap();
+
+    // the lease covers a read right after the leader is cut off
+    for i in 1..servers {
+        cfg.disconnect((leader1 + i) % servers);
+    }
+    assert_eq!(block_on(node.read_index()), Ok(index));
+
+    // but not once it expires
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    assert_eq!(block_on(node.read_index()), Err(Error::NotLeader));
+
+    for i in 1..servers {
+        cfg.connect((leader1 + i) % servers);
+    }
+    cfg.one(Entry { x: 102 }, servers, true);
+
+    cfg.end();
+}
+
+// Asks the leader to change the membership until its voters are `voters`,
+// out of joint consensus.
+fn change_membership(cfg: &Config, voters: &[usize], change: impl Fn(&Node) -> Result<()>) {
+    let mut expected: Vec<u64> = voters.iter().map(|&id| id as u64).collect();
+    expected.sort_unstable();
+    for _ in 0..20 {
+        let leader = cfg.check_one_leader();
+        let node = cfg.rafts.lock().unwrap()[leader].clone().unwrap();
+        // it fails while another change is in progress
+        let _ = change(&node);
+        let membership = node.membership();
+        let mut voters = membership.voters.clone();
+        voters.sort_unstable();
+        if voters == expected && !membership.is_joint() {
+            return;
+        }
+    }
+    panic!("the voters did not become {:?}", expected);
+}
+
+#[test]
+fn test_membership_change_2b() {
+    let servers = 5;
+    let mut cfg = Config::new_with_voters(servers, 3);
+
+    cfg.begin("Test (2B): grow and shrink the cluster");
+
+    // the servers to be added are not up yet
+    cfg.disconnect(3);
+    cfg.disconnect(4);
+    cfg.one(Entry { x: 101 }, 3, true);
+
+    // grow to five voters, one at a time
+    cfg.connect(3);
+    change_membership(&cfg, &[0, 1, 2, 3], |node| node.add_peer(3));
+    cfg.one(Entry { x: 102 }, 4, true);
+    cfg.connect(4);
+    change_membership(&cfg, &[0, 1, 2, 3, 4], |node| node.add_peer(4));
+    cfg.one(Entry { x: 103 }, 5, true);
+
+    // a quorum is now three of the five
+    let leader = cfg.check_one_leader();
+    cfg.disconnect((leader + 1) % servers);
+    cfg.disconnect((leader + 2) % servers);
+    cfg.one(Entry { x: 104 }, 3, true);
+    cfg.connect((leader + 1) % servers);
+    cfg.connect((leader + 2) % servers);
+    cfg.one(Entry { x: 105 }, 5, true);
+
+    // the leader removes itself, and the others go on without it
+    let leader1 = cfg.check_one_leader();
+    let mut voters: Vec<usize> = (0..servers).filter(|&i| i != leader1).collect();
+    change_membership(&cfg, &voters, |node| node.remove_peer(leader1));
+    cfg.one(Entry { x: 106 }, 4, true);
+    cfg.disconnect(leader1);
+    cfg.one(Entry { x: 107 }, 4, true);
+
+    // then a follower
+    let leader2 = cfg.check_one_leader();
+    let follower = *voters.iter().find(|&&i| i != leader2).unwrap();
+    voters.retain(|&i| i != follower);
+    change_membership(&cfg, &voters, |node| node.remove_peer(follower));
+    cfg.disconnect(follower);
+    cfg.one(Entry { x: 108 }, 3, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_membership_change_partition_2b() {
+    let servers = 5;
+    let mut cfg = Config::new_with_voters(servers, 3);
+
+    cfg.begin("Test (2B): membership changes under partitions");
+
+    cfg.disconnect(3);
+    cfg.disconnect(4);
+    cfg.one(Entry { x: 101 }, 3, true);
+
+    // the leader is cut off while adding a peer, and the others finish
+    let leader1 = cfg.check_one_leader();
+    cfg.connect(3);
+    let node1 = cfg.rafts.lock().unwrap()[leader1].clone().unwrap();
+    node1.add_peer(3).unwrap();
+    cfg.disconnect(leader1);
+    change_membership(&cfg, &[0, 1, 2, 3], |node| node.add_peer(3));
+    cfg.one(Entry { x: 102 }, 3, true);
+    cfg.connect(leader1);
+    cfg.one(Entry { x: 103 }, 4, true);
+
+    // a removal proposed by a leader cut off from the quorum is dropped
+    let leader2 = cfg.check_one_leader();
+    let node2 = cfg.rafts.lock().unwrap()[leader2].clone().unwrap();
+    cfg.disconnect(leader2);
+    node2.remove_peer((leader2 + 1) % 4).unwrap();
+    cfg.one(Entry { x: 104 }, 3, true);
+    cfg.connect(leader2);
+    cfg.one(Entry { x: 105 }, 4, true);
+    change_membership(&cfg, &[0, 1, 2, 3], |_| Ok(()));
+    let voters = node2.membership().voters;
+    if voters.len() != 4 {
+        panic!("the old leader kept its membership change: {:?}", voters);
+    }
+
+    // and the cluster can still be changed
+    cfg.connect(4);
+    change_membership(&cfg, &[0, 1, 2, 3, 4], |node| node.add_peer(4));
+    cfg.one(Entry { x: 106 }, 5, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_transfer_leader_2b() {
+    let servers = 5;
+    let mut cfg = Config::new(servers);
+    // the followers refuse other votes while they hear from the leader
+    cfg.set_lease_read(Some(1.1));
+
+    cfg.begin("Test (2B): leadership transfer");
+
+    let node = |cfg: &Config, i: usize| cfg.rafts.lock().unwrap()[i].clone().unwrap();
+    // well before an election timeout
+    let takes_over = |cfg: &Config, target: usize| {
+        let start = Instant::now();
+        while start.elapsed() < RAFT_ELECTION_TIMEOUT / 2 {
+            if node(cfg, target).is_leader() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} did not take over the leadership", target);
+    };
+
+    cfg.one(Entry { x: 101 }, servers, true);
+
+    // to an up-to-date follower
+    let leader1 = cfg.check_one_leader();
+    let target = (leader1 + 1) % servers;
+    node(&cfg, leader1).transfer_leader(target).unwrap();
+    takes_over(&cfg, target);
+    cfg.one(Entry { x: 102 }, servers, true);
+
+    // to a lagging one, once it has caught up
+    let leader2 = cfg.check_one_leader();
+    let target = (leader2 + 1) % servers;
+    cfg.disconnect(target);
+    for x in 103..110 {
+        cfg.one(Entry { x }, servers - 1, true);
+    }
+    cfg.connect(target);
+    node(&cfg, leader2).transfer_leader(target).unwrap();
+    takes_over(&cfg, target);
+    cfg.one(Entry { x: 110 }, servers, true);
+
+    // a transfer to an unreachable peer is aborted
+    let leader3 = cfg.check_one_leader();
+    let target = (leader3 + 1) % servers;
+    cfg.disconnect(target);
+    node(&cfg, leader3).transfer_leader(target).unwrap();
+    assert_eq!(
+        node(&cfg, leader3).start(&Entry { x: 111 }).unwrap_err(),
+        Error::TransferringLeader
+    );
+    thread::sleep(RAFT_ELECTION_TIMEOUT + Duration::from_millis(300));
+    if !node(&cfg, leader3).is_leader() {
+        panic!("{} lost the leadership to an unreachable peer", leader3);
+    }
+    cfg.one(Entry { x: 111 }, servers - 1, true);
+    cfg.connect(target);
+    cfg.one(Entry { x: 112 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_transfer_leader_lease_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers);
+    cfg.set_lease_read(Some(1.1));
+
+    cfg.begin("Test (2B): no lease read during a leadership transfer");
+
+    let node = |cfg: &Config, i: usize| cfg.rafts.lock().unwrap()[i].clone().unwrap();
+
+    // the transferee may be elected at once, so the lease is given up
+    let index = cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+    node(&cfg, leader1)
+        .transfer_leader((leader1 + 1) % servers)
+        .unwrap();
+    cfg.disconnect(leader1);
+    assert_ne!(block_on(node(&cfg, leader1).read_index()), Ok(index));
+    cfg.connect(leader1);
+
+    // and it is not trusted for a while after the transfer is aborted
+    let index = cfg.one(Entry { x: 102 }, servers, true);
+    let leader2 = cfg.check_one_leader();
+    let target = (leader2 + 1) % servers;
+    cfg.disconnect(target);
+    node(&cfg, leader2).transfer_leader(target).unwrap();
+    thread::sleep(RAFT_ELECTION_TIMEOUT + Duration::from_millis(200));
+    assert!(node(&cfg, leader2).is_leader());
+    cfg.disconnect(leader2);
+    assert_ne!(block_on(node(&cfg, leader2).read_index()), Ok(index));
+
+    cfg.connect(leader2);
+    cfg.connect(target);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
 #[test]
 fn test_persist1_2c() {
     let servers = 3;
@@ -1149,3 +1640,69 @@ fn test_snapshot_install_unreliable_crash_2d() {
         true,
     );
 }
+
+// Makes a follower lose the chunks of a snapshot it gathered when the next
+// one is sent, as if it restarted in the middle of the transfer.
+struct LoseChunks {
+    rafts: Arc<Mutex<Box<[Option<Node>]>>>,
+    follower: usize,
+    lost: AtomicBool,
+}
+
+impl RpcHooks for LoseChunks {
+    fn before_dispatch(&self, fq_name: &str, req: &[u8]) -> labrpc::Result<()> {
+        if fq_name == "raft.install_snapshot" {
+            let args: InstallSnapshotArgs = labcodec::decode(req).unwrap();
+            if args.offset > 0 && !self.lost.swap(true, Ordering::SeqCst) {
+                let rafts = self.rafts.lock().unwrap();
+                let node = rafts[self.follower].as_ref().unwrap();
+                node.raft.lock().unwrap().soft_state.incoming_snapshot = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn after_dispatch(&self, _: &str, resp: labrpc::Result<Vec<u8>>) -> labrpc::Result<Vec<u8>> {
+        resp
+    }
+}
+
+#[test]
+fn test_snapshot_missing_chunk_2d() {
+    let servers = 3;
+    let mut cfg = Config::new_with(servers, false, true);
+    // the snapshots of the tester are a single entry.
+    cfg.set_raft_config(RaftConfig {
+        snapshot_chunk_size: 4,
+        ..RaftConfig::default()
+    });
+    for i in 0..servers {
+        cfg.start1_snapshot(i);
+        cfg.connect(i);
+    }
+
+    cfg.begin("Test (2D): install snapshots (missing chunk)");
+
+    let mut random = rand::thread_rng();
+    cfg.one(random_entry(&mut random), servers, true);
+    let leader = cfg.check_one_leader();
+    let follower = (leader + 1) % servers;
+    cfg.disconnect(follower);
+    for _ in 0..SNAPSHOT_INTERVAL {
+        cfg.one(random_entry(&mut random), servers - 1, true);
+    }
+
+    // the follower then drops the last chunk, and the leader must send the
+    // snapshot again before it takes another one.
+    let hooks = Arc::new(LoseChunks {
+        rafts: cfg.rafts.clone(),
+        follower,
+        lost: AtomicBool::new(false),
+    });
+    cfg.set_hooks(leader, follower, hooks.clone());
+    cfg.connect(follower);
+    cfg.one(random_entry(&mut random), servers, true);
+    assert!(hooks.lost.load(Ordering::SeqCst));
+
+    cfg.end();
+}
@@ -0,0 +1,403 @@
+use clap::arg_enum;
+use kvs::{thread_pool::*, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
+use log::{error, info, LevelFilter};
+use rand::{rngs::SmallRng, FromEntropy, Rng, SeedableRng};
+use std::{
+    env::current_dir,
+    net::SocketAddr,
+    path::PathBuf,
+    process::exit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-bench")]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the server address",
+        value_name = "IP:PORT",
+        raw(default_value = "DEFAULT_ADDRESS"),
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(long, help = "Number of client connections", default_value = "4")]
+    connections: usize,
+    #[structopt(long, help = "Number of operations issued by each connection", default_value = "10000")]
+    ops: u64,
+    #[structopt(long, help = "Number of distinct keys", default_value = "10000")]
+    keys: u64,
+    #[structopt(long = "value-size", help = "Size of each written value in bytes", default_value = "100")]
+    value_size: usize,
+    #[structopt(long, help = "Relative weight of get operations", default_value = "50")]
+    read: u32,
+    #[structopt(long, help = "Relative weight of set operations", default_value = "50")]
+    write: u32,
+    #[structopt(long, help = "Relative weight of scan operations", default_value = "0")]
+    scan: u32,
+    #[structopt(long = "scan-length", help = "Number of pairs fetched by each scan", default_value = "10")]
+    scan_length: usize,
+    #[structopt(
+        long,
+        help = "Sets the key distribution",
+        value_name = "DISTRIBUTION",
+        default_value = "uniform",
+        raw(possible_values = "&Distribution::variants()")
+    )]
+    distribution: Distribution,
+    #[structopt(long = "zipf-theta", help = "Skew of the zipfian distribution, in (0, 1)", default_value = "0.99")]
+    zipf_theta: f64,
+    #[structopt(long, help = "Sets every key once before the benchmark starts")]
+    preload: bool,
+    #[structopt(long, help = "Seed of the random generators")]
+    seed: Option<u64>,
+    #[structopt(
+        long,
+        help = "Starts an in-process server with this engine instead of using a running one",
+        value_name = "ENGINE-NAME",
+        raw(possible_values = "&Engine::variants()")
+    )]
+    engine: Option<Engine>,
+    #[structopt(
+        long = "thread-pool",
+        help = "Sets the thread pool of the in-process server",
+        value_name = "POOL",
+        default_value = "shared_queue",
+        raw(possible_values = "&Pool::variants()")
+    )]
+    thread_pool: Pool,
+    #[structopt(long, help = "Number of threads of the in-process server")]
+    threads: Option<usize>,
+    #[structopt(long, help = "Data directory of the in-process server", parse(from_os_str))]
+    dir: Option<PathBuf>,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Pool {
+        naive,
+        shared_queue,
+        rayon
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Distribution {
+        uniform,
+        zipfian,
+        sequential
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Read,
+    Write,
+    Scan,
+}
+
+/// Zipfian generator over `0..n`, as described in "Quickly Generating
+/// Billion-Record Synthetic Databases" by Gray et al. It is the same
+/// generator YCSB uses, so small indices are the hottest keys.
+struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(n: u64, theta: f64) -> Zipfian {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta2 = zeta(2);
+        let zetan = zeta(n);
+        Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let i = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        i.min(self.n - 1)
+    }
+}
+
+/// Picks the key index of the next operation.
+#[derive(Clone)]
+enum KeyChooser {
+    Uniform(u64),
+    Zipfian(Arc<Zipfian>),
+    Sequential(u64, Arc<AtomicU64>),
+}
+
+impl KeyChooser {
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        match self {
+            KeyChooser::Uniform(n) => rng.gen_range(0, *n),
+            KeyChooser::Zipfian(zipfian) => zipfian.next(rng),
+            KeyChooser::Sequential(n, next) => next.fetch_add(1, Ordering::SeqCst) % n,
+        }
+    }
+}
+
+/// Latencies and error count collected by one connection.
+#[derive(Default)]
+struct Stats {
+    read: Vec<Duration>,
+    write: Vec<Duration>,
+    scan: Vec<Duration>,
+    errors: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.read.extend(other.read);
+        self.write.extend(other.write);
+        self.scan.extend(other.scan);
+        self.errors += other.errors;
+    }
+}
+
+fn key_of(i: u64) -> String {
+    format!("key{:010}", i)
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Warn).init();
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    if opt.connections == 0 || opt.keys == 0 {
+        return Err(KvsError::StringError(
+            "connections and keys must be positive".to_owned(),
+        ));
+    }
+    if opt.read + opt.write + opt.scan == 0 {
+        return Err(KvsError::StringError(
+            "at least one of read, write and scan must have a positive weight".to_owned(),
+        ));
+    }
+    if opt.distribution == Distribution::zipfian && !(opt.zipf_theta > 0.0 && opt.zipf_theta < 1.0) {
+        return Err(KvsError::StringError(
+            "zipf-theta must be in (0, 1)".to_owned(),
+        ));
+    }
+
+    if let Some(engine) = opt.engine {
+        let dir = match &opt.dir {
+            Some(dir) => dir.clone(),
+            None => current_dir()?,
+        };
+        let threads = opt.threads.unwrap_or_else(num_cpus::get);
+        spawn_server(engine, opt.thread_pool, threads, dir, opt.addr)?;
+        println!(
+            "in-process server: engine {:?}, thread pool {:?} with {} threads",
+            engine, opt.thread_pool, threads
+        );
+    }
+
+    let value: String = (0..opt.value_size)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+
+    if opt.preload {
+        let mut client = KvsClient::connect(opt.addr)?;
+        for i in 0..opt.keys {
+            client.set(key_of(i), value.clone())?;
+        }
+        println!("preloaded {} keys", opt.keys);
+    }
+
+    let chooser = match opt.distribution {
+        Distribution::uniform => KeyChooser::Uniform(opt.keys),
+        Distribution::zipfian if opt.keys > 1 => {
+            KeyChooser::Zipfian(Arc::new(Zipfian::new(opt.keys, opt.zipf_theta)))
+        }
+        Distribution::zipfian => KeyChooser::Uniform(opt.keys),
+        Distribution::sequential => KeyChooser::Sequential(opt.keys, Arc::new(AtomicU64::new(0))),
+    };
+
+    let start = Instant::now();
+    let mut handles = Vec::new();
+    for conn_id in 0..opt.connections {
+        let chooser = chooser.clone();
+        let value = value.clone();
+        let mut rng = match opt.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed.wrapping_add(conn_id as u64)),
+            None => SmallRng::from_entropy(),
+        };
+        let (addr, ops, scan_length) = (opt.addr, opt.ops, opt.scan_length);
+        let (read, write) = (opt.read, opt.write);
+        let total = opt.read + opt.write + opt.scan;
+        handles.push(thread::spawn(move || -> Result<Stats> {
+            let mut client = KvsClient::connect(addr)?;
+            let mut stats = Stats::default();
+            for _ in 0..ops {
+                let dice = rng.gen_range(0, total);
+                let op = if dice < read {
+                    Op::Read
+                } else if dice < read + write {
+                    Op::Write
+                } else {
+                    Op::Scan
+                };
+                let key = key_of(chooser.next(&mut rng));
+
+                let begin = Instant::now();
+                let res = match op {
+                    Op::Read => client.get(key).map(|_| ()),
+                    Op::Write => client.set(key, value.clone()),
+                    Op::Scan => client.scan(key, scan_length).map(|_| ()),
+                };
+                let elapsed = begin.elapsed();
+                match res {
+                    Ok(()) => match op {
+                        Op::Read => stats.read.push(elapsed),
+                        Op::Write => stats.write.push(elapsed),
+                        Op::Scan => stats.scan.push(elapsed),
+                    },
+                    // the connection is broken, no more requests can be sent.
+                    Err(e @ KvsError::Io(_)) | Err(e @ KvsError::Serde(_)) => {
+                        error!("connection {} aborted: {}", conn_id, e);
+                        stats.errors += 1;
+                        break;
+                    }
+                    Err(_) => stats.errors += 1,
+                }
+            }
+            Ok(stats)
+        }));
+    }
+
+    let mut stats = Stats::default();
+    for handle in handles {
+        match handle.join() {
+            Ok(res) => stats.merge(res?),
+            Err(_) => return Err(KvsError::StringError("client thread panicked".to_owned())),
+        }
+    }
+    let elapsed = start.elapsed();
+
+    report(&opt, &mut stats, elapsed);
+    Ok(())
+}
+
+fn spawn_server(engine: Engine, pool: Pool, threads: usize, dir: PathBuf, addr: SocketAddr) -> Result<()> {
+    info!("starting in-process server on {}", addr);
+    thread::spawn(move || {
+        let res = match engine {
+            Engine::kvs => KvStore::open(dir).and_then(|engine| run_with_pool(engine, pool, threads, addr)),
+            Engine::sled => sled::open(dir)
+                .map_err(KvsError::from)
+                .and_then(|db| run_with_pool(SledKvsEngine::new(db), pool, threads, addr)),
+        };
+        if let Err(e) = res {
+            error!("in-process server stopped: {}", e);
+        }
+    });
+
+    // wait for the server to accept connections.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match KvsClient::connect(addr) {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() > deadline => return Err(e),
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+fn run_with_pool<E: KvsEngine>(engine: E, pool: Pool, threads: usize, addr: SocketAddr) -> Result<()> {
+    match pool {
+        Pool::naive => KvsServer::new(engine, NaiveThreadPool::new(threads)?).run(addr),
+        Pool::shared_queue => KvsServer::new(engine, SharedQueueThreadPool::new(threads)?).run(addr),
+        Pool::rayon => KvsServer::new(engine, RayonThreadPool::new(threads)?).run(addr),
+    }
+}
+
+fn report(opt: &Opt, stats: &mut Stats, elapsed: Duration) {
+    let completed = (stats.read.len() + stats.write.len() + stats.scan.len()) as u64;
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "{} connections, {:?} keys over {} keys, {}-byte values, mix read:write:scan = {}:{}:{}",
+        opt.connections, opt.distribution, opt.keys, opt.value_size, opt.read, opt.write, opt.scan
+    );
+    println!(
+        "{} ops in {:.3}s, {:.0} ops/s, {} errors",
+        completed,
+        secs,
+        completed as f64 / secs,
+        stats.errors
+    );
+    println!(
+        "{:<6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "op", "count", "mean(us)", "p50(us)", "p90(us)", "p99(us)", "p99.9(us)", "max(us)"
+    );
+    for (name, latencies) in &mut [
+        ("get", &mut stats.read),
+        ("set", &mut stats.write),
+        ("scan", &mut stats.scan),
+    ] {
+        if latencies.is_empty() {
+            continue;
+        }
+        latencies.sort();
+        let total: Duration = latencies.iter().sum();
+        let mean = total / latencies.len() as u32;
+        println!(
+            "{:<6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            latencies.len(),
+            mean.as_micros(),
+            percentile(latencies, 50.0).as_micros(),
+            percentile(latencies, 90.0).as_micros(),
+            percentile(latencies, 99.0).as_micros(),
+            percentile(latencies, 99.9).as_micros(),
+            latencies[latencies.len() - 1].as_micros(),
+        );
+    }
+}
+
+/// Returns the `p`-th percentile of sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
@@ -1,14 +1,52 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
-use std::{net::SocketAddr, process::exit};
+use kvs::{KvsClient, KvsError, Result};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+use serde_json::Value;
+use std::{
+    env,
+    io::{self, BufRead, IsTerminal},
+    net::SocketAddr,
+    path::PathBuf,
+    process::exit,
+    time::Instant,
+};
 use structopt::StructOpt;
 
 const ADDRESS_FORMAT: &str = "IP:PORT";
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const EXIT_CODES: &str = "EXIT CODES:
+    0    success
+    1    other errors, including invalid arguments
+    2    key not found
+    3    I/O error, on the client or on the server
+    4    data corrupted on the server
+    5    server busy
+    6    precondition failed
+    7    invalid request
+    8    server limit exceeded
+    9    timed out";
+const SHELL_PROMPT: &str = "kvs> ";
+const HISTORY_FILE: &str = ".kvs-client_history";
+const SHELL_COMMANDS: &[&str] = &["get", "set", "rm", "scan", "find", "help", "exit"];
+const SHELL_HELP: &str = "COMMANDS:
+    get KEY                   Get the value of a key
+    set KEY VALUE             Set the value of a key
+    rm KEY                    Remove a key
+    scan [START] [LIMIT]      List at most LIMIT pairs from START, 10 by default
+    find INDEX FIELD [LIMIT]  Find at most LIMIT pairs by FIELD in INDEX, 10 by default
+    help                      Print this message
+    exit                      Leave the shell
+
+Words containing spaces can be quoted with ' or \".";
+const DEFAULT_SCAN_LIMIT: usize = 10;
 
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "kvs-client",
+    raw(after_help = "EXIT_CODES"),
     raw(global_settings = "&[\
                            AppSettings::DisableHelpSubcommand,\
                            AppSettings::VersionlessSubcommands]")
@@ -61,6 +99,43 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(
+        name = "find",
+        about = "Find the pairs whose JSON value holds a field, using a secondary index"
+    )]
+    Find {
+        #[structopt(name = "INDEX", help = "The name of the index")]
+        index: String,
+        #[structopt(
+            name = "FIELD",
+            help = "The field to look for, as JSON or as a plain string"
+        )]
+        field: String,
+        #[structopt(long, help = "Sets the maximum number of pairs", default_value = "100")]
+        limit: usize,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "shell",
+        about = "Run commands over one connection, from a prompt or a script on stdin"
+    )]
+    Shell {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn run(opt: Opt) -> Result<()> {
@@ -81,15 +156,241 @@ fn run(opt: Opt) -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key.to_string())?;
         }
+        Command::Find {
+            index,
+            field,
+            limit,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr)?;
+            for (key, value) in client.index_lookup(index, field, limit)? {
+                println!("{} => {}", key, value);
+            }
+        }
+        Command::Shell { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            if io::stdin().is_terminal() {
+                run_prompt(&mut client)?;
+            } else {
+                run_script(&mut client, io::stdin().lock())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads commands from an interactive prompt until `exit` or end of input.
+///
+/// Errors are printed and the prompt goes on.
+fn run_prompt(client: &mut KvsClient) -> Result<()> {
+    let mut editor = Editor::<ShellHelper, DefaultHistory>::new().map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper));
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // there is no history on the first run.
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline(SHELL_PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str()).map_err(readline_error)?;
+        match execute_line(client, &line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history).map_err(readline_error)?;
+    }
+    Ok(())
+}
+
+/// Runs the commands of a script, one per line, stopping at the first error.
+///
+/// Empty lines and lines starting with `#` are skipped.
+fn run_script<R: BufRead>(client: &mut KvsClient, script: R) -> Result<()> {
+    for line in script.lines() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !execute_line(client, &line)? {
+            break;
+        }
     }
     Ok(())
 }
 
+/// Runs one shell command and prints its result, and how long it took on stderr.
+///
+/// Returns `false` if the shell should stop.
+fn execute_line(client: &mut KvsClient, line: &str) -> Result<bool> {
+    let words = split_words(line)?;
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+    let start = Instant::now();
+    match args.as_slice() {
+        ["get", key] => match client.get(key.to_string())? {
+            Some(value) => println!("{}", pretty(&value)),
+            None => println!("Key not found"),
+        },
+        ["set", key, value] => client.set(key.to_string(), value.to_string())?,
+        ["rm", key] => client.remove(key.to_string())?,
+        ["scan", rest @ ..] if rest.len() <= 2 => {
+            let start = rest.first().copied().unwrap_or_default();
+            let limit = match rest.get(1) {
+                Some(limit) => limit.parse().map_err(|_| usage("scan [START] [LIMIT]"))?,
+                None => DEFAULT_SCAN_LIMIT,
+            };
+            for (key, value) in client.scan(start.to_owned(), limit)? {
+                println!("{} => {}", key, pretty(&value));
+            }
+        }
+        ["find", index, field, rest @ ..] if rest.len() <= 1 => {
+            let limit = match rest.first() {
+                Some(limit) => limit.parse().map_err(|_| usage("find INDEX FIELD [LIMIT]"))?,
+                None => DEFAULT_SCAN_LIMIT,
+            };
+            for (key, value) in client.index_lookup(index.to_string(), field.to_string(), limit)? {
+                println!("{} => {}", key, pretty(&value));
+            }
+        }
+        ["get", ..] => return Err(usage("get KEY")),
+        ["set", ..] => return Err(usage("set KEY VALUE")),
+        ["rm", ..] => return Err(usage("rm KEY")),
+        ["scan", ..] => return Err(usage("scan [START] [LIMIT]")),
+        ["find", ..] => return Err(usage("find INDEX FIELD [LIMIT]")),
+        ["help"] => {
+            println!("{}", SHELL_HELP);
+            return Ok(true);
+        }
+        ["exit"] | ["quit"] => return Ok(false),
+        [] => return Ok(true),
+        [command, ..] => {
+            return Err(KvsError::StringError(format!(
+                "unknown command '{}', try 'help'",
+                command
+            )))
+        }
+    }
+    eprintln!("({:.3} ms)", start.elapsed().as_secs_f64() * 1000.0);
+    Ok(true)
+}
+
+fn usage(command: &str) -> KvsError {
+    KvsError::StringError(format!("usage: {}", command))
+}
+
+/// Splits a command line into words.
+///
+/// Words can be quoted with `'` or `"`, and `\` escapes the next character
+/// outside single quotes.
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let unterminated = || KvsError::StringError("unterminated quote or escape".to_owned());
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unterminated)? {
+                        '"' => break,
+                        '\\' => word.push(chars.next().ok_or_else(unterminated)?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars.next().ok_or_else(unterminated)?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Indents values which are JSON objects or arrays, and returns the others as is.
+fn pretty(value: &str) -> String {
+    match serde_json::from_str(value) {
+        Ok(json @ Value::Object(_)) | Ok(json @ Value::Array(_)) => {
+            serde_json::to_string_pretty(&json).unwrap_or_else(|_| value.to_owned())
+        }
+        _ => value.to_owned(),
+    }
+}
+
+fn readline_error(err: ReadlineError) -> KvsError {
+    match err {
+        ReadlineError::Io(e) => KvsError::Io(e),
+        e => KvsError::StringError(e.to_string()),
+    }
+}
+
+/// Completes the command names at the prompt.
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            // only the command is completed, not the keys.
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = SHELL_COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(prefix))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
 /// implements the functionality required for kvs-client to speak to kvs-server
 fn main() {
     let opt = Opt::from_args();
     if let Err(e) = run(opt) {
         eprintln!("{}", e);
-        exit(1);
+        exit(e.exit_code());
     }
 }
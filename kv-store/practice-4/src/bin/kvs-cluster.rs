@@ -0,0 +1,188 @@
+use clap::AppSettings;
+use kvs::{rebalance, ClusterClient, ClusterConfig, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+};
+use structopt::StructOpt;
+
+const CONFIG_FORMAT: &str = "CLUSTER CONFIGURATION:
+    The file given by --config lists which server owns each range of the 16384
+    hash slots. The ranges must cover every slot once.
+
+    [[shards]]
+    start = 0
+    end = 8192                      # the slot right after the range
+    addr = \"127.0.0.1:4000\"
+
+    [[shards]]
+    start = 8192
+    end = 16384
+    addr = \"127.0.0.1:4001\"
+
+EXIT CODES:
+    The same as kvs-client.";
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-cluster",
+    raw(after_help = "CONFIG_FORMAT"),
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Reads the layout of the cluster from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        config: PathBuf,
+    },
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "A string value")]
+        value: String,
+        #[structopt(
+            long,
+            help = "Reads the layout of the cluster from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        config: PathBuf,
+    },
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Reads the layout of the cluster from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        config: PathBuf,
+    },
+    #[structopt(name = "scan", about = "List the pairs of the whole cluster in key order")]
+    Scan {
+        #[structopt(name = "START", help = "The first key to list", default_value = "")]
+        start: String,
+        #[structopt(long, help = "Sets the maximum number of pairs", default_value = "100")]
+        limit: usize,
+        #[structopt(
+            long,
+            help = "Reads the layout of the cluster from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        config: PathBuf,
+    },
+    #[structopt(
+        name = "find",
+        about = "Find the pairs whose JSON value holds a field, using a secondary index"
+    )]
+    Find {
+        #[structopt(name = "INDEX", help = "The name of the index")]
+        index: String,
+        #[structopt(
+            name = "FIELD",
+            help = "The field to look for, as JSON or as a plain string"
+        )]
+        field: String,
+        #[structopt(long, help = "Sets the maximum number of pairs", default_value = "100")]
+        limit: usize,
+        #[structopt(
+            long,
+            help = "Reads the layout of the cluster from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        config: PathBuf,
+    },
+    #[structopt(
+        name = "rebalance",
+        about = "Move the keys to their owners in a new layout, while the cluster takes no writes"
+    )]
+    Rebalance {
+        #[structopt(
+            long,
+            help = "Reads the current layout from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        from: PathBuf,
+        #[structopt(
+            long,
+            help = "Reads the new layout from this TOML file",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        to: PathBuf,
+        #[structopt(long, help = "Sets the number of keys scanned at once", default_value = "100")]
+        batch: usize,
+    },
+}
+
+fn connect(config: &Path) -> Result<ClusterClient> {
+    Ok(ClusterClient::new(ClusterConfig::load(config)?))
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Get { key, config } => {
+            if let Some(value) = connect(&config)?.get(key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+        Command::Set { key, value, config } => connect(&config)?.set(key, value)?,
+        Command::Remove { key, config } => connect(&config)?.remove(key)?,
+        Command::Scan {
+            start,
+            limit,
+            config,
+        } => {
+            for (key, value) in connect(&config)?.scan(start, limit)? {
+                println!("{} => {}", key, value);
+            }
+        }
+        Command::Find {
+            index,
+            field,
+            limit,
+            config,
+        } => {
+            for (key, value) in connect(&config)?.index_lookup(index, field, limit)? {
+                println!("{} => {}", key, value);
+            }
+        }
+        Command::Rebalance { from, to, batch } => {
+            let moved = rebalance(&ClusterConfig::load(&from)?, &ClusterConfig::load(&to)?, batch)?;
+            println!("moved {} keys", moved);
+        }
+    }
+    Ok(())
+}
+
+/// routes requests to the servers of a cluster, and moves keys between them
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(e.exit_code());
+    }
+}
@@ -1,23 +1,75 @@
 use clap::arg_enum;
-use kvs::{thread_pool::*, KvStore, KvsEngine, KvsServer, Result};
+use kvs::{
+    thread_pool::*, Compression, IndexSpec, Keyring, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer,
+    LimitsHandle, Protocol, Result, ServerLimits, SledKvsEngine, SyncPolicy,
+};
 use log::{info, LevelFilter, warn, error};
-use std::{net::SocketAddr, fs, env::current_dir, process::exit};
+use serde::Deserialize;
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use std::{
+    env::current_dir, fs, net::SocketAddr, path::PathBuf, process::exit, thread, time::Duration,
+};
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_PROTOCOL: WireProtocol = WireProtocol::json;
+const DEFAULT_THREAD_POOL: Pool = Pool::naive;
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_COMPRESSION: Codec = Codec::none;
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+const CONFIG_FORMAT: &str = "CONFIGURATION FILE:
+    The file given by --config is written in TOML. All the settings are optional,
+    and the command line overrides them. Sending SIGHUP reloads the file and
+    applies log_level, [limits], and storage.compaction_threshold and
+    storage.sync of the kvs engine. The other settings need a restart.
 
-#[derive(StructOpt, Debug)]
-#[structopt(name = "kvs-server")]
+    addr = \"127.0.0.1:4000\"
+    engine = \"kvs\"                  # kvs or sled
+    protocol = \"json\"               # json or resp
+    log_level = \"info\"              # off, error, warn, info, debug or trace
+    thread_pool = \"shared_queue\"    # naive, shared_queue or rayon
+    threads = 8                     # the number of CPUs by default
+
+    [storage]
+    compaction_threshold = 1048576  # bytes of stale records
+    sync = \"never\"                  # never or always
+    compression = \"zstd\"            # none, lz4 or zstd
+    compression_level = 3
+    compression_threshold = 256
+    key_file = \"/etc/kvs/keys\"
+    blob_threshold = 65536
+
+    [[storage.indexes]]             # secondary indexes over JSON values
+    name = \"by_city\"
+    pointer = \"/address/city\"
+
+    [limits]
+    max_key_size = 65536
+    max_value_size = 8388608
+    max_frame_size = 16777216
+    max_scan_limit = 10000
+    max_connections = 1024
+    max_in_flight = 32
+    idle_timeout = 300              # seconds, 0 to never close idle connections";
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(name = "kvs-server", raw(after_help = "CONFIG_FORMAT"))]
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Reads the settings from this TOML file",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the listening address [default: 127.0.0.1:4000]",
         value_name = "IP:PORT",
-        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -25,40 +77,440 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets the protocol spoken with clients [default: json]",
+        value_name = "PROTOCOL",
+        raw(possible_values = "&WireProtocol::variants()")
+    )]
+    protocol: Option<WireProtocol>,
+    #[structopt(long = "max-key-size", help = "Sets the maximum key size in bytes")]
+    max_key_size: Option<usize>,
+    #[structopt(long = "max-value-size", help = "Sets the maximum value size in bytes")]
+    max_value_size: Option<usize>,
+    #[structopt(long = "max-frame-size", help = "Sets the maximum request size in bytes")]
+    max_frame_size: Option<usize>,
+    #[structopt(long = "max-connections", help = "Sets the maximum number of concurrent connections")]
+    max_connections: Option<usize>,
+    #[structopt(
+        long = "max-in-flight",
+        help = "Sets the maximum number of unanswered requests per connection"
+    )]
+    max_in_flight: Option<usize>,
+    #[structopt(
+        long = "idle-timeout",
+        help = "Closes connections idle for this many seconds, 0 to never close them"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets the codec used to compress new records of the kvs engine [default: none]",
+        value_name = "CODEC",
+        raw(possible_values = "&Codec::variants()")
+    )]
+    compression: Option<Codec>,
+    #[structopt(long = "compression-level", help = "Sets the zstd compression level")]
+    compression_level: Option<i32>,
+    #[structopt(
+        long = "compression-threshold",
+        help = "Sets the size in bytes under which records are not compressed"
+    )]
+    compression_threshold: Option<usize>,
+    #[structopt(
+        long = "key-file",
+        help = "Encrypts the data of the kvs engine with the last key of this file",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(
+        long = "blob-threshold",
+        help = "Stores values of at least this many bytes in separate blob files"
+    )]
+    blob_threshold: Option<usize>,
+    #[structopt(
+        long = "index",
+        help = "Declares a secondary index of the kvs engine over a field of JSON values",
+        value_name = "NAME=POINTER",
+        raw(number_of_values = "1")
+    )]
+    indexes: Vec<String>,
 }
 
 arg_enum! {
     #[allow(non_camel_case_types)]
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
     enum Engine {
         kvs,
         sled
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+    enum WireProtocol {
+        json,
+        resp
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+    enum Codec {
+        none,
+        lz4,
+        zstd
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+enum Pool {
+    naive,
+    shared_queue,
+    rayon,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+enum SyncMode {
+    never,
+    always,
+}
+
+/// The settings of the server: the ones of the configuration file, overridden
+/// by the command line. Unset ones take their default value.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    addr: Option<SocketAddr>,
+    engine: Option<Engine>,
+    protocol: Option<WireProtocol>,
+    log_level: Option<String>,
+    thread_pool: Option<Pool>,
+    threads: Option<usize>,
+    storage: StorageConfig,
+    limits: LimitsConfig,
+}
+
+/// The settings of the kvs engine.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageConfig {
+    compaction_threshold: Option<u64>,
+    sync: Option<SyncMode>,
+    compression: Option<Codec>,
+    compression_level: Option<i32>,
+    compression_threshold: Option<usize>,
+    key_file: Option<PathBuf>,
+    blob_threshold: Option<usize>,
+    indexes: Option<Vec<IndexConfig>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndexConfig {
+    name: String,
+    pointer: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsConfig {
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    max_scan_limit: Option<usize>,
+    max_connections: Option<usize>,
+    max_in_flight: Option<usize>,
+    idle_timeout: Option<u64>,
+}
+
+impl Config {
+    /// Reads the configuration file, if any, and applies the command line to it.
+    fn load(opt: &Opt) -> Result<Config> {
+        let mut config: Config = match &opt.config {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+                KvsError::StringError(format!("invalid config file {}: {}", path.display(), e))
+            })?,
+            None => Config::default(),
+        };
+
+        override_with(&mut config.addr, opt.addr);
+        override_with(&mut config.engine, opt.engine);
+        override_with(&mut config.protocol, opt.protocol);
+
+        let storage = &mut config.storage;
+        override_with(&mut storage.compression, opt.compression);
+        override_with(&mut storage.compression_level, opt.compression_level);
+        override_with(&mut storage.compression_threshold, opt.compression_threshold);
+        override_with(&mut storage.key_file, opt.key_file.clone());
+        override_with(&mut storage.blob_threshold, opt.blob_threshold);
+        if !opt.indexes.is_empty() {
+            let indexes = opt.indexes.iter().map(|index| match index.split_once('=') {
+                Some((name, pointer)) => Ok(IndexConfig {
+                    name: name.to_owned(),
+                    pointer: pointer.to_owned(),
+                }),
+                None => Err(KvsError::StringError(format!(
+                    "invalid index '{}', expected NAME=POINTER",
+                    index
+                ))),
+            });
+            storage.indexes = Some(indexes.collect::<Result<_>>()?);
+        }
+
+        let limits = &mut config.limits;
+        override_with(&mut limits.max_key_size, opt.max_key_size);
+        override_with(&mut limits.max_value_size, opt.max_value_size);
+        override_with(&mut limits.max_frame_size, opt.max_frame_size);
+        override_with(&mut limits.max_connections, opt.max_connections);
+        override_with(&mut limits.max_in_flight, opt.max_in_flight);
+        override_with(&mut limits.idle_timeout, opt.idle_timeout);
+
+        // catch an invalid level now rather than on the first reload.
+        config.log_level()?;
+        Ok(config)
+    }
+
+    fn log_level(&self) -> Result<LevelFilter> {
+        match &self.log_level {
+            Some(level) => level
+                .parse()
+                .map_err(|_| KvsError::StringError(format!("invalid log level '{}'", level))),
+            None => Ok(DEFAULT_LOG_LEVEL),
+        }
+    }
+
+    /// Returns the names of the settings which differ and need a restart.
+    fn restart_needed(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.addr != other.addr {
+            changed.push("addr");
+        }
+        if self.engine != other.engine {
+            changed.push("engine");
+        }
+        if self.protocol != other.protocol {
+            changed.push("protocol");
+        }
+        if self.thread_pool != other.thread_pool || self.threads != other.threads {
+            changed.push("thread_pool");
+        }
+        if self.storage.static_part() != other.storage.static_part() {
+            changed.push("storage");
+        }
+        changed
+    }
+}
+
+impl StorageConfig {
+    /// Returns the settings without the ones which can change at runtime.
+    fn static_part(&self) -> StorageConfig {
+        StorageConfig {
+            compaction_threshold: None,
+            sync: None,
+            ..self.clone()
+        }
+    }
+}
+
+fn override_with<T>(setting: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *setting = value;
+    }
+}
 
 fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let config = Config::load(&opt)?;
+    log::set_max_level(config.log_level()?);
+
+    let curr_engine = current_engine()?;
+    let engine = config.engine.or(curr_engine).unwrap_or(DEFAULT_ENGINE);
+    if curr_engine.is_some() && Some(engine) != curr_engine {
+        error!("The engine is already set to {:?}, please use the same engine", curr_engine);
+        exit(1);
+    }
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Using engine: {:?}", engine);
-    info!("Listening on {}", opt.addr);
 
     // write engine to engine dir
     fs::write(current_dir()?.join("engine"), format!("{:?}", engine))?;
 
-    let pool = NaiveThreadPool::new(4).unwrap();
-
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, pool, opt.addr),
-        Engine::sled => unimplemented!(),   // TODO
+        Engine::kvs => {
+            let store = KvStore::open_with_options(current_dir()?, store_options(&config.storage)?)?;
+            let reconfigure = {
+                let store = store.clone();
+                move |storage: &StorageConfig| {
+                    let options = store_options(storage)?;
+                    store.set_compaction_threshold(options.compaction_threshold)?;
+                    store.set_sync_policy(options.sync)
+                }
+            };
+            run_with_engine(store, opt, config, reconfigure)
+        }
+        Engine::sled => run_with_engine(
+            SledKvsEngine::new(sled::open(current_dir()?)?),
+            opt,
+            config,
+            |_: &StorageConfig| Ok(()),
+        ),
     }
 }
 
-fn run_with_engine<E: KvsEngine, P: ThreadPool>(engine: E, pool: P,addr: SocketAddr) -> Result<()> {
-    let mut server = KvsServer::new(engine, pool);
+fn run_with_engine<E, R>(engine: E, opt: Opt, config: Config, reconfigure: R) -> Result<()>
+where
+    E: KvsEngine,
+    R: Fn(&StorageConfig) -> Result<()> + Send + 'static,
+{
+    let threads = config.threads.unwrap_or_else(num_cpus::get);
+    let pool = config.thread_pool.unwrap_or(DEFAULT_THREAD_POOL);
+    info!("Using thread pool: {:?} with {} threads", pool, threads);
+    match pool {
+        Pool::naive => serve(engine, NaiveThreadPool::new(threads)?, opt, config, reconfigure),
+        Pool::shared_queue => serve(
+            engine,
+            SharedQueueThreadPool::new(threads)?,
+            opt,
+            config,
+            reconfigure,
+        ),
+        Pool::rayon => serve(engine, RayonThreadPool::new(threads)?, opt, config, reconfigure),
+    }
+}
+
+fn serve<E, P, R>(engine: E, pool: P, opt: Opt, config: Config, reconfigure: R) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool,
+    R: Fn(&StorageConfig) -> Result<()> + Send + 'static,
+{
+    let addr = config
+        .addr
+        .unwrap_or_else(|| DEFAULT_LISTENING_ADDRESS.parse().unwrap());
+    let protocol = match config.protocol.unwrap_or(DEFAULT_PROTOCOL) {
+        WireProtocol::json => Protocol::Json,
+        WireProtocol::resp => Protocol::Resp,
+    };
+    info!("Listening on {}", addr);
+    info!("Speaking protocol: {:?}", protocol);
+
+    let mut server =
+        KvsServer::with_limits(engine, pool, server_limits(&config.limits)).with_protocol(protocol);
+    if opt.config.is_some() {
+        reload_on_sighup(opt, config, server.limits_handle(), reconfigure)?;
+    }
     server.run(addr)
 }
 
+/// Reloads the configuration file on every SIGHUP, and applies the settings
+/// which can change while the server runs.
+///
+/// A configuration which fails to load is ignored as a whole.
+fn reload_on_sighup<R>(opt: Opt, mut config: Config, limits: LimitsHandle, reconfigure: R) -> Result<()>
+where
+    R: Fn(&StorageConfig) -> Result<()> + Send + 'static,
+{
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let res = Config::load(&opt).and_then(|new| {
+                reconfigure(&new.storage)?;
+                limits.set(server_limits(&new.limits));
+                log::set_max_level(new.log_level()?);
+                Ok(new)
+            });
+            let new = match res {
+                Ok(new) => new,
+                Err(e) => {
+                    error!("Failed to reload the configuration: {}", e);
+                    continue;
+                }
+            };
+            for setting in config.restart_needed(&new) {
+                warn!("The {} setting changed, restart kvs-server to apply it", setting);
+            }
+            // only keep what was applied.
+            config.log_level = new.log_level;
+            config.limits = new.limits;
+            config.storage.compaction_threshold = new.storage.compaction_threshold;
+            config.storage.sync = new.storage.sync;
+            info!("Reloaded the configuration");
+        }
+    });
+    Ok(())
+}
+
+/// Overrides the default limits with the configured ones.
+fn server_limits(config: &LimitsConfig) -> ServerLimits {
+    let mut limits = ServerLimits::default();
+    if let Some(size) = config.max_key_size {
+        limits.max_key_size = size;
+    }
+    if let Some(size) = config.max_value_size {
+        limits.max_value_size = size;
+    }
+    if let Some(size) = config.max_frame_size {
+        limits.max_frame_size = size;
+    }
+    if let Some(n) = config.max_scan_limit {
+        limits.max_scan_limit = n;
+    }
+    if let Some(n) = config.max_connections {
+        limits.max_connections = n;
+    }
+    if let Some(n) = config.max_in_flight {
+        limits.max_in_flight = n;
+    }
+    if let Some(secs) = config.idle_timeout {
+        limits.idle_timeout = if secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(secs))
+        };
+    }
+    limits
+}
+
+/// Builds the options of the kvs engine from the configuration.
+fn store_options(config: &StorageConfig) -> Result<KvStoreOptions> {
+    let keyring = match &config.key_file {
+        Some(path) => Keyring::load(path)?,
+        None => Keyring::new(),
+    };
+    let defaults = KvStoreOptions::default();
+    Ok(KvStoreOptions {
+        compression: match config.compression.unwrap_or(DEFAULT_COMPRESSION) {
+            Codec::none => Compression::None,
+            Codec::lz4 => Compression::Lz4,
+            Codec::zstd => Compression::Zstd(config.compression_level.unwrap_or(DEFAULT_ZSTD_LEVEL)),
+        },
+        compression_threshold: config
+            .compression_threshold
+            .unwrap_or(defaults.compression_threshold),
+        keyring,
+        blob_threshold: config.blob_threshold,
+        compaction_threshold: config
+            .compaction_threshold
+            .unwrap_or(defaults.compaction_threshold),
+        sync: match config.sync {
+            Some(SyncMode::always) => SyncPolicy::Always,
+            Some(SyncMode::never) => SyncPolicy::Never,
+            None => defaults.sync,
+        },
+        indexes: config
+            .indexes
+            .iter()
+            .flatten()
+            .map(|index| IndexSpec::new(index.name.as_str(), index.pointer.as_str()))
+            .collect(),
+        ..defaults
+    })
+}
+
 fn current_engine() -> Result<Option<Engine>> {
     let engine = current_dir()?.join("engine");
     if !engine.exists() {
@@ -76,19 +528,11 @@ fn current_engine() -> Result<Option<Engine>> {
 
 /// implements the functionality required for kvs-client to speak to kvs-server
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(|curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
-        }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("The engine is already set to {:?}, please use the same engine", curr_engine);
-            exit(1);
-        }
-        run(opt)
-    });
-    if let Err(e) = res {
+    // records are filtered by the maximum level, which the configuration can change.
+    env_logger::builder().filter_level(LevelFilter::Trace).init();
+    log::set_max_level(DEFAULT_LOG_LEVEL);
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
         error!("{}", e);
         exit(1);
     }
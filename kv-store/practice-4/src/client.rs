@@ -6,21 +6,22 @@
  * @@Email: ihusharp@gmail.com
  */
 use std::{
-    io::{BufReader, BufWriter, Write},
+    io::{BufReader, BufWriter},
     net::{TcpStream, ToSocketAddrs},
 };
 
-use serde::Deserialize;
-use serde_json::{de::IoRead, Deserializer};
-
 use crate::{
-    common::{GetResponse, RemoveResponse, SetResponse, Request},
+    common::{read_message, write_frame, Request, Response},
     KvsError, Result,
 };
 
+/// The number of requests `get_many` and `set_many` keep in flight, below the
+/// default `max_in_flight` of the server.
+const PIPELINE_DEPTH: usize = 16;
+
 /// implements the functionality required for kvs-client to speak to kvs-server
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
 }
 
@@ -30,41 +31,115 @@ impl KvsClient {
         let tcp_reader = TcpStream::connect(addr)?;
         let tcp_writer = tcp_reader.try_clone()?;
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            reader: BufReader::new(tcp_reader),
             writer: BufWriter::new(tcp_writer),
         })
     }
 
     /// Get the value of a given key from the server.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let response = GetResponse::deserialize(&mut self.reader)?;
-        match response {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(err) => Err(KvsError::StringError(err)),
+        match self.send_request(&Request::Get { key })? {
+            Response::Get(value) => Ok(value),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
         }
     }
 
     /// Set the value of a given key from the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let response = SetResponse::deserialize(&mut self.reader)?;
-        match response {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(err) => Err(KvsError::StringError(err)),
+        match self.send_request(&Request::Set { key, value })? {
+            Response::Set => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
         }
     }
 
     /// Remove a given key from the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let response = RemoveResponse::deserialize(&mut self.reader)?;
-        match response {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(err) => Err(KvsError::StringError(err)),
+        match self.send_request(&Request::Remove { key })? {
+            Response::Remove => Ok(()),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Get the values of `keys` from the server, in the same order.
+    ///
+    /// The requests are pipelined instead of waiting for each answer.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let reqs = keys.into_iter().map(|key| Request::Get { key }).collect();
+        self.send_requests(reqs)?
+            .into_iter()
+            .map(|resp| match resp {
+                Response::Get(value) => Ok(value),
+                Response::Err(err) => Err(err.into()),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+            .collect()
+    }
+
+    /// Set the values of several keys in the server.
+    ///
+    /// The requests are pipelined instead of waiting for each answer. On an
+    /// error, the pairs before the failed one may have been set.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let reqs = pairs
+            .into_iter()
+            .map(|(key, value)| Request::Set { key, value })
+            .collect();
+        for resp in self.send_requests(reqs)? {
+            match resp {
+                Response::Set => {}
+                Response::Err(err) => return Err(err.into()),
+                _ => return Err(KvsError::StringError("Invalid response".to_owned())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Scan at most `limit` key/value pairs starting from `start` in the server.
+    pub fn scan(&mut self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        match self.send_request(&Request::Scan { start, limit })? {
+            Response::Scan(pairs) => Ok(pairs),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Find at most `limit` key/value pairs whose JSON value holds `field` at
+    /// the pointer of the secondary index `index` in the server.
+    pub fn index_lookup(
+        &mut self,
+        index: String,
+        field: String,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        match self.send_request(&Request::IndexLookup { index, field, limit })? {
+            Response::IndexLookup(pairs) => Ok(pairs),
+            Response::Err(err) => Err(err.into()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    fn send_request(&mut self, req: &Request) -> Result<Response> {
+        write_frame(&mut self.writer, req)?;
+        read_message(&mut self.reader)?
+            .ok_or_else(|| KvsError::StringError("No response received".to_owned()))
+    }
+
+    /// Sends `reqs` `PIPELINE_DEPTH` at a time, and returns their responses.
+    fn send_requests(&mut self, reqs: Vec<Request>) -> Result<Vec<Response>> {
+        let mut resps = Vec::with_capacity(reqs.len());
+        for chunk in reqs.chunks(PIPELINE_DEPTH) {
+            for req in chunk {
+                write_frame(&mut self.writer, req)?;
+            }
+            for _ in chunk {
+                resps.push(read_message(&mut self.reader)?.ok_or_else(|| {
+                    KvsError::StringError("No response received".to_owned())
+                })?);
+            }
         }
+        Ok(resps)
     }
 }
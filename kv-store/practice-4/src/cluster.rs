@@ -0,0 +1,314 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs,
+    net::SocketAddr,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::{KvsClient, KvsError, Result};
+
+/// The number of hash slots keys are spread over.
+pub const SLOTS: u32 = 16384;
+
+/// Returns the hash slot of a key, in `0..SLOTS`.
+///
+/// It is the 64-bit FNV-1a hash of the key, so every client agrees on it.
+pub fn key_slot(key: &str) -> u32 {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    (hash % u64::from(SLOTS)) as u32
+}
+
+/// A range of hash slots served by one `KvsServer`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shard {
+    /// The first slot of the range.
+    pub start: u32,
+    /// The slot right after the range.
+    pub end: u32,
+    /// The address of the server owning the range.
+    pub addr: SocketAddr,
+}
+
+/// The static layout of a cluster: which server owns each hash slot.
+///
+/// In TOML, it is a list of shards covering every slot once:
+///
+/// ```toml
+/// [[shards]]
+/// start = 0
+/// end = 8192
+/// addr = "127.0.0.1:4000"
+///
+/// [[shards]]
+/// start = 8192
+/// end = 16384
+/// addr = "127.0.0.1:4001"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfig {
+    shards: Vec<Shard>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    shards: Vec<Shard>,
+}
+
+impl ClusterConfig {
+    /// Creates a layout from its shards, in any order.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the shards overlap or leave slots unowned.
+    pub fn new(mut shards: Vec<Shard>) -> Result<ClusterConfig> {
+        shards.sort_by_key(|shard| shard.start);
+        let mut next = 0;
+        for shard in &shards {
+            if shard.start != next || shard.end <= shard.start {
+                return Err(KvsError::StringError(format!(
+                    "shard {}..{} of {}: expected a non-empty range from slot {}",
+                    shard.start, shard.end, shard.addr, next
+                )));
+            }
+            next = shard.end;
+        }
+        if next != SLOTS {
+            return Err(KvsError::StringError(format!(
+                "slots {}..{} are not owned by any shard",
+                next, SLOTS
+            )));
+        }
+        Ok(ClusterConfig { shards })
+    }
+
+    /// Evenly splits the slots between `nodes`.
+    pub fn even(nodes: &[SocketAddr]) -> Result<ClusterConfig> {
+        let n = nodes.len() as u32;
+        let shards = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, &addr)| Shard {
+                start: SLOTS * i as u32 / n,
+                end: SLOTS * (i as u32 + 1) / n,
+                addr,
+            })
+            .collect();
+        ClusterConfig::new(shards)
+    }
+
+    /// Reads a layout from a TOML file.
+    pub fn load(path: &Path) -> Result<ClusterConfig> {
+        let file: ConfigFile = toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            KvsError::StringError(format!("invalid cluster config {}: {}", path.display(), e))
+        })?;
+        ClusterConfig::new(file.shards)
+    }
+
+    /// Returns the shards, ordered by slot.
+    pub fn shards(&self) -> &[Shard] {
+        &self.shards
+    }
+
+    /// Returns the addresses of the servers, without duplicates.
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        let mut nodes: Vec<_> = self.shards.iter().map(|shard| shard.addr).collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    /// Returns the address of the server owning `key`.
+    pub fn owner(&self, key: &str) -> SocketAddr {
+        let slot = key_slot(key);
+        let i = self.shards.partition_point(|shard| shard.end <= slot);
+        self.shards[i].addr
+    }
+}
+
+/// A client of a cluster of `KvsServer`s.
+///
+/// Each request about a key is sent to the server owning it. The keys of a
+/// multi-key request are grouped by owner, and each group is pipelined to its
+/// server. Scans and index lookups are sent to every server and their results
+/// merged, keeping only the pairs a server owns, so stray copies left by an
+/// interrupted `rebalance` are not seen twice. Connections are opened on first
+/// use.
+pub struct ClusterClient {
+    config: ClusterConfig,
+    clients: BTreeMap<SocketAddr, KvsClient>,
+}
+
+impl ClusterClient {
+    /// Creates a client routing requests with `config`.
+    pub fn new(config: ClusterConfig) -> ClusterClient {
+        ClusterClient {
+            config,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the layout requests are routed with.
+    pub fn config(&self) -> &ClusterConfig {
+        &self.config
+    }
+
+    /// Get the value of a given key from the cluster.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        connect(&mut self.clients, self.config.owner(&key))?.get(key)
+    }
+
+    /// Set the value of a given key in the cluster.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        connect(&mut self.clients, self.config.owner(&key))?.set(key, value)
+    }
+
+    /// Remove a given key from the cluster.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        connect(&mut self.clients, self.config.owner(&key))?.remove(key)
+    }
+
+    /// Get the values of `keys` from the cluster, in the same order.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let groups = self.group_by_owner(keys.into_iter().enumerate(), |(_, key)| key);
+        for (node, group) in groups {
+            let (positions, keys): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+            let found = connect(&mut self.clients, node)?.get_many(keys)?;
+            for (i, value) in positions.into_iter().zip(found) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Set the values of several keys in the cluster.
+    ///
+    /// On an error, some of the pairs may have been set.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (node, group) in self.group_by_owner(pairs, |(key, _)| key) {
+            connect(&mut self.clients, node)?.set_many(group)?;
+        }
+        Ok(())
+    }
+
+    /// Splits `items` by the server owning their key, keeping their order.
+    fn group_by_owner<T>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        key: impl Fn(&T) -> &str,
+    ) -> BTreeMap<SocketAddr, Vec<T>> {
+        let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for item in items {
+            groups.entry(self.config.owner(key(&item))).or_default().push(item);
+        }
+        groups
+    }
+
+    /// Scan at most `limit` key/value pairs starting from `start` in the
+    /// whole cluster.
+    pub fn scan(&mut self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for node in self.config.nodes() {
+            let client = connect(&mut self.clients, node)?;
+            let mut start = start.clone();
+            let mut owned = 0;
+            // skip the pairs the node does not own until it gives `limit` pairs.
+            while owned < limit {
+                let batch = client.scan(start, limit)?;
+                let done = batch.len() < limit;
+                start = match batch.last() {
+                    Some((key, _)) => format!("{}\0", key),
+                    None => break,
+                };
+                for (key, value) in batch {
+                    if self.config.owner(&key) == node && owned < limit {
+                        pairs.push((key, value));
+                        owned += 1;
+                    }
+                }
+                if done {
+                    break;
+                }
+            }
+        }
+        pairs.sort();
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    /// Find at most `limit` key/value pairs whose JSON value holds `field` at
+    /// the pointer of the secondary index `index`, in the whole cluster.
+    pub fn index_lookup(
+        &mut self,
+        index: String,
+        field: String,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for node in self.config.nodes() {
+            let client = connect(&mut self.clients, node)?;
+            let found = client.index_lookup(index.clone(), field.clone(), limit)?;
+            pairs.extend(found.into_iter().filter(|(key, _)| self.config.owner(key) == node));
+        }
+        pairs.sort();
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+}
+
+/// Returns the connection to `addr`, opening it if needed.
+fn connect(
+    clients: &mut BTreeMap<SocketAddr, KvsClient>,
+    addr: SocketAddr,
+) -> Result<&mut KvsClient> {
+    Ok(match clients.entry(addr) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(KvsClient::connect(addr)?),
+    })
+}
+
+/// Moves the keys of the servers of `from` to their owners in `to`.
+///
+/// The keys of each server are scanned `batch` at a time, and every key `to`
+/// assigns to another server is copied there, then removed. A key is always on
+/// at least one server, so an interrupted run can simply be started again. The
+/// cluster should not take writes while it runs, and clients should switch to
+/// `to` once it is done.
+///
+/// Returns the number of keys moved.
+pub fn rebalance(from: &ClusterConfig, to: &ClusterConfig, batch: usize) -> Result<usize> {
+    if batch == 0 {
+        return Err(KvsError::StringError("the batch size must not be 0".to_owned()));
+    }
+    let mut target = ClusterClient::new(to.clone());
+    let mut moved = 0;
+    for node in from.nodes() {
+        let mut source = KvsClient::connect(node)?;
+        let mut start = String::new();
+        loop {
+            let pairs = source.scan(start, batch)?;
+            let done = pairs.len() < batch;
+            start = match pairs.last() {
+                Some((key, _)) => format!("{}\0", key),
+                None => break,
+            };
+            for (key, value) in pairs {
+                if to.owner(&key) != node {
+                    target.set(key.clone(), value)?;
+                    source.remove(key)?;
+                    moved += 1;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+    }
+    Ok(moved)
+}
@@ -1,4 +1,8 @@
-use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{KvsError, Result};
 
 /// Request type for kvs.
 #[derive(Debug, Serialize, Deserialize)]
@@ -9,22 +13,150 @@ pub enum Request {
     Set { key: String, value: String },
     /// Remove a given key from the server.
     Remove { key: String },
+    /// Scan at most `limit` key/value pairs starting from `start`.
+    Scan { start: String, limit: usize },
+    /// Find at most `limit` key/value pairs whose JSON value holds `field`
+    /// at the pointer of the secondary index `index`.
+    IndexLookup {
+        index: String,
+        field: String,
+        limit: usize,
+    },
 }
 
+/// Response type for kvs.
+///
+/// Every request is answered by exactly one response. `Err` can also be sent
+/// by the server on its own, e.g. when a connection is rejected, so a client
+/// can always decode it whatever it asked for.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+pub enum Response {
+    Get(Option<String>),
+    Set,
+    Remove,
+    Scan(Vec<(String, String)>),
+    IndexLookup(Vec<(String, String)>),
+    Err(RemoteError),
 }
 
+/// Error type sent over the network.
+///
+/// It mirrors `KvsError`, so the client gets back the same kind of error the
+/// server ran into instead of a bare message.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
-    Ok(()),
-    Err(String),
+pub enum RemoteError {
+    KeyNotFound,
+    Io(String),
+    Corruption(String),
+    Busy(String),
+    PreconditionFailed(String),
+    InvalidRequest(String),
+    LimitExceeded(String),
+    Timeout(String),
+    Other(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum RemoveResponse {
-    Ok(()),
-    Err(String),
+impl From<KvsError> for RemoteError {
+    fn from(err: KvsError) -> RemoteError {
+        match err {
+            KvsError::KeyNotFound => RemoteError::KeyNotFound,
+            KvsError::Io(e) => RemoteError::Io(e.to_string()),
+            // the engines only deserialize what they have written before,
+            // so these errors mean the stored data is broken.
+            KvsError::Serde(e) => RemoteError::Corruption(e.to_string()),
+            KvsError::Utf8(e) => RemoteError::Corruption(e.to_string()),
+            e @ KvsError::NotValidType => RemoteError::Corruption(e.to_string()),
+            KvsError::Sled(sled::Error::Io(e)) => RemoteError::Io(e.to_string()),
+            KvsError::Sled(e @ sled::Error::Corruption { .. }) => {
+                RemoteError::Corruption(e.to_string())
+            }
+            e @ KvsError::Sled(_) => RemoteError::Other(e.to_string()),
+            KvsError::Corruption(msg) => RemoteError::Corruption(msg),
+            e @ KvsError::IntegrityCheckFailed(_) => RemoteError::Corruption(e.to_string()),
+            KvsError::Busy(msg) => RemoteError::Busy(msg),
+            KvsError::PreconditionFailed(msg) => RemoteError::PreconditionFailed(msg),
+            KvsError::InvalidRequest(msg) => RemoteError::InvalidRequest(msg),
+            KvsError::LimitExceeded(msg) => RemoteError::LimitExceeded(msg),
+            KvsError::Timeout(msg) => RemoteError::Timeout(msg),
+            e @ KvsError::IndexNotFound(_) => RemoteError::InvalidRequest(e.to_string()),
+            e @ KvsError::Locked(_) | e @ KvsError::ReadOnly | e @ KvsError::UnknownKey(_) => {
+                RemoteError::Other(e.to_string())
+            }
+            KvsError::StringError(msg) => RemoteError::Other(msg),
+        }
+    }
+}
+
+impl From<RemoteError> for KvsError {
+    fn from(err: RemoteError) -> KvsError {
+        match err {
+            RemoteError::KeyNotFound => KvsError::KeyNotFound,
+            RemoteError::Io(msg) => KvsError::Io(io::Error::other(msg)),
+            RemoteError::Corruption(msg) => KvsError::Corruption(msg),
+            RemoteError::Busy(msg) => KvsError::Busy(msg),
+            RemoteError::PreconditionFailed(msg) => KvsError::PreconditionFailed(msg),
+            RemoteError::InvalidRequest(msg) => KvsError::InvalidRequest(msg),
+            RemoteError::LimitExceeded(msg) => KvsError::LimitExceeded(msg),
+            RemoteError::Timeout(msg) => KvsError::Timeout(msg),
+            RemoteError::Other(msg) => KvsError::StringError(msg),
+        }
+    }
+}
+
+/// A frame read from a length-delimited stream.
+pub enum Frame {
+    /// The payload of a frame.
+    Data(Vec<u8>),
+    /// A frame larger than the allowed size. Its payload has been skipped,
+    /// so the stream is still positioned at the next frame.
+    TooLarge(u64),
+    /// The peer closed the stream between two frames.
+    Eof,
+}
+
+/// Serializes `value` as JSON and writes it as one frame prefixed by its
+/// length as a big-endian `u32`.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let payload = serde_json::to_vec(value)?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads one frame whose payload is at most `max_size` bytes.
+pub fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> Result<Frame> {
+    let mut len_buf = [0; 4];
+    let mut read = 0;
+    while read < len_buf.len() {
+        match reader.read(&mut len_buf[read..]) {
+            Ok(0) if read == 0 => return Ok(Frame::Eof),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let len = u32::from_be_bytes(len_buf) as u64;
+    if len > max_size as u64 {
+        let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+        if skipped < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        return Ok(Frame::TooLarge(len));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Frame::Data(payload))
+}
+
+/// Reads one frame and deserializes it, with no limit on its size.
+///
+/// Returns `None` if the peer closed the stream.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    match read_frame(reader, u32::MAX as usize)? {
+        Frame::Data(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        Frame::TooLarge(_) | Frame::Eof => Ok(None),
+    }
 }
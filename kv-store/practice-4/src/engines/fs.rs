@@ -0,0 +1,143 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+/// The filesystem operations `KvStore` relies on.
+///
+/// `KvStore` uses `OsFs` unless `KvStoreOptions::fs` is set, which lets tests
+/// inject faults or crashes below the store.
+///
+/// Creating and removing files is expected to be durable once it returns,
+/// while the data written to a file is only durable once it is synced.
+pub trait Fs: fmt::Debug + Send + Sync + 'static {
+    /// Creates a directory and all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the paths of the files in a directory.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FsRead>>;
+
+    /// Creates a file, or opens it if it exists, for appending.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FsWrite>>;
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the length of a file.
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Maps the content of a file which is not written anymore in memory.
+    ///
+    /// It reads the whole file by default.
+    fn map(&self, path: &Path) -> io::Result<Box<dyn FsMap>> {
+        let mut data = Vec::new();
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(Box::new(data))
+    }
+
+    /// Locks a file, in shared mode or exclusively, creating it if needed.
+    ///
+    /// The lock is held until the returned value is dropped. It returns an
+    /// error of kind `WouldBlock` if the lock is held by someone else.
+    fn lock(&self, path: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>>;
+}
+
+/// A file opened for reading by `Fs::open`.
+pub trait FsRead: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> FsRead for T {}
+
+/// The content of a file mapped by `Fs::map`.
+pub trait FsMap: AsRef<[u8]> + Send + Sync {}
+
+impl<T: AsRef<[u8]> + Send + Sync> FsMap for T {}
+
+/// A file opened for appending by `Fs::create`.
+pub trait FsWrite: Write + Send {
+    /// Makes the data written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl FsWrite for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// The filesystem of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFs;
+
+impl Fs for OsFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FsRead>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FsWrite>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn map(&self, path: &Path) -> io::Result<Box<dyn FsMap>> {
+        let file = File::open(path)?;
+        // Safety: the store only maps files it does not write anymore, and
+        // holds the lock of the directory so no other store writes them.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Box::new(map))
+    }
+
+    fn lock(&self, path: &Path, shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        let file = if shared {
+            // a read-only store should not need write permission when the file exists.
+            match File::open(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    OpenOptions::new().write(true).create(true).truncate(false).open(path)?
+                }
+                res => res?,
+            }
+        } else {
+            OpenOptions::new().write(true).create(true).truncate(false).open(path)?
+        };
+
+        let res = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        match res {
+            Ok(()) => Ok(Box::new(file)),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
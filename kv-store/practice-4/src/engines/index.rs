@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crossbeam_skiplist::{SkipMap, SkipSet};
+use serde_json::Value;
+
+use crate::{KvsError, Result};
+
+/// The declaration of a secondary index of a `KvStore`.
+///
+/// The index maps the field found at `pointer` in the values which are JSON
+/// documents to the keys holding them. Values which are not JSON, or which do
+/// not have the field, are not indexed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexSpec {
+    /// The name lookups refer to the index by.
+    pub name: String,
+    /// The JSON pointer of the indexed field, e.g. `/address/city`.
+    pub pointer: String,
+}
+
+impl IndexSpec {
+    /// Creates the declaration of an index.
+    pub fn new(name: impl Into<String>, pointer: impl Into<String>) -> IndexSpec {
+        IndexSpec {
+            name: name.into(),
+            pointer: pointer.into(),
+        }
+    }
+}
+
+/// The indexed fields of a value, stored along with it in the log.
+///
+/// It maps the pointer of each index to the field found there, as JSON, or to
+/// `None` if the value does not have it. Storing `None` tells a value without
+/// the field from one written before the index was declared.
+pub(crate) type IndexFields = BTreeMap<String, Option<String>>;
+
+/// The secondary indexes of a `KvStore`.
+///
+/// They are kept in memory and rebuilt from the fields stored in the log when
+/// the store is opened.
+pub(crate) struct Indexes {
+    indexes: BTreeMap<String, SecondaryIndex>,
+    pointers: BTreeSet<String>,
+}
+
+struct SecondaryIndex {
+    pointer: String,
+    // the indexed fields along with the keys holding them, for lookups.
+    entries: SkipSet<(String, String)>,
+    // the indexed field of each key, to update `entries`.
+    fields: SkipMap<String, String>,
+}
+
+impl Indexes {
+    pub(crate) fn new(specs: &[IndexSpec]) -> Result<Indexes> {
+        let mut indexes = BTreeMap::new();
+        for spec in specs {
+            if !spec.pointer.is_empty() && !spec.pointer.starts_with('/') {
+                return Err(KvsError::StringError(format!(
+                    "invalid JSON pointer '{}' for index {}",
+                    spec.pointer, spec.name
+                )));
+            }
+            let index = SecondaryIndex {
+                pointer: spec.pointer.clone(),
+                entries: SkipSet::new(),
+                fields: SkipMap::new(),
+            };
+            if indexes.insert(spec.name.clone(), index).is_some() {
+                return Err(KvsError::StringError(format!("duplicate index {}", spec.name)));
+            }
+        }
+        let pointers = specs.iter().map(|spec| spec.pointer.clone()).collect();
+        Ok(Indexes { indexes, pointers })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Extracts the indexed fields of a value.
+    pub(crate) fn extract(&self, value: &str) -> IndexFields {
+        if self.is_empty() {
+            return IndexFields::new();
+        }
+        let json: Option<Value> = serde_json::from_str(value).ok();
+        self.pointers
+            .iter()
+            .map(|pointer| {
+                let field = json.as_ref().and_then(|json| json.pointer(pointer));
+                (pointer.clone(), field.map(Value::to_string))
+            })
+            .collect()
+    }
+
+    /// Returns whether `fields` holds the field of every index.
+    pub(crate) fn covers(&self, fields: &IndexFields) -> bool {
+        self.pointers.iter().all(|pointer| fields.contains_key(pointer))
+    }
+
+    /// Returns whether an index is declared on `pointer`.
+    pub(crate) fn is_declared(&self, pointer: &str) -> bool {
+        self.pointers.contains(pointer)
+    }
+
+    /// Indexes `key` by its new fields.
+    pub(crate) fn insert(&self, key: &str, fields: &IndexFields) {
+        for index in self.indexes.values() {
+            index.remove(key);
+            if let Some(Some(field)) = fields.get(&index.pointer) {
+                index.entries.insert((field.clone(), key.to_owned()));
+                index.fields.insert(key.to_owned(), field.clone());
+            }
+        }
+    }
+
+    /// Removes `key` from all the indexes.
+    pub(crate) fn remove(&self, key: &str) {
+        for index in self.indexes.values() {
+            index.remove(key);
+        }
+    }
+
+    /// Returns the first `limit` keys, in order, whose field in the index
+    /// `name` equals `field`.
+    ///
+    /// `field` is parsed as JSON, or taken as a string if it is not valid
+    /// JSON, so `30` matches a number and `alice` matches `"alice"`.
+    pub(crate) fn lookup(&self, name: &str, field: &str, limit: usize) -> Result<Vec<String>> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        let field = match serde_json::from_str::<Value>(field) {
+            Ok(json) => json.to_string(),
+            Err(_) => Value::String(field.to_owned()).to_string(),
+        };
+        Ok(index
+            .entries
+            .range((field.clone(), String::new())..)
+            .take_while(|entry| entry.value().0 == field)
+            .take(limit)
+            .map(|entry| entry.value().1.clone())
+            .collect())
+    }
+}
+
+impl SecondaryIndex {
+    fn remove(&self, key: &str) {
+        if let Some(old) = self.fields.remove(key) {
+            self.entries.remove(&(old.value().clone(), key.to_owned()));
+        }
+    }
+}
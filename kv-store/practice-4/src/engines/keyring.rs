@@ -0,0 +1,155 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::{KvsError, Result};
+
+/// Length in bytes of an encryption key.
+pub const KEY_LEN: usize = 32;
+// length of the random nonce stored in front of each sealed record.
+const NONCE_LEN: usize = 12;
+
+/// Encryption keys of a `KvStore`, identified by a number.
+///
+/// New generation files are encrypted with the active key, which is the last
+/// one added. The other keys are only used to read older generations, until a
+/// compaction rewrites them with the active key.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    ciphers: BTreeMap<u32, Cipher>,
+    active: Option<u32>,
+}
+
+impl Keyring {
+    /// Creates an empty keyring. A store opened with it is not encrypted.
+    pub fn new() -> Keyring {
+        Keyring::default()
+    }
+
+    /// Adds a key and makes it the active one.
+    ///
+    /// The id 0 is reserved for unencrypted generations.
+    pub fn add(&mut self, id: u32, key: [u8; KEY_LEN]) -> Result<()> {
+        if id == 0 {
+            return Err(KvsError::StringError("key id 0 is reserved".to_owned()));
+        }
+        if self.ciphers.contains_key(&id) {
+            return Err(KvsError::StringError(format!("duplicate key id {}", id)));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        self.ciphers.insert(id, Cipher(cipher));
+        self.active = Some(id);
+        Ok(())
+    }
+
+    /// Loads the keys from a file.
+    ///
+    /// Each line holds a key id and the key as 64 hexadecimal digits, separated
+    /// by spaces. Empty lines and lines starting with `#` are ignored. The last
+    /// key of the file is the active one.
+    pub fn load(path: impl AsRef<Path>) -> Result<Keyring> {
+        let mut keyring = Keyring::new();
+        let content = fs::read_to_string(path.as_ref())?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                KvsError::StringError(format!(
+                    "{}:{}: expected a key id and a 32-byte hexadecimal key",
+                    path.as_ref().display(),
+                    n + 1
+                ))
+            };
+            let mut fields = line.split_whitespace();
+            let id = fields
+                .next()
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(invalid)?;
+            let mut key = [0; KEY_LEN];
+            fields
+                .next()
+                .and_then(|key_hex| hex::decode_to_slice(key_hex, &mut key).ok())
+                .ok_or_else(invalid)?;
+            if fields.next().is_some() {
+                return Err(invalid());
+            }
+            keyring.add(id, key)?;
+        }
+        Ok(keyring)
+    }
+
+    /// Returns the id of the active key, or 0 if the keyring is empty.
+    pub(crate) fn active_id(&self) -> u32 {
+        self.active.unwrap_or(0)
+    }
+
+    /// Returns the cipher of the key `id`, or `None` for unencrypted generations.
+    ///
+    /// Unencrypted generations are refused once the keyring holds keys, so
+    /// records cannot be slipped into an encrypted store in plain text.
+    pub(crate) fn cipher(&self, id: u32) -> Result<Option<Cipher>> {
+        if id == 0 {
+            if self.ciphers.is_empty() {
+                return Ok(None);
+            }
+            return Err(KvsError::IntegrityCheckFailed(
+                "unencrypted file in an encrypted store".to_owned(),
+            ));
+        }
+        match self.ciphers.get(&id) {
+            Some(cipher) => Ok(Some(cipher.clone())),
+            None => Err(KvsError::UnknownKey(id)),
+        }
+    }
+}
+
+impl fmt::Debug for Keyring {
+    // never print the keys themselves.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("ids", &self.ciphers.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+/// Authenticated encryption with one key.
+#[derive(Clone)]
+pub(crate) struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    /// Encrypts `data` with a random nonce, authenticating `aad` along with it.
+    ///
+    /// Returns the nonce followed by the ciphertext and its tag.
+    pub(crate) fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| KvsError::StringError("encryption failed".to_owned()))?;
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    /// Decrypts what `seal` returned.
+    ///
+    /// It returns `KvsError::IntegrityCheckFailed` if the data or `aad` were
+    /// modified, or if they were sealed with another key.
+    pub(crate) fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(KvsError::IntegrityCheckFailed(
+                "record is too short".to_owned(),
+            ));
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|_| KvsError::IntegrityCheckFailed("tag mismatch".to_owned()))
+    }
+}
@@ -1,30 +1,146 @@
 use std::{
-    collections::BTreeMap,
+    collections::{btree_map::Entry, BTreeMap},
     ffi::OsStr,
-    fs::{self, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
     ops::Range,
-    path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, Ordering}, Mutex}, cell::RefCell,
+    path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, Ordering}, Mutex}, cell::{Cell, RefCell},
+    thread,
 };
 
 use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use log::error;
+use log::{error, warn};
 
+use super::fs::{Fs, FsMap, FsRead, FsWrite, OsFs};
+use super::index::{IndexFields, IndexSpec, Indexes};
+use super::keyring::{Cipher, Keyring};
 use crate::{KvsEngine, KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// name of the file locked by the processes using a directory.
+const LOCK_FILE: &str = "LOCK";
+// first byte of a record with a header. Records starting with `{` are
+// plain JSON commands written before compression was supported.
+const RECORD_MAGIC: u8 = 0xfe;
+// first bytes of a generation file with a header. The header is followed by
+// the id of the key encrypting the records as a big-endian `u32`, 0 if they
+// are not encrypted. Files without a header are not encrypted.
+const GEN_MAGIC: &[u8; 4] = b"KVSG";
+const GEN_HEADER_LEN: usize = 8;
+
+/// The codec used to compress the records of a `KvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Records are stored as plain JSON.
+    None,
+    /// Records are compressed with LZ4.
+    Lz4,
+    /// Records are compressed with zstd at the given level.
+    Zstd(i32),
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd(_) => 2,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd(level) => Ok(zstd::bulk::compress(data, level)?),
+        }
+    }
+}
+
+/// Decompresses the payload of a record written with the codec `id`.
+fn decompress(id: u8, data: Vec<u8>) -> Result<Vec<u8>> {
+    match id {
+        0 => Ok(data),
+        1 => lz4_flex::decompress_size_prepended(&data)
+            .map_err(|e| KvsError::Corruption(format!("invalid lz4 record: {}", e))),
+        2 => zstd::stream::decode_all(data.as_slice())
+            .map_err(|e| KvsError::Corruption(format!("invalid zstd record: {}", e))),
+        id => Err(KvsError::Corruption(format!("unknown codec {}", id))),
+    }
+}
+
+/// When a `KvStore` makes its writes durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Writes are handed to the operating system, which persists them later.
+    /// The last writes can be lost if the machine crashes.
+    Never,
+    /// Writes are synced to disk before `set` or `remove` returns.
+    Always,
+}
+
+/// Options of a `KvStore`.
+///
+/// The codec and the active key only apply to the records written from now
+/// on. Records written with other ones stay readable, and `KvStore::compact`
+/// rewrites them with the current ones.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// The codec used to compress the records.
+    pub compression: Compression,
+    /// Records smaller than this many bytes are not compressed.
+    pub compression_threshold: usize,
+    /// The keys encrypting the generation files. New generations are not
+    /// encrypted if it is empty, and unencrypted ones are refused otherwise.
+    pub keyring: Keyring,
+    /// Opens the store for inspection only, see `KvStore::open_read_only`.
+    pub read_only: bool,
+    /// Values of at least this many bytes are stored in blob files and only
+    /// referenced from the log, so compactions do not copy them. All values
+    /// stay in the log if it is `None`.
+    pub blob_threshold: Option<usize>,
+    /// A new blob file is started once the current one reaches this size.
+    pub blob_file_size: u64,
+    /// A blob file is rewritten once this fraction of it is garbage.
+    pub blob_gc_ratio: f64,
+    /// The filesystem the store is kept in.
+    pub fs: Arc<dyn Fs>,
+    /// A compaction runs once the stale records take this many bytes.
+    pub compaction_threshold: u64,
+    /// When writes are synced to disk.
+    pub sync: SyncPolicy,
+    /// The secondary indexes over the JSON values. Opening the store with a
+    /// new index rewrites the log like `KvStore::compact` to fill it.
+    pub indexes: Vec<IndexSpec>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compression: Compression::None,
+            compression_threshold: 256,
+            keyring: Keyring::new(),
+            read_only: false,
+            blob_threshold: None,
+            blob_file_size: 64 * 1024 * 1024,
+            blob_gc_ratio: 0.5,
+            fs: Arc::new(OsFs),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            sync: SyncPolicy::Never,
+            indexes: Vec::new(),
+        }
+    }
+}
 
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are stored in a `HashMap` in memory and persisted to disk by add to log.
 ///
 /// ```rust
-/// use kvs::{KvStore, Result};
+/// use kvs::{KvStore, KvsEngine, Result};
 /// fn try_main() -> Result<()> {
 ///     use std::env::current_dir;
-///     let mut store = KvStore::open(current_dir()?)?;
+///     let store = KvStore::open(current_dir()?)?;
 ///     store.set("key".to_owned(), "value".to_owned())?;
 ///     let val = store.get("key".to_owned())?;
 ///     assert_eq!(val, Some("value".to_owned()));
@@ -34,22 +150,42 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 ///
 #[derive(Clone)]
 pub struct KvStore {
-    // writer of the current log.
-    writer: Arc<Mutex<KvStoreWriter>>,
+    // writer of the current log, `None` if the store is read-only.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
     reader: KvStoreReader,
     // map generation number to the file reader
     index: Arc<SkipMap<String, CommandPos>>,
+    indexes: Arc<Indexes>,
+    // the lock of the `LOCK` file. It is released when the last clone is dropped.
+    _lock: Arc<dyn Send + Sync>,
 }
 
 struct KvStoreWriter {
     path: Arc<PathBuf>,
     reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
+    writer: BufWriterWithPos<Box<dyn FsWrite>>,
     index: Arc<SkipMap<String, CommandPos>>,
+    indexes: Arc<Indexes>,
     current_gen: u64,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     uncompacted: u64,
+    options: KvStoreOptions,
+    // cipher of the active key, used for all the generations written.
+    cipher: Option<Cipher>,
+    // writer of the current blob file, created with the first large value.
+    blob_writer: Option<BlobWriter>,
+    // number of the next blob file.
+    next_blob: u64,
+    // the size of each blob file and the number of bytes of values in it
+    // which are overwritten or removed.
+    blob_sizes: BTreeMap<u64, u64>,
+    blob_garbage: BTreeMap<u64, u64>,
+}
+
+struct BlobWriter {
+    file: u64,
+    writer: BufWriterWithPos<Box<dyn FsWrite>>,
 }
 
 impl KvStoreWriter {
@@ -57,20 +193,45 @@ impl KvStoreWriter {
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(key.as_str()) {
-                self.uncompacted += old_cmd.value().len;
+        let fields = self.indexes.extract(&value);
+        let cmd = match self.options.blob_threshold {
+            Some(threshold) if value.len() >= threshold => {
+                let blob = self.write_blob(key.clone(), value)?;
+                Command::SetBlob { key, blob, fields }
             }
-            self.index.insert(
-                key, ( self.current_gen, pos..self.writer.pos ).into());
-        }
-        if self.uncompacted > COMPACTION_THRESHOLD {
+            _ => Command::Set { key, value, fields },
+        };
+        self.write_set(cmd)?;
+        self.sync_if_needed()?;
+        if self.uncompacted > self.options.compaction_threshold {
             self.compact()?;
         }
+        self.collect_blobs()
+    }
+
+    /// Writes a `Set` or `SetBlob` command to the log and indexes it.
+    fn write_set(&mut self, cmd: Command) -> Result<()> {
+        let pos = self.writer.pos;
+        let file = RecordFile::Log(self.current_gen);
+        write_record(&mut self.writer, file, &cmd, &self.options, self.cipher.as_ref())?;
+        self.writer.flush()?;
+        let (key, blob, fields) = match cmd {
+            Command::Set { key, fields, .. } => (key, None, fields),
+            Command::SetBlob { key, blob, fields } => (key, Some(blob), fields),
+            Command::Remove { .. } => unreachable!(),
+        };
+        let cmd_pos = CommandPos {
+            blob,
+            ..(self.current_gen, pos..self.writer.pos).into()
+        };
+        let old_cmd = self.index.get(key.as_str()).map(|entry| *entry.value());
+        if let Some(old_cmd) = old_cmd {
+            self.discard(old_cmd);
+        }
+        self.indexes.insert(&key, &fields);
+        self.reader.replace_index(|| {
+            self.index.insert(key, cmd_pos);
+        });
         Ok(())
     }
 
@@ -78,37 +239,180 @@ impl KvStoreWriter {
     fn remove(&mut self, key: String) -> Result<()> {
         if self.index.contains_key(&key) {
             let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
+            let file = RecordFile::Log(self.current_gen);
+            write_record(&mut self.writer, file, &cmd, &self.options, self.cipher.as_ref())?;
             self.writer.flush()?;
+            self.sync_if_needed()?;
             if let Command::Remove { key } = cmd {
-                self.index.remove(&key);
+                self.indexes.remove(&key);
+                let old_cmd = self.index.remove(&key).map(|entry| *entry.value());
+                if let Some(old_cmd) = old_cmd {
+                    self.discard(old_cmd);
+                }
             }
-            Ok(())
+            self.collect_blobs()
         } else {
             Err(KvsError::KeyNotFound)?
         }
     }
 
+    /// Syncs the current log if the sync policy asks for it.
+    fn sync_if_needed(&mut self) -> Result<()> {
+        if self.options.sync == SyncPolicy::Always {
+            self.writer.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Accounts for a command which is not live anymore.
+    fn discard(&mut self, cmd_pos: CommandPos) {
+        self.uncompacted += cmd_pos.len;
+        if let Some(blob) = cmd_pos.blob {
+            *self.blob_garbage.entry(blob.file).or_insert(0) += blob.len;
+        }
+    }
+
+    /// Appends a value to the current blob file, starting a new one if it is full.
+    fn write_blob(&mut self, key: String, value: String) -> Result<BlobPos> {
+        let full = match &self.blob_writer {
+            Some(blob_writer) => blob_writer.writer.pos >= self.options.blob_file_size,
+            None => true,
+        };
+        if full {
+            let file = self.next_blob;
+            let key_id = self.options.keyring.active_id();
+            let writer = new_log_file(&*self.options.fs, &blob_path(&self.path, file), key_id)?;
+            self.blob_sizes.insert(file, writer.pos);
+            self.blob_writer = Some(BlobWriter { file, writer });
+            self.next_blob += 1;
+        }
+
+        let BlobWriter { file, writer } = self.blob_writer.as_mut().unwrap();
+        let pos = writer.pos;
+        let cmd = Command::set(key, value);
+        let len = write_record(
+            writer,
+            RecordFile::Blob(*file),
+            &cmd,
+            &self.options,
+            self.cipher.as_ref(),
+        )?;
+        // the value must be durable before the log refers to it.
+        writer.sync()?;
+        self.blob_sizes.insert(*file, writer.pos);
+        Ok(BlobPos {
+            file: *file,
+            pos,
+            len,
+        })
+    }
+
+    /// Rewrites the live values of the blob files with too much garbage to the
+    /// current blob file, and deletes them.
+    ///
+    /// The current blob file is never collected.
+    fn collect_blobs(&mut self) -> Result<()> {
+        let current = self.blob_writer.as_ref().map(|blob_writer| blob_writer.file);
+        let ratio = self.options.blob_gc_ratio;
+        let files: Vec<u64> = self
+            .blob_sizes
+            .iter()
+            .filter(|&(file, size)| {
+                let garbage = self.blob_garbage.get(file).copied().unwrap_or(0);
+                Some(*file) != current && garbage as f64 >= *size as f64 * ratio
+            })
+            .map(|(file, _)| *file)
+            .collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let live: Vec<(String, BlobPos)> = self
+            .index
+            .iter()
+            .filter_map(|entry| match entry.value().blob {
+                Some(blob) if files.contains(&blob.file) => Some((entry.key().clone(), blob)),
+                _ => None,
+            })
+            .collect();
+        for (key, blob) in live {
+            let value = self.reader.get_blob(blob)?;
+            let fields = self.indexes.extract(&value);
+            let blob = self.write_blob(key.clone(), value)?;
+            self.write_set(Command::SetBlob { key, blob, fields })?;
+        }
+        // the log must point to the new copies before the old ones are gone.
+        self.writer.sync()?;
+
+        self.reader.blob_gcs.fetch_add(1, Ordering::SeqCst);
+        for file in files {
+            self.blob_sizes.remove(&file);
+            self.blob_garbage.remove(&file);
+            let file_path = blob_path(&self.path, file);
+            if let Err(e) = self.options.fs.remove_file(&file_path) {
+                error!("{:?} cannot be deleted: {}", file_path, e);
+            }
+        }
+        Ok(())
+    }
+
     /// Clears stale entries in the log.
+    ///
+    /// The live entries are rewritten with the current options, so this also
+    /// recompresses them if the codec changed, reencrypts them if the active
+    /// key changed, and rebuilds the secondary indexes if they changed.
     pub fn compact(&mut self) -> Result<()> {
+        // the compaction file holds the latest values, so a crash must not keep
+        // it while losing the end of the current log.
+        self.writer.sync()?;
+
         // increase current gen by 2. current_gen + 1 is for the compaction file.
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+        let key_id = self.options.keyring.active_id();
+        let fs = &*self.options.fs;
+        self.writer = new_log_file(fs, &log_path(&self.path, self.current_gen), key_id)?;
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-        let mut new_pos = 0; // pos in the new log file.
+        let mut compaction_writer =
+            new_log_file(fs, &log_path(&self.path, compaction_gen), key_id)?;
+        let mut new_pos = compaction_writer.pos; // pos in the new log file.
+        let mut moved = Vec::with_capacity(self.index.len());
+        let mut reindexed = Vec::new();
         for entry in &mut self.index.iter() {
-            let len = self.reader.read_(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(), 
-                (compaction_gen, new_pos..new_pos+len).into());
+            let mut cmd = self.reader.get(*entry.value())?;
+            if let Some(fields) = self.reader.reindex(&mut cmd, &self.indexes)? {
+                reindexed.push((entry.key().clone(), fields));
+            }
+            let len = write_record(
+                &mut compaction_writer,
+                RecordFile::Log(compaction_gen),
+                &cmd,
+                &self.options,
+                self.cipher.as_ref(),
+            )?;
+            moved.push((
+                entry.key().clone(),
+                CommandPos {
+                    blob: entry.value().blob,
+                    ..(compaction_gen, new_pos..new_pos+len).into()
+                },
+            ));
             new_pos += len;
         }
-        compaction_writer.flush()?;
+        // the stale files are deleted below.
+        compaction_writer.sync()?;
 
+        // the compaction file is complete, and the previous log is not written
+        // anymore. Readers only find the moved records once they can be read.
+        self.reader.mutable_from.store(self.current_gen, Ordering::SeqCst);
+        self.reader.replace_index(|| {
+            for (key, cmd_pos) in moved {
+                self.index.insert(key, cmd_pos);
+            }
+        });
+        for (key, fields) in reindexed {
+            self.indexes.insert(&key, &fields);
+        }
         self.reader.safe_point.store(compaction_gen, Ordering::SeqCst);
         self.reader.clear_stale_handles();
         // remove stale log files.
@@ -117,12 +421,12 @@ impl KvStoreWriter {
         // its stale file handles. On Unix, the files will be deleted after all the handles
         // are closed. On Windows, the deletions below will fail and stale files are expected
         // to be deleted in the next compaction.
-        let stale_gens = sorted_gen_list(&self.path)?
+        let stale_gens = sorted_gen_list(fs, &self.path, "log")?
             .into_iter()
             .filter(|gen| *gen < compaction_gen);
         for stale_gen in stale_gens {
             let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(log_path(&self.path, stale_gen)) {
+            if let Err(e) = fs.remove_file(&file_path) {
                 error!("{:?} cannot be deleted: {}", file_path, e);
             }
         }
@@ -141,10 +445,93 @@ impl KvStoreWriter {
 /// can read concurrently through multiple `KvStore`s in different
 /// threads.
 struct KvStoreReader {
+    fs: Arc<dyn Fs>,
     path: Arc<PathBuf>,
     // generation of the latest compaction file.
     safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    // generations from this one on may still be written. The older ones are
+    // immutable, so they are mapped in memory once and shared by all readers.
+    mutable_from: Arc<AtomicU64>,
+    mapped: Arc<SkipMap<u64, Arc<MappedGen>>>,
+    keyring: Arc<Keyring>,
+    // readers of the generations which may still be written.
+    readers: RefCell<BTreeMap<u64, GenReader>>,
+    // odd while entries of the index are being replaced, and bumped twice
+    // by each replacement.
+    replacing: Arc<AtomicU64>,
+    // number of blob collections so far, and when the blob readers were last
+    // cleared. The readers of deleted blob files are closed when they differ.
+    blob_gcs: Arc<AtomicU64>,
+    seen_blob_gcs: Cell<u64>,
+    blob_readers: RefCell<BTreeMap<u64, GenReader>>,
+}
+
+/// Reader of one generation or blob file.
+struct GenReader {
+    reader: BufReaderWithPos<Box<dyn FsRead>>,
+    // cipher of the key given in the header of the file.
+    cipher: Option<Cipher>,
+}
+
+impl GenReader {
+    /// Opens a generation or blob file and reads its header.
+    fn open(fs: &dyn Fs, file_path: &Path, keyring: &Keyring) -> Result<GenReader> {
+        let mut reader = BufReaderWithPos::new(fs.open(file_path)?)?;
+        let (header_len, key_id) = parse_header(reader.fill_buf()?);
+        reader.consume(header_len);
+        let cipher = match key_id {
+            Some(key_id) => keyring.cipher(key_id)?,
+            None => None,
+        };
+        Ok(GenReader { reader, cipher })
+    }
+}
+
+/// An immutable generation file mapped in memory.
+struct MappedGen {
+    data: Box<dyn FsMap>,
+    // cipher of the key given in the header of the file.
+    cipher: Option<Cipher>,
+}
+
+impl MappedGen {
+    fn open(fs: &dyn Fs, file_path: &Path, keyring: &Keyring) -> Result<MappedGen> {
+        let data = fs.map(file_path)?;
+        let (_, key_id) = parse_header((*data).as_ref());
+        let cipher = match key_id {
+            Some(key_id) => keyring.cipher(key_id)?,
+            None => None,
+        };
+        Ok(MappedGen { data, cipher })
+    }
+
+    /// Returns the bytes of a record.
+    fn record(&self, cmd_pos: CommandPos) -> Result<&[u8]> {
+        let data = (*self.data).as_ref();
+        usize::try_from(cmd_pos.pos + cmd_pos.len)
+            .ok()
+            .and_then(|end| data.get(cmd_pos.pos as usize..end))
+            .ok_or_else(|| {
+                KvsError::Corruption(format!("record past the end of {}.log", cmd_pos.gen))
+            })
+    }
+}
+
+/// Parses the beginning of a generation or blob file.
+///
+/// Returns the length of its header and the id of the key encrypting it,
+/// which is 0 for files written before headers existed. The id is `None` if
+/// the file holds no record.
+fn parse_header(buf: &[u8]) -> (usize, Option<u32>) {
+    if buf.len() < GEN_HEADER_LEN && GEN_MAGIC.starts_with(&buf[..buf.len().min(GEN_MAGIC.len())]) {
+        // the header was torn by a crash, or never written.
+        (buf.len(), None)
+    } else if buf.starts_with(GEN_MAGIC) {
+        let key_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        (GEN_HEADER_LEN, Some(key_id))
+    } else {
+        (0, Some(0))
+    }
 }
 
 impl KvStoreReader {
@@ -154,53 +541,155 @@ impl KvStoreReader {
     /// The compaction generation contains the sum of all operations before it and the
     /// in-memory index contains no entries with generation number less than safe_point.
     /// So we can safely close those file handles and the stale files can be deleted.
+    ///
+    /// The mappings of these generations are released too. Readers still using
+    /// one keep it alive until they are done.
     fn clear_stale_handles(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
         let mut readers = self.readers.borrow_mut();
         while !readers.is_empty() {
             let first_gen = *readers.keys().next().unwrap();
-            if first_gen >= self.safe_point.load(Ordering::SeqCst) {
+            if first_gen >= safe_point {
                 break;
             }
             readers.remove(&first_gen);
         }
+        while let Some(entry) = self.mapped.front() {
+            if *entry.key() >= safe_point {
+                break;
+            }
+            entry.remove();
+        }
     }
     
+    /// Runs `f`, which replaces entries of the index.
+    ///
+    /// The index replaces an entry by removing it first, so readers missing a
+    /// key meanwhile look it up again.
+    fn replace_index(&self, f: impl FnOnce()) {
+        self.replacing.fetch_add(1, Ordering::SeqCst);
+        f();
+        self.replacing.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns whether a key missing from the index since `replacing` was
+    /// loaded may have been being replaced.
+    fn maybe_replaced(&self, replacing: u64) -> bool {
+        replacing % 2 == 1 || self.replacing.load(Ordering::SeqCst) != replacing
+    }
+
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn read_<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where 
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(&mut dyn BufRead, Option<&Cipher>) -> Result<R>,
     {
         self.clear_stale_handles();
 
+        if cmd_pos.gen < self.mutable_from.load(Ordering::SeqCst) {
+            let mapped = match self.mapped.get(&cmd_pos.gen) {
+                Some(entry) => Arc::clone(entry.value()),
+                None => {
+                    let file_path = log_path(&self.path, cmd_pos.gen);
+                    let mapped = MappedGen::open(&*self.fs, &file_path, &self.keyring)?;
+                    // another reader may have mapped it in the meantime.
+                    let entry = self.mapped.get_or_insert(cmd_pos.gen, Arc::new(mapped));
+                    Arc::clone(entry.value())
+                }
+            };
+            return f(&mut mapped.record(cmd_pos)?, mapped.cipher.as_ref());
+        }
+
         let mut readers = self.readers.borrow_mut();
         if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(
-                File::open(log_path(&self.path, cmd_pos.gen))?)?;
+            let reader =
+                GenReader::open(&*self.fs, &log_path(&self.path, cmd_pos.gen), &self.keyring)?;
             readers.insert(cmd_pos.gen, reader);
         }
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        let GenReader { reader, cipher } = readers.get_mut(&cmd_pos.gen).unwrap();
         reader.seek(io::SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+        f(&mut reader.take(cmd_pos.len), cipher.as_ref())
     }
 
     fn get(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_(cmd_pos, |mut cmd_reader| {
-            Ok(serde_json::from_reader(&mut cmd_reader)?)
+        self.read_(cmd_pos, |cmd_reader, cipher| {
+            read_record(cmd_reader, cipher, RecordFile::Log(cmd_pos.gen), cmd_pos.pos)?
+                .ok_or_else(|| KvsError::Corruption("record is missing".to_owned()))
         })
     }
 
-    
+    /// Reads the value stored in a blob file.
+    fn get_blob(&self, blob: BlobPos) -> Result<String> {
+        let blob_gcs = self.blob_gcs.load(Ordering::SeqCst);
+        if self.seen_blob_gcs.replace(blob_gcs) != blob_gcs {
+            self.blob_readers.borrow_mut().clear();
+        }
+
+        let mut readers = self.blob_readers.borrow_mut();
+        let GenReader { reader, cipher } = match readers.entry(blob.file) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file_path = blob_path(&self.path, blob.file);
+                entry.insert(GenReader::open(&*self.fs, &file_path, &self.keyring)?)
+            }
+        };
+        reader.seek(io::SeekFrom::Start(blob.pos))?;
+        let file = RecordFile::Blob(blob.file);
+        match read_record(&mut reader.take(blob.len), cipher.as_ref(), file, blob.pos)? {
+            Some(Command::Set { value, .. }) => Ok(value),
+            _ => Err(KvsError::Corruption(format!("invalid blob in {}.blob", blob.file))),
+        }
+    }
+
+    /// Makes the indexed fields of a `Set` or `SetBlob` command the ones of the
+    /// declared indexes.
+    ///
+    /// Returns the fields if they had to be extracted from the value because
+    /// the command was written before some of the indexes were declared.
+    fn reindex(&self, cmd: &mut Command, indexes: &Indexes) -> Result<Option<IndexFields>> {
+        let (fields, extracted) = match cmd {
+            Command::Set { value, fields, .. } if !indexes.covers(fields) => {
+                (fields, indexes.extract(value))
+            }
+            Command::SetBlob { blob, fields, .. } if !indexes.covers(fields) => {
+                (fields, indexes.extract(&self.get_blob(*blob)?))
+            }
+            Command::Set { fields, .. } | Command::SetBlob { fields, .. } => {
+                // drop the fields of the indexes which are not declared anymore.
+                fields.retain(|pointer, _| indexes.is_declared(pointer));
+                return Ok(None);
+            }
+            Command::Remove { .. } => return Ok(None),
+        };
+        *fields = extracted.clone();
+        Ok(Some(extracted))
+    }
+
+    /// Reads the key/value pair of a `Set` or `SetBlob` command.
+    fn get_pair(&self, cmd_pos: CommandPos) -> Result<(String, String)> {
+        match self.get(cmd_pos)? {
+            Command::Set { key, value, .. } => Ok((key, value)),
+            Command::SetBlob { key, blob, .. } => Ok((key, self.get_blob(blob)?)),
+            Command::Remove { .. } => Err(KvsError::NotValidType),
+        }
+    }
 }
 
 impl Clone for KvStoreReader {
     fn clone(&self) -> Self {
         KvStoreReader {
+            fs: Arc::clone(&self.fs),
             path: Arc::clone(&self.path),
             safe_point: Arc::clone(&self.safe_point),
+            mutable_from: Arc::clone(&self.mutable_from),
+            mapped: Arc::clone(&self.mapped),
+            keyring: Arc::clone(&self.keyring),
             readers: RefCell::new(BTreeMap::new()),
+            replacing: Arc::clone(&self.replacing),
+            blob_gcs: Arc::clone(&self.blob_gcs),
+            seen_blob_gcs: Cell::new(self.blob_gcs.load(Ordering::SeqCst)),
+            blob_readers: RefCell::new(BTreeMap::new()),
         }
     }
 }
@@ -209,41 +698,195 @@ impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
     /// 
     /// This will create a new directory if the given one does not exist.
+    ///
+    /// The directory is locked exclusively until the store and all its clones
+    /// are dropped. It returns `KvsError::Locked` if another store, in this
+    /// process or in another one, has the directory open.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = Arc::new(path.into());
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
 
-        let mut index = Arc::new(SkipMap::new());
-        let mut readers = BTreeMap::new();
+    /// Open the KvStore at a given path with the given options.
+    ///
+    /// It behaves like `KvStore::open` otherwise.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = path.into();
+        let fs = Arc::clone(&options.fs);
+        if !options.read_only {
+            fs.create_dir_all(&path)?;
+        }
+        let lock = Arc::from(lock_dir(&*fs, &path, options.read_only)?);
+        let path = Arc::new(path);
+        let keyring = Arc::new(options.keyring.clone());
+
+        let index = Arc::new(SkipMap::new());
+        let indexes = Arc::new(Indexes::new(&options.indexes)?);
         let mut uncompacted = 0;
+        // whether some values were written before some of the indexes were declared.
+        let mut unindexed = false;
 
-        let gen_list = sorted_gen_list(&path)?;
+        let gen_list = sorted_gen_list(&*fs, &path, "log")?;
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+            let mut reader = GenReader::open(&*fs, &log_path(&path, gen), &keyring)?;
+            uncompacted += load(gen, &mut reader, &index, &indexes, &mut unindexed)?;
         }
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
+
+        let mut blob_sizes = BTreeMap::new();
+        for file in sorted_gen_list(&*fs, &path, "blob")? {
+            blob_sizes.insert(file, fs.file_len(&blob_path(&path, file))?);
+        }
+        // Whatever the index does not refer to is garbage, including the values
+        // whose records a compaction dropped from the log, and the values
+        // written just before a crash but never referred to.
+        let mut blob_garbage: BTreeMap<u64, u64> = blob_sizes
+            .iter()
+            .map(|(&file, &size)| (file, size.saturating_sub(GEN_HEADER_LEN as u64)))
+            .collect();
+        for entry in index.iter() {
+            if let Some(blob) = entry.value().blob {
+                if let Some(garbage) = blob_garbage.get_mut(&blob.file) {
+                    *garbage = garbage.saturating_sub(blob.len);
+                }
+            }
+        }
+        let next_blob = blob_sizes.keys().next_back().unwrap_or(&0) + 1;
         
         let reader = KvStoreReader {
+            fs: Arc::clone(&fs),
             path: Arc::clone(&path),
             safe_point: Arc::new(AtomicU64::new(0)),
-            readers: RefCell::new(readers),
+            mutable_from: Arc::new(AtomicU64::new(current_gen)),
+            mapped: Arc::new(SkipMap::new()),
+            keyring,
+            // the existing generations are immutable, so they are mapped when read.
+            readers: RefCell::new(BTreeMap::new()),
+            replacing: Arc::new(AtomicU64::new(0)),
+            blob_gcs: Arc::new(AtomicU64::new(0)),
+            seen_blob_gcs: Cell::new(0),
+            blob_readers: RefCell::new(BTreeMap::new()),
         };
-        let writer = KvStoreWriter {
+        if options.read_only {
+            if unindexed {
+                // the log cannot be rewritten, so the fields are only extracted in memory.
+                for entry in index.iter() {
+                    let mut cmd = reader.get(*entry.value())?;
+                    if let Some(fields) = reader.reindex(&mut cmd, &indexes)? {
+                        indexes.insert(entry.key(), &fields);
+                    }
+                }
+            }
+            return Ok(KvStore {
+                reader,
+                writer: None,
+                index,
+                indexes,
+                _lock: lock,
+            });
+        }
+
+        let key_id = options.keyring.active_id();
+        let mut writer = KvStoreWriter {
             path: Arc::clone(&path),
             reader: reader.clone(),
-            writer: BufWriterWithPos::new(File::create(log_path(&path, current_gen))?)?,
+            writer: new_log_file(&*fs, &log_path(&path, current_gen), key_id)?,
             index: Arc::clone(&index),
+            indexes: Arc::clone(&indexes),
             current_gen,
             uncompacted,
+            cipher: options.keyring.cipher(key_id)?,
+            options,
+            blob_writer: None,
+            next_blob,
+            blob_sizes,
+            blob_garbage,
         };
+        if unindexed {
+            writer.compact()?;
+        }
 
         Ok(KvStore {
             reader,
-            writer: Arc::new(Mutex::new(writer)),
+            writer: Some(Arc::new(Mutex::new(writer))),
             index,
+            indexes,
+            _lock: lock,
         })
     }
+
+    /// Open the KvStore at a given path for inspection only.
+    ///
+    /// The directory is locked in shared mode, so any number of read-only stores
+    /// can be opened together, but not along with a writable one. The store sees
+    /// the data as it was when it was opened: it never creates a new generation
+    /// nor compacts, and `set` and `remove` return `KvsError::ReadOnly`.
+    ///
+    /// Use `KvStore::open_with_options` with `read_only` set to open an
+    /// encrypted store read-only.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            read_only: true,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Clears stale entries in the log and rewrites the live ones with the
+    /// options the store was opened with.
+    pub fn compact(&self) -> Result<()> {
+        self.writer()?.lock().unwrap().compact()
+    }
+
+    /// Sets the number of bytes of stale records which triggers a compaction.
+    ///
+    /// It returns `KvsError::ReadOnly` if the store is read-only.
+    pub fn set_compaction_threshold(&self, threshold: u64) -> Result<()> {
+        self.writer()?.lock().unwrap().options.compaction_threshold = threshold;
+        Ok(())
+    }
+
+    /// Sets when the following writes are synced to disk.
+    ///
+    /// It returns `KvsError::ReadOnly` if the store is read-only.
+    pub fn set_sync_policy(&self, sync: SyncPolicy) -> Result<()> {
+        self.writer()?.lock().unwrap().options.sync = sync;
+        Ok(())
+    }
+
+    /// Reads the pair of a key, or returns `None` if it does not exist.
+    ///
+    /// A compaction or a blob collection may move the pair and delete its
+    /// file while it is read, so it is looked up again in that case, as well
+    /// as when it is missing while the index replaces entries.
+    fn read_pair(&self, key: &str) -> Result<Option<(String, String)>> {
+        loop {
+            let replacing = self.reader.replacing.load(Ordering::SeqCst);
+            let blob_gcs = self.reader.blob_gcs.load(Ordering::SeqCst);
+            let cmd_pos = match self.index.get(key) {
+                Some(entry) => *entry.value(),
+                None if self.reader.maybe_replaced(replacing) => {
+                    thread::yield_now();
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            match self.reader.get_pair(cmd_pos) {
+                Err(_) if self.is_stale(cmd_pos, blob_gcs) => continue,
+                res => return res.map(Some),
+            }
+        }
+    }
+
+    /// Returns whether a compaction moved the command out of its generation,
+    /// or blob files were collected since `blob_gcs` was loaded.
+    fn is_stale(&self, cmd_pos: CommandPos, blob_gcs: u64) -> bool {
+        cmd_pos.gen < self.reader.safe_point.load(Ordering::SeqCst)
+            || self.reader.blob_gcs.load(Ordering::SeqCst) != blob_gcs
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        self.writer.as_deref().ok_or(KvsError::ReadOnly)
+    }
 }
 
 
@@ -252,57 +895,88 @@ impl KvsEngine for KvStore {
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+        self.writer()?.lock().unwrap().set(key, value)
     }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { key: _, value } = self.reader.get(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::NotValidType)?
-            }
-        } else {
-            Ok(None)
-        }
+        Ok(self.read_pair(&key)?.map(|(_, value)| value))
     }
 
     /// Remove a given key.
     fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+        self.writer()?.lock().unwrap().remove(key)
+    }
+
+    /// Finds the pairs whose JSON value holds `field` at the pointer of a
+    /// secondary index.
+    fn index_lookup(&self, index: String, field: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for key in self.indexes.lookup(&index, &field, limit)? {
+            pairs.extend(self.read_pair(&key)?);
+        }
+        Ok(pairs)
+    }
+
+    /// Scans key/value pairs in key order starting from `start`.
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for entry in self.index.range(start..) {
+            if pairs.len() == limit {
+                break;
+            }
+            let blob_gcs = self.reader.blob_gcs.load(Ordering::SeqCst);
+            let pair = match self.reader.get_pair(*entry.value()) {
+                Err(_) if self.is_stale(*entry.value(), blob_gcs) => self.read_pair(entry.key())?,
+                res => Some(res?),
+            };
+            pairs.extend(pair);
+        }
+        Ok(pairs)
+    }
+}
+
+/// Lock the `LOCK` file of the directory, in shared mode for read-only stores
+/// and exclusively otherwise.
+///
+/// Returns the lock, which is held until it is dropped.
+fn lock_dir(fs: &dyn Fs, dir: &Path, shared: bool) -> Result<Box<dyn Send + Sync>> {
+    match fs.lock(&dir.join(LOCK_FILE), shared) {
+        Ok(lock) => Ok(lock),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            Err(KvsError::Locked(dir.display().to_string()))
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Create a new log or blob file and write its header.
 ///
-/// Returns the writer to the log.
+/// Returns the writer to the file.
 fn new_log_file(
+    fs: &dyn Fs,
     path: &Path,
-    gen: u64,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
+    key_id: u32,
+) -> Result<BufWriterWithPos<Box<dyn FsWrite>>> {
+    let file = fs.create(path)?;
+    let mut writer = BufWriterWithPos::new(file, fs.file_len(path)?);
+    writer.write_all(GEN_MAGIC)?;
+    writer.write_all(&key_id.to_be_bytes())?;
+    writer.flush()?;
     Ok(writer)
 }
 
-/// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+/// Returns sorted numbers of the files with the given extension, i.e. the
+/// generations for "log" and the blob files for "blob", in the given directory.
+fn sorted_gen_list(fs: &dyn Fs, path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs.read_dir(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some(extension.as_ref()))
         .flat_map(|path| {
-            path.file_name()
+            path.file_stem()
                 .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
                 .map(str::parse::<u64>)
         })
         .flatten()
@@ -316,42 +990,204 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 /// Returns how many bytes can be saved after a compaction.
 fn load(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
+    reader: &mut GenReader,
     index: &SkipMap<String, CommandPos>,
+    indexes: &Indexes,
+    unindexed: &mut bool,
 ) -> Result<u64> {
-    let mut pos = 0;
-
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        let cmd = cmd?;
-        match cmd {
-            Command::Set { key, .. } => {
-                index.insert(key, (gen, pos..new_pos).into());
-                pos = new_pos;
+    let GenReader { reader, cipher } = reader;
+    let mut pos = reader.pos;
+    let mut uncompacted = 0;
+
+    loop {
+        let cmd = match read_record(reader, cipher.as_ref(), RecordFile::Log(gen), pos) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => break,
+            // a crash can leave the last record partially written. It was never
+            // acknowledged, so it is ignored.
+            Err(e) if is_torn(&e) => {
+                warn!("ignoring a torn record at {}:{}", gen, pos);
+                break;
             }
+            Err(e) => return Err(e),
+        };
+        let new_pos = reader.pos;
+        let (key, blob, fields) = match cmd {
+            Command::Set { key, fields, .. } => (key, None, fields),
+            Command::SetBlob { key, blob, fields } => (key, Some(blob), fields),
             Command::Remove { key } => {
-                index.remove(&key);
+                indexes.remove(&key);
+                if let Some(old_cmd) = index.remove(&key) {
+                    uncompacted += old_cmd.value().len;
+                }
+                // the "remove" command itself can be deleted in the next compaction.
+                uncompacted += new_pos - pos;
+                pos = new_pos;
+                continue;
             }
+        };
+        if let Some(old_cmd) = index.get(&key) {
+            uncompacted += old_cmd.value().len;
         }
+        if indexes.covers(&fields) {
+            indexes.insert(&key, &fields);
+        } else {
+            indexes.remove(&key);
+            *unindexed = true;
+        }
+        index.insert(key, CommandPos { blob, ..(gen, pos..new_pos).into() });
+        pos = new_pos;
+    }
+    Ok(uncompacted)
+}
+
+/// Returns whether an error reading a record means the log ends in the
+/// middle of it.
+fn is_torn(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        KvsError::Serde(e) => e.is_eof(),
+        _ => false,
+    }
+}
+
+/// The file holding a record.
+#[derive(Clone, Copy)]
+enum RecordFile {
+    Log(u64),
+    Blob(u64),
+}
+
+impl RecordFile {
+    /// Returns the data authenticated along with an encrypted record at `pos`:
+    /// the first two bytes of its header, the file and the offset, so a record
+    /// cannot be moved elsewhere in the store without being detected.
+    fn aad(self, header: &[u8], pos: u64) -> Vec<u8> {
+        let (kind, number) = match self {
+            RecordFile::Log(gen) => (b'L', gen),
+            RecordFile::Blob(file) => (b'B', file),
+        };
+        let mut aad = header[..2].to_vec();
+        aad.push(kind);
+        aad.extend_from_slice(&number.to_be_bytes());
+        aad.extend_from_slice(&pos.to_be_bytes());
+        aad
+    }
+}
+
+/// Writes a command as one record at the end of `file`, compressed if it is
+/// large enough and encrypted if a cipher is given.
+///
+/// A record is a header made of `RECORD_MAGIC`, the codec id and the payload
+/// length as a big-endian `u32`, followed by the payload. An encrypted payload
+/// is bound to its file and offset, see `RecordFile::aad`.
+///
+/// Returns the length of the record.
+fn write_record<W: Write>(
+    writer: &mut BufWriterWithPos<W>,
+    file: RecordFile,
+    cmd: &Command,
+    options: &KvStoreOptions,
+    cipher: Option<&Cipher>,
+) -> Result<u64> {
+    let json = serde_json::to_vec(cmd)?;
+    let mut codec = if json.len() < options.compression_threshold {
+        Compression::None
+    } else {
+        options.compression
+    };
+    let mut payload = codec.compress(&json)?;
+    if payload.len() >= json.len() {
+        // not worth it, e.g. for random data.
+        codec = Compression::None;
+        payload = json;
+    }
+    let header = [RECORD_MAGIC, codec.id()];
+    if let Some(cipher) = cipher {
+        payload = cipher.seal(&file.aad(&header, writer.pos), &payload)?;
+    }
+
+    writer.write_all(&header)?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(6 + payload.len() as u64)
+}
+
+/// Reads the next record, which starts at `pos` in `file`, decrypting it with
+/// `cipher` if the file is encrypted.
+///
+/// Returns `None` at the end of the log.
+fn read_record<R: BufRead + ?Sized>(
+    reader: &mut R,
+    cipher: Option<&Cipher>,
+    file: RecordFile,
+    pos: u64,
+) -> Result<Option<Command>> {
+    let first = match reader.fill_buf()?.first() {
+        Some(&byte) => byte,
+        None => return Ok(None),
+    };
+    match first {
+        b'{' if cipher.is_some() => Err(KvsError::IntegrityCheckFailed(
+            "plain record in an encrypted generation".to_owned(),
+        )),
+        b'{' => {
+            // `serde_json` reads the reader byte per byte and stops right after
+            // the command, so the reader is left at the next record.
+            let mut de = serde_json::Deserializer::from_reader(reader);
+            Ok(Some(Command::deserialize(&mut de)?))
+        }
+        RECORD_MAGIC => {
+            let mut header = [0; 6];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+            let mut payload = vec![0; len as usize];
+            reader.read_exact(&mut payload)?;
+            if let Some(cipher) = cipher {
+                payload = cipher.open(&file.aad(&header, pos), &payload)?;
+            }
+            let json = decompress(header[1], payload)?;
+            Ok(Some(serde_json::from_slice(&json)?))
+        }
+        byte => Err(KvsError::Corruption(format!("unknown record header {:#x}", byte))),
     }
-    Ok(pos)
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+fn blob_path(dir: &Path, file: u64) -> PathBuf {
+    dir.join(format!("{}.blob", file))
+}
+
 /// Struct representing a command.
+///
+/// Blob files only contain `Set` commands, and `SetBlob` points to one of them.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        fields: IndexFields,
+    },
+    SetBlob {
+        key: String,
+        blob: BlobPos,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        fields: IndexFields,
+    },
     Remove { key: String },
 }
 
 impl Command {
     pub fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+        Command::Set {
+            key,
+            value,
+            fields: IndexFields::new(),
+        }
     }
     pub fn remove(key: String) -> Command {
         Command::Remove { key }
@@ -381,30 +1217,46 @@ impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     }
 }
 
+impl<R: Read + Seek> BufRead for BufReaderWithPos<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
 impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let n = self.reader.seek(pos)?;
-        self.pos += n as u64;
-        Ok(n)
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
     }
 }
 
-struct BufWriterWithPos<W: Write + Seek> {
+struct BufWriterWithPos<W: Write> {
     writer: BufWriter<W>,
     pos: u64,
 }
 
-impl BufWriterWithPos<File> {
-    fn new(mut file: File) -> Result<Self> {
-        let pos = file.seek(io::SeekFrom::Current(0))?;
-        Ok(BufWriterWithPos {
+impl BufWriterWithPos<Box<dyn FsWrite>> {
+    /// Wraps a file opened for appending, whose length is `pos`.
+    fn new(file: Box<dyn FsWrite>, pos: u64) -> Self {
+        BufWriterWithPos {
             writer: BufWriter::new(file),
             pos,
-        })
+        }
+    }
+
+    /// Flushes the buffer and makes the file durable.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().sync()
     }
 }
 
-impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+impl<W: Write> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.writer.write(buf)?;
         self.pos += n as u64;
@@ -422,6 +1274,8 @@ struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // where the value is if the command is a `SetBlob`.
+    blob: Option<BlobPos>,
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -430,6 +1284,15 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            blob: None,
         }
     }
 }
+
+/// Represents the position and length of a value in a blob file.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct BlobPos {
+    file: u64,
+    pos: u64,
+    len: u64,
+}
@@ -1,4 +1,4 @@
-use crate::Result;
+use crate::{KvsError, Result};
 
 /// defines the storage interface called by KvsServer
 pub trait KvsEngine: Clone + Send + 'static {
@@ -18,8 +18,36 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Scans key/value pairs in key order, starting from the first key that
+    /// is greater than or equal to `start`.
+    ///
+    /// At most `limit` pairs are returned.
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>>;
+
+    /// Finds the pairs whose value is a JSON document holding `field` at the
+    /// pointer of the secondary index `index`, in key order.
+    ///
+    /// `field` is parsed as JSON, or taken as a string if it is not valid JSON.
+    /// At most `limit` pairs are returned.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::IndexNotFound` if the engine has no such index.
+    fn index_lookup(&self, index: String, field: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let _ = (field, limit);
+        Err(KvsError::IndexNotFound(index))
+    }
 }
 
+mod fs;
+mod index;
+mod keyring;
 mod kvs;
+mod sled;
 
-pub use self::kvs::KvStore;
\ No newline at end of file
+pub use self::fs::{Fs, FsMap, FsRead, FsWrite, OsFs};
+pub use self::index::IndexSpec;
+pub use self::keyring::{Keyring, KEY_LEN};
+pub use self::kvs::{Compression, KvStore, KvStoreOptions, SyncPolicy};
+pub use self::sled::SledKvsEngine;
\ No newline at end of file
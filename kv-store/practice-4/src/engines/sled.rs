@@ -0,0 +1,52 @@
+use sled::{Db, Tree};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// Wrapper of `sled::Db`
+#[derive(Clone)]
+pub struct SledKvsEngine(Db);
+
+impl SledKvsEngine {
+    /// Creates a `SledKvsEngine` from `sled::Db`.
+    pub fn new(db: Db) -> Self {
+        SledKvsEngine(db)
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let tree: &Tree = &self.0;
+        tree.insert(key, value.into_bytes()).map(|_| ())?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        Ok(tree
+            .get(key)?
+            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let tree: &Tree = &self.0;
+        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn scan(&self, start: String, limit: usize) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        let mut pairs = Vec::new();
+        for item in tree.range(start.into_bytes()..).take(limit) {
+            let (key, value) = item?;
+            pairs.push((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ));
+        }
+        Ok(pairs)
+    }
+}
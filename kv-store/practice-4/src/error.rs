@@ -25,6 +25,59 @@ pub enum KvsError {
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// Data read back from the storage is corrupted.
+    #[fail(display = "corruption: {}", _0)]
+    Corruption(String),
+    /// The server is too busy to serve the request.
+    #[fail(display = "server busy: {}", _0)]
+    Busy(String),
+    /// A condition the request depends on does not hold.
+    #[fail(display = "precondition failed: {}", _0)]
+    PreconditionFailed(String),
+    /// The request is malformed.
+    #[fail(display = "invalid request: {}", _0)]
+    InvalidRequest(String),
+    /// The request exceeds a limit of the server.
+    #[fail(display = "limit exceeded: {}", _0)]
+    LimitExceeded(String),
+    /// The operation did not complete in time.
+    #[fail(display = "timed out: {}", _0)]
+    Timeout(String),
+    /// The directory is already opened by another store.
+    #[fail(display = "{} is locked by another store", _0)]
+    Locked(String),
+    /// The store is opened read-only.
+    #[fail(display = "the store is opened read-only")]
+    ReadOnly,
+    /// An encrypted record failed authentication, it was modified or
+    /// encrypted with another key.
+    #[fail(display = "integrity check failed: {}", _0)]
+    IntegrityCheckFailed(String),
+    /// A generation is encrypted with a key missing from the keyring.
+    #[fail(display = "encryption key {} is not in the keyring", _0)]
+    UnknownKey(u32),
+    /// The engine has no secondary index with this name.
+    #[fail(display = "no index named {}", _0)]
+    IndexNotFound(String),
+}
+
+impl KvsError {
+    /// The exit code of kvs-client and kvs-cluster for this error.
+    ///
+    /// 1 stands for the errors without a code of their own.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KvsError::KeyNotFound => 2,
+            KvsError::Io(_) => 3,
+            KvsError::Corruption(_) => 4,
+            KvsError::Busy(_) => 5,
+            KvsError::PreconditionFailed(_) => 6,
+            KvsError::InvalidRequest(_) => 7,
+            KvsError::LimitExceeded(_) => 8,
+            KvsError::Timeout(_) => 9,
+            _ => 1,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -2,13 +2,19 @@
 //! A simple key/value store.
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine};
+pub use cluster::{key_slot, rebalance, ClusterClient, ClusterConfig, Shard, SLOTS};
+pub use engines::{
+    Compression, Fs, FsMap, FsRead, FsWrite, IndexSpec, Keyring, KvStore, KvStoreOptions, KvsEngine,
+    OsFs, SledKvsEngine, SyncPolicy, KEY_LEN,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use server::{KvsServer, LimitsHandle, Protocol, ServerLimits};
 
 mod client;
+mod cluster;
 mod common;
 mod engines;
 mod error;
+mod resp;
 mod server;
 pub mod thread_pool;
\ No newline at end of file
@@ -0,0 +1,472 @@
+//! A front-end speaking the Redis serialization protocol (RESP), so that
+//! Redis tools and client libraries can access a `KvsServer`.
+//!
+//! Commands are translated into `Request`s and go through the same limits
+//! and engine calls as the JSON protocol.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+};
+
+use log::{debug, info};
+
+use crate::{
+    common::{Request, Response},
+    server::{execute, is_timeout, DeadlineReader, ServerLimits},
+    KvsEngine, KvsError, Result,
+};
+
+// Cursors kept for unfinished `SCAN`s of a single connection.
+const MAX_CURSORS: usize = 1024;
+// Number of keys returned by a `SCAN` without `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+// Most arguments a command may have, as in Redis.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// A reply to a Redis command.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(KvsError),
+    Integer(i64),
+    Bulk(String),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+/// Serves a Redis client until it disconnects.
+pub fn handle_client<E: KvsEngine>(engine: E, tcp: TcpStream, limits: &ServerLimits) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    info!("connected to {} (resp)", peer_addr);
+    let mut reader = BufReader::new(DeadlineReader::new(&tcp));
+    let mut writer = BufWriter::new(&tcp);
+    let mut session = Session {
+        engine,
+        limits,
+        cursors: BTreeMap::new(),
+        next_cursor: 1,
+    };
+    loop {
+        // the whole command must arrive in time, not just each read.
+        reader.get_mut().set_timeout(limits.idle_timeout)?;
+        let args = match read_command(&mut reader, limits.max_frame_size) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(KvsError::Io(ref e)) if is_timeout(e) => {
+                let idle = KvsError::Timeout("connection idle for too long".to_owned());
+                write_error(&mut writer, &idle)?;
+                break;
+            }
+            Err(e @ KvsError::Io(_)) => return Err(e),
+            // the stream cannot be resynchronized after a protocol error.
+            Err(e) => {
+                write_error(&mut writer, &e)?;
+                break;
+            }
+        };
+        // the whole command was read, so an invalid argument only fails it.
+        let args: Vec<String> = match args.into_iter().map(String::from_utf8).collect() {
+            Ok(args) => args,
+            Err(_) => {
+                let invalid = KvsError::InvalidRequest("invalid argument".to_owned());
+                write_error(&mut writer, &invalid)?;
+                continue;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        debug!("Receive command from {}: {:?}", peer_addr, args);
+        let quit = args[0].eq_ignore_ascii_case("QUIT");
+        let reply = if quit {
+            Reply::Simple("OK")
+        } else {
+            session.dispatch(args)
+        };
+        write_reply(&mut writer, &reply)?;
+        writer.flush()?;
+        debug!("Reply sent to {}: {:?}", peer_addr, reply);
+        if quit {
+            break;
+        }
+    }
+    info!("disconnected from {}", peer_addr);
+    Ok(())
+}
+
+/// Writes a single error reply.
+pub fn write_error<W: Write>(writer: &mut W, err: &KvsError) -> Result<()> {
+    writer.write_all(error_line(err).as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+struct Session<'a, E: KvsEngine> {
+    engine: E,
+    limits: &'a ServerLimits,
+    // maps a `SCAN` cursor to the key the scan continues from.
+    cursors: BTreeMap<u64, String>,
+    next_cursor: u64,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    fn dispatch(&mut self, args: Vec<String>) -> Reply {
+        let mut args = args.into_iter();
+        let name = args.next().unwrap_or_default().to_ascii_uppercase();
+        let args: Vec<String> = args.collect();
+        let res = match (name.as_str(), args.len()) {
+            ("PING", 0) => Ok(Reply::Simple("PONG")),
+            ("PING", 1) => Ok(Reply::Bulk(args.into_iter().next().unwrap())),
+            ("GET", 1) => self.get(args.into_iter().next().unwrap()),
+            ("SET", 2) => {
+                let mut args = args.into_iter();
+                self.set(args.next().unwrap(), args.next().unwrap())
+            }
+            ("DEL", n) if n > 0 => self.del(args),
+            ("EXISTS", n) if n > 0 => self.exists(args),
+            ("MGET", n) if n > 0 => args
+                .into_iter()
+                .map(|key| self.get(key))
+                .collect::<Result<Vec<_>>>()
+                .map(Reply::Array),
+            ("MSET", n) if n > 0 && n % 2 == 0 => self.mset(args),
+            ("SCAN", n) if n > 0 => self.scan(args),
+            // sent by redis-cli when it starts, an empty reply is enough.
+            ("COMMAND", _) => Ok(Reply::Array(Vec::new())),
+            ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("MGET", _)
+            | ("MSET", _) | ("SCAN", _) => Err(KvsError::InvalidRequest(format!(
+                "wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))),
+            _ => Err(KvsError::InvalidRequest(format!(
+                "unknown command '{}'",
+                name.to_ascii_lowercase()
+            ))),
+        };
+        res.unwrap_or_else(Reply::Error)
+    }
+
+    fn execute(&self, req: Request) -> Result<Response> {
+        match execute(&self.engine, req, self.limits) {
+            Response::Err(e) => Err(e.into()),
+            resp => Ok(resp),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Reply> {
+        match self.execute(Request::Get { key })? {
+            Response::Get(Some(value)) => Ok(Reply::Bulk(value)),
+            _ => Ok(Reply::Nil),
+        }
+    }
+
+    fn set(&self, key: String, value: String) -> Result<Reply> {
+        self.execute(Request::Set { key, value })?;
+        Ok(Reply::Simple("OK"))
+    }
+
+    fn del(&self, keys: Vec<String>) -> Result<Reply> {
+        let mut removed = 0;
+        for key in keys {
+            match self.execute(Request::Remove { key }) {
+                Ok(_) => removed += 1,
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Reply::Integer(removed))
+    }
+
+    fn exists(&self, keys: Vec<String>) -> Result<Reply> {
+        let mut found = 0;
+        for key in keys {
+            if let Response::Get(Some(_)) = self.execute(Request::Get { key })? {
+                found += 1;
+            }
+        }
+        Ok(Reply::Integer(found))
+    }
+
+    fn mset(&self, args: Vec<String>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        while let (Some(key), Some(value)) = (args.next(), args.next()) {
+            self.execute(Request::Set { key, value })?;
+        }
+        Ok(Reply::Simple("OK"))
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    fn scan(&mut self, args: Vec<String>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        let cursor = args
+            .next()
+            .unwrap()
+            .parse::<u64>()
+            .map_err(|_| KvsError::InvalidRequest("invalid cursor".to_owned()))?;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        while let Some(option) = args.next() {
+            let arg = args
+                .next()
+                .ok_or_else(|| KvsError::InvalidRequest("syntax error".to_owned()))?;
+            match option.to_ascii_uppercase().as_str() {
+                "MATCH" => pattern = Some(arg),
+                "COUNT" => {
+                    count = arg.parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                        KvsError::InvalidRequest("value is not an integer or out of range".to_owned())
+                    })?
+                }
+                _ => return Err(KvsError::InvalidRequest("syntax error".to_owned())),
+            }
+        }
+
+        let start = if cursor == 0 {
+            String::new()
+        } else {
+            self.cursors
+                .remove(&cursor)
+                .ok_or_else(|| KvsError::InvalidRequest("invalid cursor".to_owned()))?
+        };
+        let pairs = match self.execute(Request::Scan { start, limit: count })? {
+            Response::Scan(pairs) => pairs,
+            _ => Vec::new(),
+        };
+
+        let next_cursor = match pairs.last() {
+            Some((last, _)) if pairs.len() == count => {
+                if self.cursors.len() >= MAX_CURSORS {
+                    let oldest = *self.cursors.keys().next().unwrap();
+                    self.cursors.remove(&oldest);
+                }
+                let id = self.next_cursor;
+                self.next_cursor += 1;
+                // the smallest key after `last`.
+                self.cursors.insert(id, format!("{}\0", last));
+                id
+            }
+            _ => 0,
+        };
+        let keys = pairs
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| pattern.as_ref().is_none_or(|p| glob_match(p.as_bytes(), key.as_bytes())))
+            .map(Reply::Bulk)
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(next_cursor.to_string()),
+            Reply::Array(keys),
+        ]))
+    }
+}
+
+/// Reads a command, either sent as an array of bulk strings or inline.
+///
+/// The arguments are not checked to be UTF-8, so that the connection can go
+/// on after a command with an invalid one. Returns `None` if the client
+/// closed the connection.
+fn read_command<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        return Ok(Some(line.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect()));
+    }
+
+    // a null or empty array is an empty command.
+    let count = parse_len(&line[1..], "multibulk length")?.unwrap_or(0);
+    if count > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length".to_owned()));
+    }
+    // the headers and terminators count against the limit too, so that
+    // many empty arguments cannot take unbounded memory.
+    let mut remaining = max_size;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let line = read_line(reader, max_size)?.ok_or_else(unexpected_eof)?;
+        if !line.starts_with('$') {
+            return Err(protocol_error(format!("expected '$', got '{}'", line)));
+        }
+        let len = match parse_len(&line[1..], "bulk length")? {
+            Some(len) => len,
+            None => return Err(protocol_error("invalid bulk length".to_owned())),
+        };
+        let size = line.len() + len + 4;
+        if size > remaining {
+            return Err(KvsError::LimitExceeded(format!(
+                "command exceeds the limit of {} bytes",
+                max_size
+            )));
+        }
+        remaining -= size;
+        let mut buf = vec![0; len + 2];
+        reader.read_exact(&mut buf)?;
+        if !buf.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF".to_owned()));
+        }
+        buf.truncate(len);
+        args.push(buf);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line terminated by CRLF, without the terminator.
+fn read_line<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    (&mut *reader)
+        .take(max_size as u64 + 2)
+        .read_until(b'\n', &mut buf)?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if !buf.ends_with(b"\n") {
+        if buf.len() > max_size {
+            return Err(KvsError::LimitExceeded(format!(
+                "command exceeds the limit of {} bytes",
+                max_size
+            )));
+        }
+        return Err(unexpected_eof());
+    }
+    buf.pop();
+    if buf.ends_with(b"\r") {
+        buf.pop();
+    }
+    Ok(Some(String::from_utf8(buf)?))
+}
+
+/// Parses the length in an array or bulk string header, `None` if it is
+/// negative, i.e. null.
+fn parse_len(s: &str, what: &str) -> Result<Option<usize>> {
+    match s.parse::<i64>() {
+        Ok(n) if n < 0 => Ok(None),
+        Ok(n) => Ok(Some(n as usize)),
+        Err(_) => Err(protocol_error(format!("invalid {}", what))),
+    }
+}
+
+fn protocol_error(msg: String) -> KvsError {
+    KvsError::InvalidRequest(format!("Protocol error: {}", msg))
+}
+
+fn unexpected_eof() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+        Reply::Error(e) => writer.write_all(error_line(e).as_bytes())?,
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+        Reply::Bulk(s) => {
+            write!(writer, "${}\r\n", s.len())?;
+            writer.write_all(s.as_bytes())?;
+            writer.write_all(b"\r\n")?;
+        }
+        Reply::Nil => writer.write_all(b"$-1\r\n")?,
+        Reply::Array(replies) => {
+            write!(writer, "*{}\r\n", replies.len())?;
+            for reply in replies {
+                write_reply(writer, reply)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Formats an error reply. The first word is the error kind, as Redis does.
+fn error_line(err: &KvsError) -> String {
+    let kind = match err {
+        KvsError::Busy(_) => "BUSY",
+        KvsError::Timeout(_) => "TIMEOUT",
+        _ => "ERR",
+    };
+    let msg = match err {
+        KvsError::Busy(msg)
+        | KvsError::Timeout(msg)
+        | KvsError::InvalidRequest(msg)
+        | KvsError::LimitExceeded(msg) => msg.clone(),
+        e => e.to_string(),
+    };
+    // a simple string cannot contain line breaks.
+    format!("-{} {}\r\n", kind, msg.replace(['\r', '\n'], " "))
+}
+
+/// Matches `s` against a glob-style pattern supporting `*`, `?`, `[...]`
+/// and `\` escapes, like the `MATCH` option of Redis.
+///
+/// Only the last `*` is backtracked to, so it takes at most
+/// O(len(pattern) * len(s)) steps however many stars the pattern has.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where to resume after the last `*`: the pattern after it, and the
+    // start of the bytes it matches so far.
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, s[i]) {
+            p = next;
+            i += 1;
+            continue;
+        }
+        match star {
+            // let the `*` match one more byte.
+            Some((after_star, start)) => {
+                p = after_star;
+                i = start + 1;
+                star = Some((after_star, start + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the element of the pattern at `p`, which is not `*`,
+/// and returns where the next element starts.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    let (matched, next) = match *pattern.get(p)? {
+        b'?' => (true, p + 1),
+        b'[' => match pattern[p + 1..].iter().position(|&c| c == b']') {
+            Some(end) => {
+                let class = &pattern[p + 1..p + 1 + end];
+                (class_match(class, c), p + end + 2)
+            }
+            // an unclosed bracket is a literal.
+            None => (c == b'[', p + 1),
+        },
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c, p + 2),
+        expected => (expected == c, p + 1),
+    };
+    if matched {
+        Some(next)
+    } else {
+        None
+    }
+}
+
+/// Matches `c` against the inside of a `[...]` class.
+fn class_match(class: &[u8], c: u8) -> bool {
+    let (negate, class) = match class.split_first() {
+        Some((b'^', class)) => (true, class),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            matched |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    matched != negate
+}
@@ -5,83 +5,386 @@
  * @LastEditTime: 2022-09-06 16:07:06
  * @@Email: ihusharp@gmail.com
  */
-use std::{net::{ToSocketAddrs, TcpListener, TcpStream}, io::{BufReader, BufWriter, Write}};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{KvsEngine, Result, common::{Request, GetResponse, SetResponse}, thread_pool::ThreadPool};
-use log::{error, info, debug};
-use serde_json::Deserializer;
+use crate::{
+    common::{read_frame, write_frame, Frame, Request, Response},
+    resp,
+    thread_pool::ThreadPool,
+    KvsEngine, KvsError, Result,
+};
+use log::{debug, error, info, warn};
+
+/// Limits protecting a `KvsServer` from misbehaving clients.
+///
+/// A request breaking a limit is answered with an error response instead of
+/// being executed.
+#[derive(Debug, Clone)]
+pub struct ServerLimits {
+    /// Maximum size of a key in bytes.
+    pub max_key_size: usize,
+    /// Maximum size of a value in bytes.
+    pub max_value_size: usize,
+    /// Maximum size of a request frame in bytes.
+    pub max_frame_size: usize,
+    /// Maximum number of pairs a scan may ask for.
+    pub max_scan_limit: usize,
+    /// Maximum number of connections served at the same time.
+    pub max_connections: usize,
+    /// Maximum number of requests of a connection received but not answered yet.
+    pub max_in_flight: usize,
+    /// Close connections that take longer than this to send a request, counting
+    /// from the previous response. `None` never closes them.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            max_key_size: 64 * 1024,
+            max_value_size: 8 * 1024 * 1024,
+            max_frame_size: 16 * 1024 * 1024,
+            max_scan_limit: 10_000,
+            max_connections: 1024,
+            max_in_flight: 32,
+            idle_timeout: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// A handle changing the limits of a running `KvsServer`.
+///
+/// New limits apply to the connections accepted afterwards. The connections
+/// being served keep the limits they started with.
+#[derive(Debug, Clone)]
+pub struct LimitsHandle(Arc<RwLock<Arc<ServerLimits>>>);
+
+impl LimitsHandle {
+    fn new(limits: ServerLimits) -> Self {
+        LimitsHandle(Arc::new(RwLock::new(Arc::new(limits))))
+    }
+
+    /// Returns the current limits.
+    pub fn get(&self) -> Arc<ServerLimits> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    /// Replaces the limits.
+    pub fn set(&self, limits: ServerLimits) {
+        *self.0.write().unwrap() = Arc::new(limits);
+    }
+}
+
+/// The wire protocol spoken by a `KvsServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Length-delimited JSON frames, spoken by `KvsClient`.
+    Json,
+    /// The Redis serialization protocol, spoken by Redis clients.
+    Resp,
+}
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    protocol: Protocol,
+    limits: LimitsHandle,
+    // number of connections being served.
+    connections: Arc<AtomicUsize>,
 }
 
 /// The server of a key value store.
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     /// Creates a new `KvsServer` with the given engine.
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer::with_limits(engine, pool, ServerLimits::default())
+    }
+
+    /// Creates a new `KvsServer` with the given engine and limits.
+    pub fn with_limits(engine: E, pool: P, limits: ServerLimits) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            protocol: Protocol::Json,
+            limits: LimitsHandle::new(limits),
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sets the protocol spoken with clients, `Protocol::Json` by default.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Returns a handle to change the limits while the server runs.
+    pub fn limits_handle(&self) -> LimitsHandle {
+        self.limits.clone()
     }
 
     /// Run the server listening on the given address
     pub fn run(&mut self, addr: impl ToSocketAddrs) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
         for stream in listener.incoming() {
-            let engine = self.engine.clone();            
-            self.pool.spawn(move || match stream {
-               Ok(stream) => {
-                    if let Err(e) = handle_client(engine, stream) {
-                        error!("Error handling client: {}", e);
-                    }
-               } 
-               Err(e) => error!("failed to accept connection: {}", e),
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let limits = self.limits.get();
+            if self.connections.fetch_add(1, Ordering::SeqCst) >= limits.max_connections {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+                reject(stream, self.protocol, limits.max_connections);
+                continue;
+            }
+
+            let guard = ConnectionGuard(Arc::clone(&self.connections));
+            let engine = self.engine.clone();
+            let protocol = self.protocol;
+            self.pool.spawn(move || {
+                let _guard = guard;
+                let res = match protocol {
+                    Protocol::Json => handle_client(engine, stream, &limits),
+                    Protocol::Resp => resp::handle_client(engine, stream, &limits),
+                };
+                if let Err(e) = res {
+                    error!("Error handling client: {}", e);
+                }
             });
         }
         Ok(())
     }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+/// Releases the slot of a connection when it is dropped, even if the
+/// serving job panics.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a client that the server is full and closes its connection.
+fn reject(tcp: TcpStream, protocol: Protocol, max_connections: usize) {
+    if let Ok(peer_addr) = tcp.peer_addr() {
+        warn!("rejected {}: already serving {} connections", peer_addr, max_connections);
+    }
+    let err = KvsError::Busy(format!(
+        "too many connections, the server serves at most {}",
+        max_connections
+    ));
+    let _ = tcp.set_write_timeout(Some(Duration::from_millis(100)));
+    let sent = match protocol {
+        Protocol::Json => write_frame(&mut &tcp, &Response::Err(err.into())),
+        Protocol::Resp => resp::write_error(&mut &tcp, &err),
+    };
+    if sent.is_ok() && tcp.shutdown(Shutdown::Write).is_ok() {
+        // Drain what the client has sent so far. Closing a socket with unread
+        // data resets the connection, which could discard the response.
+        let _ = tcp.set_read_timeout(Some(Duration::from_millis(20)));
+        let _ = io::copy(&mut (&tcp).take(64 * 1024), &mut io::sink());
+    }
+}
+
+/// A request read from a connection.
+enum Incoming {
+    /// A request to execute.
+    Request(Request),
+    /// A request answered with an error without being executed.
+    Rejected(KvsError),
+    /// Answer with an error and close the connection.
+    Close(KvsError),
+}
+
+/// Reads from a connection, failing with `TimedOut` once the deadline passed
+/// however the bytes trickle in.
+pub(crate) struct DeadlineReader<'a> {
+    tcp: &'a TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineReader<'a> {
+    pub(crate) fn new(tcp: &'a TcpStream) -> Self {
+        DeadlineReader { tcp, deadline: None }
+    }
+
+    /// Gives the reads from now on `timeout` to complete, `None` for no limit.
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        if self.deadline.is_none() {
+            self.tcp.set_read_timeout(None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.tcp.set_read_timeout(Some(deadline - now))?;
+        }
+        (&mut &*self.tcp).read(buf)
+    }
+}
+
+/// Tells whether a read failed because the client took too long.
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn handle_client<E: KvsEngine>(engine: E, tcp: TcpStream, limits: &ServerLimits) -> Result<()> {
     let peer_addr = tcp.peer_addr()?;
     info!("connected to {}", peer_addr);
-    let reader = BufReader::new(&tcp);
+    let mut reader = BufReader::new(DeadlineReader::new(&tcp));
     let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-
-    for req in req_reader {
-        let req = req?;
-        match req {
-            Request::Get { key } => {
-                let resp = match engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value) ,
-                    Err(e) =>  GetResponse::Err(e.to_string()) ,
-                };
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Response sent to {}: {:?}", peer_addr, resp);
-            }
-            Request::Set { key, value } => {
-                let resp = match engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()) ,
-                    Err(e) => { SetResponse::Err(e.to_string()) },
-                };
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Response sent to {}: {:?}", peer_addr, resp);
+    let max_in_flight = limits.max_in_flight.max(1);
+    'serve: loop {
+        // The requests a client pipelined are in flight until they are
+        // answered. Once there are `max_in_flight`, the next one is rejected,
+        // and the connection is not read until they are answered.
+        reader.get_mut().set_timeout(limits.idle_timeout)?;
+        let mut batch = vec![read_request(&mut reader, limits.max_frame_size)];
+        while !reader.buffer().is_empty()
+            && matches!(
+                batch.last(),
+                Some(Some(Incoming::Request(_))) | Some(Some(Incoming::Rejected(_)))
+            )
+        {
+            let incoming = read_request(&mut reader, limits.max_frame_size);
+            if batch.len() < max_in_flight {
+                batch.push(incoming);
+                continue;
             }
-            Request::Remove { key } => {
-                let resp = match engine.remove(key) {
-                    Ok(_) => SetResponse::Ok(()) ,
-                    Err(e) => { SetResponse::Err(e.to_string()) },
-                };
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Response sent to {}: {:?}", peer_addr, resp);
+            let busy = match incoming {
+                Some(Incoming::Request(_)) | Some(Incoming::Rejected(_)) => {
+                    Some(Incoming::Rejected(KvsError::Busy(format!(
+                        "too many requests in flight, at most {} are allowed",
+                        max_in_flight
+                    ))))
+                }
+                other => other,
+            };
+            batch.push(busy);
+            break;
+        }
+
+        for incoming in batch {
+            let (resp, close) = match incoming {
+                Some(Incoming::Request(req)) => {
+                    debug!("Receive request from {}: {:?}", peer_addr, req);
+                    (execute(&engine, req, limits), false)
+                }
+                Some(Incoming::Rejected(e)) => (Response::Err(e.into()), false),
+                Some(Incoming::Close(e)) => (Response::Err(e.into()), true),
+                None => break 'serve,
+            };
+            write_frame(&mut writer, &resp)?;
+            debug!("Response sent to {}: {:?}", peer_addr, resp);
+            if close {
+                break 'serve;
             }
         }
     }
-
+    info!("disconnected from {}", peer_addr);
     Ok(())
 }
 
+/// Reads a request, `None` if the connection is to be closed without an
+/// answer.
+fn read_request<R: BufRead>(reader: &mut R, max_frame_size: usize) -> Option<Incoming> {
+    let incoming = match read_frame(reader, max_frame_size) {
+        Ok(Frame::Eof) => return None,
+        Ok(Frame::TooLarge(len)) => Incoming::Rejected(KvsError::LimitExceeded(format!(
+            "request of {} bytes exceeds the limit of {} bytes",
+            len, max_frame_size
+        ))),
+        Ok(Frame::Data(payload)) => match serde_json::from_slice(&payload) {
+            Ok(req) => Incoming::Request(req),
+            Err(e) => Incoming::Rejected(KvsError::InvalidRequest(e.to_string())),
+        },
+        Err(KvsError::Io(ref e)) if is_timeout(e) => {
+            Incoming::Close(KvsError::Timeout("connection idle for too long".to_owned()))
+        }
+        Err(e) => {
+            debug!("stop reading requests: {}", e);
+            return None;
+        }
+    };
+    Some(incoming)
+}
+
+/// Checks a request against the limits and executes it on the engine.
+pub(crate) fn execute<E: KvsEngine>(engine: &E, req: Request, limits: &ServerLimits) -> Response {
+    if let Err(e) = check_limits(&req, limits) {
+        return Response::Err(e.into());
+    }
+    let resp = match req {
+        Request::Get { key } => engine.get(key).map(Response::Get),
+        Request::Set { key, value } => engine.set(key, value).map(|_| Response::Set),
+        Request::Remove { key } => engine.remove(key).map(|_| Response::Remove),
+        Request::Scan { start, limit } => engine.scan(start, limit).map(Response::Scan),
+        Request::IndexLookup {
+            index,
+            field,
+            limit,
+        } => engine
+            .index_lookup(index, field, limit)
+            .map(Response::IndexLookup),
+    };
+    resp.unwrap_or_else(|e| Response::Err(e.into()))
+}
+
+fn check_limits(req: &Request, limits: &ServerLimits) -> Result<()> {
+    let key = match req {
+        Request::Get { key } | Request::Set { key, .. } | Request::Remove { key } => key,
+        Request::Scan { start, .. } => start,
+        // the field is compared with parts of values, but it is as small as a key.
+        Request::IndexLookup { field, .. } => field,
+    };
+    if key.len() > limits.max_key_size {
+        return Err(KvsError::LimitExceeded(format!(
+            "key of {} bytes exceeds the limit of {} bytes",
+            key.len(),
+            limits.max_key_size
+        )));
+    }
+    match req {
+        Request::Set { value, .. } if value.len() > limits.max_value_size => {
+            Err(KvsError::LimitExceeded(format!(
+                "value of {} bytes exceeds the limit of {} bytes",
+                value.len(),
+                limits.max_value_size
+            )))
+        }
+        Request::Scan { limit, .. } if *limit > limits.max_scan_limit => {
+            Err(KvsError::LimitExceeded(format!(
+                "scan of {} pairs exceeds the limit of {} pairs",
+                limit, limits.max_scan_limit
+            )))
+        }
+        Request::IndexLookup { limit, .. } if *limit > limits.max_scan_limit => {
+            Err(KvsError::LimitExceeded(format!(
+                "lookup of {} pairs exceeds the limit of {} pairs",
+                limit, limits.max_scan_limit
+            )))
+        }
+        _ => Ok(()),
+    }
+}
@@ -273,6 +273,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .current_dir(&temp_dir)
         .assert()
         .failure()
+        .code(2)
         .stderr(contains("Key not found"));
 
     Command::cargo_bin("kvs-client")
@@ -335,3 +336,287 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-client shell` should run a script from stdin over one connection.
+#[test]
+fn cli_shell_script() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(
+            "# a comment\n\
+             set key1 value1\n\
+             \n\
+             set key2 '{\"a\": [1, 2]}'\n\
+             get key1\n\
+             get key2\n\
+             rm key1\n\
+             get key1\n\
+             scan\n",
+        )
+        .assert()
+        .success()
+        .stdout(contains("value1\n"))
+        .stdout(contains("{\n  \"a\": [\n    1,\n    2\n  ]\n}\n"))
+        .stdout(contains("Key not found"))
+        .stdout(contains("key2 => {"))
+        .stderr(contains(" ms)"));
+
+    // a script stops at the first error and exits with its code.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("rm key1\nset key3 value3\n")
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client find` should look values up by a secondary index of the server
+#[test]
+fn cli_find() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--index", "by_city=/city"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[
+        ("alice", r#"{"city":"Paris"}"#),
+        ("bob", r#"{"city":"Lyon"}"#),
+        ("carol", r#"{"city":"Paris"}"#),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["find", "by_city", "Paris", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("alice => {\"city\":\"Paris\"}\ncarol => {\"city\":\"Paris\"}\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["find", "by_name", "alice", "--addr", addr])
+        .assert()
+        .failure()
+        .code(7);
+
+    child.kill().expect("server exited before killed");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--index", "by_city"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-cluster` should route keys to several servers and move them to a new one
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4009", "127.0.0.1:4010"];
+    let temp_dir = TempDir::new().unwrap();
+    let mut children: Vec<_> = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            let dir = temp_dir.path().join(i.to_string());
+            fs::create_dir(&dir).unwrap();
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--engine", "kvs", "--addr", addr])
+                .current_dir(&dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let one = temp_dir.path().join("one.toml");
+    let two = temp_dir.path().join("two.toml");
+    fs::write(
+        &one,
+        format!("[[shards]]\nstart = 0\nend = 16384\naddr = \"{}\"\n", addrs[0]),
+    )
+    .unwrap();
+    fs::write(
+        &two,
+        format!(
+            "[[shards]]\nstart = 0\nend = 8192\naddr = \"{}\"\n\
+             [[shards]]\nstart = 8192\nend = 16384\naddr = \"{}\"\n",
+            addrs[0], addrs[1]
+        ),
+    )
+    .unwrap();
+
+    for i in 0..10 {
+        Command::cargo_bin("kvs-cluster")
+            .unwrap()
+            .args(&["set", &format!("key{}", i), &format!("value{}", i), "--config"])
+            .arg(&one)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-cluster")
+        .unwrap()
+        .args(&["rebalance", "--batch", "3", "--from"])
+        .arg(&one)
+        .arg("--to")
+        .arg(&two)
+        .assert()
+        .success()
+        .stdout(contains("moved"));
+
+    Command::cargo_bin("kvs-cluster")
+        .unwrap()
+        .args(&["get", "key7", "--config"])
+        .arg(&two)
+        .assert()
+        .success()
+        .stdout("value7\n");
+    Command::cargo_bin("kvs-cluster")
+        .unwrap()
+        .args(&["scan", "key", "--limit", "3", "--config"])
+        .arg(&two)
+        .assert()
+        .success()
+        .stdout("key0 => value0\nkey1 => value1\nkey2 => value2\n");
+    // both servers hold keys now
+    for addr in &addrs {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["shell", "--addr", addr])
+            .with_stdin()
+            .buffer("scan\n")
+            .assert()
+            .success()
+            .stdout(contains("=> value"));
+    }
+
+    for child in &mut children {
+        child.kill().expect("server exited before killed");
+    }
+
+    // an invalid layout is rejected
+    fs::write(&one, "[[shards]]\nstart = 0\nend = 100\naddr = \"127.0.0.1:4009\"\n").unwrap();
+    Command::cargo_bin("kvs-cluster")
+        .unwrap()
+        .args(&["get", "key1", "--config"])
+        .arg(&one)
+        .assert()
+        .failure()
+        .stderr(contains("not owned"));
+}
+
+// `kvs-server` should read its settings from `--config` and reload the limits on SIGHUP.
+#[test]
+fn cli_config_file() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    let config = |max_value_size: usize| {
+        format!(
+            "addr = \"{}\"\n\
+             engine = \"kvs\"\n\
+             thread_pool = \"shared_queue\"\n\
+             threads = 4\n\
+             \n\
+             [storage]\n\
+             sync = \"always\"\n\
+             \n\
+             [limits]\n\
+             max_value_size = {}\n",
+            addr, max_value_size
+        )
+    };
+    fs::write(&config_path, config(8)).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .arg("--config")
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "a long value", "--addr", addr])
+        .assert()
+        .failure()
+        .code(8);
+
+    fs::write(&config_path, config(64)).unwrap();
+    Command::new("kill")
+        .args(&["-HUP", &child.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "a long value", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("a long value\n");
+
+    child.kill().expect("server exited before killed");
+
+    // an invalid file is rejected at startup.
+    fs::write(&config_path, "engine = \"kvs\"\nunknown = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid config file"));
+}
@@ -0,0 +1,177 @@
+use kvs::thread_pool::*;
+use kvs::{
+    key_slot, rebalance, ClusterClient, ClusterConfig, IndexSpec, KvStore, KvStoreOptions,
+    KvsClient, KvsServer, Result, Shard, SLOTS,
+};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start a server backed by a `KvStore` indexing `/city` in a temporary directory.
+fn start_server(addr: SocketAddr) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        indexes: vec![IndexSpec::new("by_city", "/city")],
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    thread::spawn(move || {
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        KvsServer::new(store, pool).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+    ports
+        .iter()
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect()
+}
+
+// Keys held by the server at `addr`, whoever owns them.
+fn node_keys(addr: SocketAddr) -> Vec<String> {
+    let mut client = KvsClient::connect(addr).unwrap();
+    let pairs = client.scan(String::new(), 10000).unwrap();
+    pairs.into_iter().map(|(key, _)| key).collect()
+}
+
+#[test]
+fn cluster_config() {
+    let nodes = addrs(&[4301, 4302]);
+    let shard = |start, end, addr| Shard { start, end, addr };
+
+    let config = ClusterConfig::even(&nodes).unwrap();
+    assert_eq!(
+        config.shards(),
+        [shard(0, SLOTS / 2, nodes[0]), shard(SLOTS / 2, SLOTS, nodes[1])]
+    );
+    assert_eq!(config.nodes(), nodes);
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        let expected = if key_slot(&key) < SLOTS / 2 { nodes[0] } else { nodes[1] };
+        assert_eq!(config.owner(&key), expected);
+    }
+
+    // gaps and overlaps are rejected
+    assert!(ClusterConfig::new(vec![shard(0, 100, nodes[0]), shard(101, SLOTS, nodes[1])]).is_err());
+    assert!(ClusterConfig::new(vec![shard(0, 100, nodes[0]), shard(50, SLOTS, nodes[1])]).is_err());
+    assert!(ClusterConfig::new(vec![shard(0, 100, nodes[0])]).is_err());
+    // a server can own several ranges
+    let config = ClusterConfig::new(vec![
+        shard(100, 200, nodes[1]),
+        shard(0, 100, nodes[0]),
+        shard(200, SLOTS, nodes[0]),
+    ])
+    .unwrap();
+    assert_eq!(config.nodes(), nodes);
+
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("cluster.toml");
+    std::fs::write(
+        &path,
+        "[[shards]]\nstart = 0\nend = 16384\naddr = \"127.0.0.1:4301\"\n",
+    )
+    .unwrap();
+    assert_eq!(ClusterConfig::load(&path).unwrap().nodes(), &nodes[..1]);
+}
+
+#[test]
+fn route_and_rebalance() -> Result<()> {
+    let nodes = addrs(&[4303, 4304, 4305]);
+    let _dirs: Vec<_> = nodes.iter().map(|&addr| start_server(addr)).collect();
+
+    let before = ClusterConfig::even(&nodes[..2])?;
+    let mut client = ClusterClient::new(before.clone());
+    for i in 0..100 {
+        let city = if i % 2 == 0 { "Paris" } else { "Lyon" };
+        client.set(format!("key{:02}", i), format!(r#"{{"city":"{}"}}"#, city))?;
+    }
+    client.remove("key99".to_owned())?;
+
+    // each key is only on its owner
+    for &node in &nodes[..2] {
+        let keys = node_keys(node);
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|key| before.owner(key) == node));
+    }
+    assert!(node_keys(nodes[2]).is_empty());
+    assert_eq!(client.get("key42".to_owned())?, Some(r#"{"city":"Paris"}"#.to_owned()));
+    assert_eq!(client.get("key99".to_owned())?, None);
+
+    // scans and lookups are merged across the servers
+    let keys: Vec<_> = client.scan("key10".to_owned(), 5)?.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, ["key10", "key11", "key12", "key13", "key14"]);
+    assert_eq!(client.scan(String::new(), 1000)?.len(), 99);
+    let found = client.index_lookup("by_city".to_owned(), "Lyon".to_owned(), 1000)?;
+    assert_eq!(found.len(), 49);
+    assert_eq!(found[0].0, "key01");
+
+    // adding a server moves about a third of the keys to it
+    let after = ClusterConfig::even(&nodes)?;
+    let moved = rebalance(&before, &after, 7)?;
+    let expected = (0..99)
+        .filter(|i| {
+            let key = format!("key{:02}", i);
+            before.owner(&key) != after.owner(&key)
+        })
+        .count();
+    assert_eq!(moved, expected);
+    for &node in &nodes {
+        assert!(node_keys(node).iter().all(|key| after.owner(key) == node));
+    }
+    assert!(!node_keys(nodes[2]).is_empty());
+
+    let mut client = ClusterClient::new(after.clone());
+    assert_eq!(client.scan(String::new(), 1000)?.len(), 99);
+    for i in 0..99 {
+        assert!(client.get(format!("key{:02}", i))?.is_some());
+    }
+    // running it again moves nothing
+    assert_eq!(rebalance(&after, &after, 7)?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn multi_key_requests() -> Result<()> {
+    let nodes = addrs(&[4306, 4307]);
+    let _dirs: Vec<_> = nodes.iter().map(|&addr| start_server(addr)).collect();
+
+    let config = ClusterConfig::even(&nodes)?;
+    let mut client = ClusterClient::new(config.clone());
+    // enough keys that each server gets several pipelined chunks
+    let pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs.clone())?;
+
+    // each key is only on its owner
+    for &node in &nodes {
+        let keys = node_keys(node);
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|key| config.owner(key) == node));
+    }
+
+    // the values come back in the order of the keys, whichever server has them
+    let keys = vec!["key07", "missing", "key42", "key07", "key99"];
+    let values = client.get_many(keys.into_iter().map(String::from).collect())?;
+    assert_eq!(
+        values,
+        [
+            Some("value7".to_owned()),
+            None,
+            Some("value42".to_owned()),
+            Some("value7".to_owned()),
+            Some("value99".to_owned()),
+        ]
+    );
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values: Vec<_> = pairs.into_iter().map(|(_, value)| Some(value)).collect();
+    assert_eq!(client.get_many(keys)?, values);
+    assert!(client.get_many(vec![])?.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+use kvs::{Fs, FsRead, FsWrite, KvStore, KvStoreOptions, KvsEngine, Result};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// An in-memory filesystem which crashes after a given number of operations.
+///
+/// Writes are only durable once synced. When the filesystem crashes, the write
+/// in progress is cut short and every operation fails until `recover` is
+/// called, which keeps a random prefix of the unsynced data of each file.
+/// Creating and removing files is durable at once.
+#[derive(Debug, Clone)]
+struct FaultFs(Arc<Mutex<FsState>>);
+
+#[derive(Debug)]
+struct FsState {
+    files: BTreeMap<PathBuf, Arc<Mutex<FileData>>>,
+    // operations left before the crash, `None` to never crash.
+    ops_left: Option<u64>,
+    crashed: bool,
+    rng: SmallRng,
+}
+
+#[derive(Debug, Default)]
+struct FileData {
+    data: Vec<u8>,
+    synced: usize,
+}
+
+impl FaultFs {
+    fn new(seed: u64) -> FaultFs {
+        FaultFs(Arc::new(Mutex::new(FsState {
+            files: BTreeMap::new(),
+            ops_left: None,
+            crashed: false,
+            rng: SmallRng::seed_from_u64(seed),
+        })))
+    }
+
+    fn crash_after(&self, ops: u64) {
+        self.0.lock().unwrap().ops_left = Some(ops);
+    }
+
+    /// Simulates a power loss followed by a restart.
+    fn recover(&self) {
+        let mut state = self.0.lock().unwrap();
+        let FsState { files, rng, .. } = &mut *state;
+        for file in files.values() {
+            let mut file = file.lock().unwrap();
+            let len = rng.gen_range(file.synced, file.data.len() + 1);
+            file.data.truncate(len);
+            file.synced = len;
+        }
+        state.ops_left = None;
+        state.crashed = false;
+    }
+}
+
+impl FsState {
+    /// Accounts for a mutating operation. It returns whether this operation is
+    /// the one interrupted by the crash.
+    fn tick(&mut self) -> io::Result<bool> {
+        if self.crashed {
+            return Err(crashed());
+        }
+        match &mut self.ops_left {
+            Some(0) => {
+                self.crashed = true;
+                Ok(true)
+            }
+            Some(ops) => {
+                *ops -= 1;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.crashed {
+            Err(crashed())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("the filesystem crashed")
+}
+
+impl Fs for FaultFs {
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        self.0.lock().unwrap().check()
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.0.lock().unwrap();
+        state.check()?;
+        Ok(state
+            .files
+            .keys()
+            .filter(|file| file.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FsRead>> {
+        let state = self.0.lock().unwrap();
+        state.check()?;
+        match state.files.get(path) {
+            Some(file) => Ok(Box::new(MemReader {
+                fs: self.clone(),
+                file: Arc::clone(file),
+                pos: 0,
+            })),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FsWrite>> {
+        let mut state = self.0.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let file = state.files.entry(path.to_owned()).or_default();
+        Ok(Box::new(MemWriter {
+            fs: self.clone(),
+            file: Arc::clone(file),
+        }))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.0.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        match state.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        let state = self.0.lock().unwrap();
+        state.check()?;
+        match state.files.get(path) {
+            Some(file) => Ok(file.lock().unwrap().data.len() as u64),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn lock(&self, _path: &Path, _shared: bool) -> io::Result<Box<dyn Send + Sync>> {
+        self.0.lock().unwrap().check()?;
+        Ok(Box::new(()))
+    }
+}
+
+struct MemReader {
+    fs: FaultFs,
+    file: Arc<Mutex<FileData>>,
+    pos: u64,
+}
+
+impl Read for MemReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fs.0.lock().unwrap().check()?;
+        let file = self.file.lock().unwrap();
+        let start = (self.pos as usize).min(file.data.len());
+        let n = buf.len().min(file.data.len() - start);
+        buf[..n].copy_from_slice(&file.data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for MemReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.lock().unwrap().data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+struct MemWriter {
+    fs: FaultFs,
+    file: Arc<Mutex<FileData>>,
+}
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.fs.0.lock().unwrap();
+        let mut file = self.file.lock().unwrap();
+        if state.tick()? {
+            // a short write, interrupted by the crash
+            let n = state.rng.gen_range(0, buf.len() + 1);
+            file.data.extend_from_slice(&buf[..n]);
+            return Err(crashed());
+        }
+        file.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FsWrite for MemWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.fs.0.lock().unwrap();
+        if state.tick()? {
+            return Err(crashed());
+        }
+        let mut file = self.file.lock().unwrap();
+        file.synced = file.data.len();
+        Ok(())
+    }
+}
+
+type Model = BTreeMap<String, String>;
+
+fn options(fs: &FaultFs) -> KvStoreOptions {
+    KvStoreOptions {
+        fs: Arc::new(fs.clone()),
+        blob_threshold: Some(64),
+        blob_file_size: 1024,
+        ..KvStoreOptions::default()
+    }
+}
+
+fn dump(store: &KvStore) -> Result<Model> {
+    Ok(store.scan(String::new(), usize::MAX)?.into_iter().collect())
+}
+
+/// Runs random operations until the filesystem crashes.
+///
+/// Returns the states the store can be recovered to: the states after each
+/// operation, from the last one known to be durable, including the one of the
+/// operation interrupted by the crash.
+fn run_workload(fs: &FaultFs, rng: &mut SmallRng, mut model: Model) -> Vec<Model> {
+    let mut states = vec![model.clone()];
+    let store = match KvStore::open_with_options("/kvs", options(fs)) {
+        Ok(store) => store,
+        Err(_) => return states,
+    };
+    for _ in 0..200 {
+        let key = format!("key{}", rng.gen_range(0, 8));
+        let res = match rng.gen_range(0, 20) {
+            0 => store.compact().map(|_| {
+                // a compaction makes everything before it durable
+                states.clear();
+            }),
+            1..=4 => {
+                let res = store.get(key.clone());
+                if let Ok(value) = &res {
+                    assert_eq!(value.as_ref(), model.get(&key));
+                }
+                res.map(|_| ())
+            }
+            5..=8 if model.contains_key(&key) => {
+                model.remove(&key);
+                store.remove(key)
+            }
+            _ => {
+                let len = if rng.gen_bool(0.3) { 100 } else { 10 };
+                let value: String = (0..len).map(|_| rng.gen_range(b'a', b'z') as char).collect();
+                model.insert(key.clone(), value.clone());
+                store.set(key, value)
+            }
+        };
+        // the operation interrupted by the crash may or may not be recovered.
+        states.push(model.clone());
+        if res.is_err() {
+            break;
+        }
+    }
+    states
+}
+
+// Should recover a state the store went through after crashing at any point
+#[test]
+fn recover_after_crash() -> Result<()> {
+    for seed in 0..300 {
+        let fs = FaultFs::new(seed);
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut model = Model::new();
+        for round in 0..3 {
+            fs.crash_after(rng.gen_range(0, 300));
+            let states = run_workload(&fs, &mut rng, model);
+            fs.recover();
+
+            let store = KvStore::open_with_options("/kvs", options(&fs))?;
+            model = dump(&store)?;
+            assert!(
+                states.contains(&model),
+                "seed {} round {}: recovered {:?}, expected one of {:?}",
+                seed,
+                round,
+                model,
+                states
+            );
+        }
+    }
+    Ok(())
+}
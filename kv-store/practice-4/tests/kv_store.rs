@@ -1,4 +1,6 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{Compression, IndexSpec, Keyring, KvStore, KvStoreOptions, KvsEngine, KvsError, Result};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -81,6 +83,485 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should return pairs in key order starting from the given key
+#[test]
+fn scan_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key4".to_owned())?;
+
+    let pairs = store.scan("key3".to_owned(), 3)?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key5".to_owned(), "value5".to_owned()),
+            ("key6".to_owned(), "value6".to_owned()),
+        ]
+    );
+    assert!(store.scan("key9x".to_owned(), 10)?.is_empty());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("key".to_owned(), 100)?.len(), 9);
+
+    Ok(())
+}
+
+// Should read logs mixing plain, legacy and compressed records
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a log written before records had a header
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"legacy1","value":"value1"}}{"Set":{"key":"legacy2","value":"value2"}}{"Remove":{"key":"legacy2"}}"#,
+    )?;
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    let large_value = |i: usize| format!("{} {}", i, "lorem ipsum ".repeat(1000));
+    let options = KvStoreOptions {
+        compression: Compression::Lz4,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in 0..10 {
+        store.set(format!("large{}", i), large_value(i))?;
+    }
+    assert!(dir_size() < 10 * 12_000 / 4);
+    drop(store);
+
+    // switch to zstd and recompress everything
+    let options = KvStoreOptions {
+        compression: Compression::Zstd(3),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("large10".to_owned(), large_value(10))?;
+    store.compact()?;
+    assert_eq!(store.get("large0".to_owned())?, Some(large_value(0)));
+    drop(store);
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("legacy1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("legacy2".to_owned())?, None);
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for i in 0..=10 {
+        assert_eq!(store.get(format!("large{}", i))?, Some(large_value(i)));
+    }
+
+    Ok(())
+}
+
+// Should encrypt the logs and detect modified records
+#[test]
+fn encrypted_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key_file = key_dir.path().join("keys");
+    fs::write(&key_file, format!("# test keys\n1 {}\n", "11".repeat(32)))?;
+    let options = || -> Result<KvStoreOptions> {
+        Ok(KvStoreOptions {
+            keyring: Keyring::load(&key_file)?,
+            ..KvStoreOptions::default()
+        })
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options()?)?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), "secret2".to_owned())?;
+    drop(store);
+    let log = fs::read(temp_dir.path().join("1.log"))?;
+    assert!(!String::from_utf8_lossy(&log).contains("secret"));
+
+    // the key is needed to open the store
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::UnknownKey(1))));
+
+    // rotate to a new key
+    fs::write(
+        &key_file,
+        format!("1 {}\n2 {}\n", "11".repeat(32), "22".repeat(32)),
+    )?;
+    let store = KvStore::open_with_options(temp_dir.path(), options()?)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    store.compact()?;
+    drop(store);
+    fs::write(&key_file, format!("2 {}\n", "22".repeat(32)))?;
+    let store = KvStore::open_with_options(temp_dir.path(), options()?)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("secret2".to_owned()));
+
+    // flip the last byte of the tag of the last record
+    store.set("key3".to_owned(), "secret3".to_owned())?;
+    let last_log = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_owned())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max_by_key(|path| {
+            let gen = path.file_stem().unwrap().to_str().unwrap();
+            gen.parse::<u64>().unwrap()
+        })
+        .unwrap();
+    let mut log = fs::read(&last_log)?;
+    *log.last_mut().unwrap() ^= 1;
+    fs::write(&last_log, log)?;
+    assert!(matches!(
+        store.get("key3".to_owned()),
+        Err(KvsError::IntegrityCheckFailed(_))
+    ));
+    drop(store);
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options()?),
+        Err(KvsError::IntegrityCheckFailed(_))
+    ));
+
+    Ok(())
+}
+
+// Should refuse unencrypted files and records moved within an encrypted store
+#[test]
+fn encrypted_store_integrity() -> Result<()> {
+    let options = || -> Result<KvStoreOptions> {
+        let mut keyring = Keyring::new();
+        keyring.add(1, [0x11; 32])?;
+        Ok(KvStoreOptions {
+            keyring,
+            ..KvStoreOptions::default()
+        })
+    };
+
+    // a generation written without a key
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "plain".to_owned())?;
+    drop(store);
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options()?),
+        Err(KvsError::IntegrityCheckFailed(_))
+    ));
+
+    // a generation written before headers existed
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key","value":"plain"}}"#,
+    )?;
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options()?),
+        Err(KvsError::IntegrityCheckFailed(_))
+    ));
+
+    // two records of the same length swapped in place
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options()?)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let len = (log.len() - 8) / 2;
+    let (first, second) = log[8..].split_at_mut(len);
+    first.swap_with_slice(second);
+    fs::write(&log_path, log)?;
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options()?),
+        Err(KvsError::IntegrityCheckFailed(_))
+    ));
+
+    Ok(())
+}
+
+// Should keep large values out of the log and collect overwritten ones
+#[test]
+fn large_values_in_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let files_size = |extension: &str| {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let options = || KvStoreOptions {
+        blob_threshold: Some(1024),
+        blob_file_size: 64 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let large_value = |i: usize, round: usize| format!("{}-{}-{}", i, round, "x".repeat(8 * 1024));
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for round in 0..20 {
+        for i in 0..20 {
+            store.set(format!("large{}", i), large_value(i, round))?;
+        }
+    }
+    store.remove("large0".to_owned())?;
+
+    // the log only holds references to the values
+    assert!(files_size("log") < 20 * 20 * 1024);
+    // the overwritten values are collected
+    assert!(files_size("blob") < 4 * 20 * 8 * 1024);
+    assert_eq!(store.get("large0".to_owned())?, None);
+    assert_eq!(store.get("large1".to_owned())?, Some(large_value(1, 19)));
+    // 19 large values and "small"
+    assert_eq!(store.scan("large".to_owned(), 100)?.len(), 20);
+
+    store.compact()?;
+    assert_eq!(store.get("large2".to_owned())?, Some(large_value(2, 19)));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for i in 1..20 {
+        assert_eq!(store.get(format!("large{}", i))?, Some(large_value(i, 19)));
+    }
+    // values stay readable once blob files are disabled
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large3".to_owned())?, Some(large_value(3, 19)));
+
+    Ok(())
+}
+
+// Should keep counting the garbage of blob files whose records were compacted
+#[test]
+fn blob_garbage_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        blob_threshold: Some(1024),
+        blob_file_size: 8 * 1024,
+        blob_gc_ratio: 0.4,
+        ..KvStoreOptions::default()
+    };
+    let large_value = |i: usize, round: usize| format!("{}-{}-{}", i, round, "x".repeat(2 * 1024));
+
+    // the first blob file holds four values.
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..8 {
+        store.set(format!("large{}", i), large_value(i, 0))?;
+    }
+    // a quarter of it is garbage, not enough to collect it.
+    store.set("large0".to_owned(), large_value(0, 1))?;
+    store.compact()?;
+    assert!(temp_dir.path().join("1.blob").exists());
+
+    // the overwritten value is not in the log anymore, but still garbage.
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("large1".to_owned(), large_value(1, 1))?;
+    assert!(!temp_dir.path().join("1.blob").exists());
+    for i in 0..8 {
+        let round = if i < 2 { 1 } else { 0 };
+        assert_eq!(store.get(format!("large{}", i))?, Some(large_value(i, round)));
+    }
+    Ok(())
+}
+
+// Should read large values while blob collections move them to new files
+#[test]
+fn concurrent_get_during_blob_gc() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        blob_file_size: 16 * 1024,
+        blob_gc_ratio: 0.4,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let large_value = |i: usize, round: usize| format!("{}-{}-{}", i, round, "x".repeat(2 * 1024));
+    // the values which are never overwritten share their blob files with
+    // values which are, so they are moved when the files are collected.
+    for i in 0..20 {
+        store.set(format!("stable{}", i), large_value(i, 0))?;
+        store.set(format!("churn{}", i), large_value(i, 0))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        let handle = thread::spawn(move || {
+            let mut i = thread_id;
+            while !done.load(Ordering::SeqCst) {
+                let key_id = i % 20;
+                assert_eq!(
+                    store.get(format!("stable{}", key_id)).unwrap(),
+                    Some(large_value(key_id, 0))
+                );
+                i += 1;
+            }
+        });
+        handles.push(handle);
+    }
+
+    for round in 1..20 {
+        for i in 0..20 {
+            store.set(format!("churn{}", i), large_value(i, round))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // the first blob files were collected.
+    assert!(!temp_dir.path().join("1.blob").exists());
+    Ok(())
+}
+
+// Should find values by the fields of their JSON documents
+#[test]
+fn secondary_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |indexes: &[(&str, &str)]| KvStoreOptions {
+        indexes: indexes
+            .iter()
+            .map(|(name, pointer)| IndexSpec::new(*name, *pointer))
+            .collect(),
+        ..KvStoreOptions::default()
+    };
+    let user = |city: &str, age: u32| format!(r#"{{"address":{{"city":"{}"}},"age":{}}}"#, city, age);
+    let keys = |pairs: Vec<(String, String)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+    let store = KvStore::open_with_options(temp_dir.path(), options(&[("by_city", "/address/city")]))?;
+    store.set("alice".to_owned(), user("Paris", 30))?;
+    store.set("bob".to_owned(), user("Lyon", 25))?;
+    store.set("carol".to_owned(), user("Paris", 41))?;
+    store.set("dave".to_owned(), "not json".to_owned())?;
+    store.set("erin".to_owned(), r#"{"age":30}"#.to_owned())?;
+
+    let pairs = store.index_lookup("by_city".to_owned(), "Paris".to_owned(), 10)?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("alice".to_owned(), user("Paris", 30)),
+            ("carol".to_owned(), user("Paris", 41)),
+        ]
+    );
+    assert_eq!(keys(store.index_lookup("by_city".to_owned(), "\"Paris\"".to_owned(), 1)?), ["alice"]);
+    assert!(store.index_lookup("by_city".to_owned(), "Rome".to_owned(), 10)?.is_empty());
+
+    // overwritten and removed keys leave the index
+    store.set("alice".to_owned(), user("Lyon", 31))?;
+    store.remove("carol".to_owned())?;
+    assert!(store.index_lookup("by_city".to_owned(), "Paris".to_owned(), 10)?.is_empty());
+    assert_eq!(keys(store.index_lookup("by_city".to_owned(), "Lyon".to_owned(), 10)?), ["alice", "bob"]);
+
+    match store.index_lookup("by_age".to_owned(), "30".to_owned(), 10) {
+        Err(KvsError::IndexNotFound(name)) => assert_eq!(name, "by_age"),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // the indexes are rebuilt from the log, and a new index from the values
+    drop(store);
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        options(&[("by_city", "/address/city"), ("by_age", "/age")]),
+    )?;
+    assert_eq!(keys(store.index_lookup("by_city".to_owned(), "Lyon".to_owned(), 10)?), ["alice", "bob"]);
+    assert_eq!(keys(store.index_lookup("by_age".to_owned(), "30".to_owned(), 10)?), ["erin"]);
+    assert!(store.index_lookup("by_age".to_owned(), "\"30\"".to_owned(), 10)?.is_empty());
+
+    store.compact()?;
+    assert_eq!(keys(store.index_lookup("by_age".to_owned(), "25".to_owned(), 10)?), ["bob"]);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options(&[("by_age", "/age")]))?;
+    assert_eq!(keys(store.index_lookup("by_age".to_owned(), "31".to_owned(), 10)?), ["alice"]);
+    assert!(store.index_lookup("by_city".to_owned(), "Lyon".to_owned(), 10).is_err());
+
+    // invalid declarations are rejected
+    drop(store);
+    assert!(KvStore::open_with_options(temp_dir.path(), options(&[("by_age", "age")])).is_err());
+    assert!(KvStore::open_with_options(temp_dir.path(), options(&[("a", "/x"), ("a", "/y")])).is_err());
+
+    Ok(())
+}
+
+// Should not open a directory used by another store
+#[test]
+fn lock_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked(_))));
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::Locked(_))
+    ));
+
+    // The lock is held until the last clone is dropped
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked(_))));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should read but never write a store opened read-only
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let logs = log_count();
+
+    // Any number of read-only stores can be opened together
+    let first = KvStore::open_read_only(temp_dir.path())?;
+    let second = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(first.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(second.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(first.scan("key".to_owned(), 10)?.len(), 2);
+
+    assert!(matches!(
+        first.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        second.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked(_))));
+    assert_eq!(log_count(), logs);
+
+    drop(first);
+    drop(second);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -132,15 +613,17 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
         });
+        handles.push(handle);
     }
     barrier.wait();
 
@@ -148,8 +631,12 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data.
+    // The threads must be gone so that their clones release the lock.
     drop(store);
+    for handle in handles {
+        handle.join().unwrap();
+    }
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -209,3 +696,49 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should read the values while compactions move them to new generations
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let done = Arc::clone(&done);
+        let handle = thread::spawn(move || {
+            let mut i = thread_id;
+            while !done.load(Ordering::SeqCst) {
+                let key_id = i % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+                i += 1;
+            }
+        });
+        handles.push(handle);
+    }
+
+    // overwriting the values with the same ones triggers compactions.
+    for _ in 0..50 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+    }
+    store.compact()?;
+    done.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsEngine, KvsServer, Protocol, ServerLimits, SledKvsEngine};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Start a RESP server backed by the given engine.
+fn start_server<E: KvsEngine>(engine: E, addr: &'static str) {
+    start_server_with_limits(engine, addr, ServerLimits::default());
+}
+
+fn start_server_with_limits<E: KvsEngine>(engine: E, addr: &'static str, limits: ServerLimits) {
+    thread::spawn(move || {
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::with_limits(engine, pool, limits).with_protocol(Protocol::Resp);
+        server.run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+}
+
+struct RespConn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespConn {
+    fn connect(addr: &str) -> RespConn {
+        let tcp = TcpStream::connect(addr).unwrap();
+        RespConn {
+            reader: BufReader::new(tcp.try_clone().unwrap()),
+            writer: tcp,
+        }
+    }
+
+    // Send a command as an array of bulk strings and return the raw reply.
+    fn call(&mut self, args: &[&str]) -> String {
+        let mut cmd = format!("*{}\r\n", args.len());
+        for arg in args {
+            cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(cmd.as_bytes()).unwrap();
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let mut reply = line.clone();
+        match line.as_bytes()[0] {
+            b'$' => {
+                let len: i64 = line[1..].trim_end().parse().unwrap();
+                if len >= 0 {
+                    let mut buf = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut buf).unwrap();
+                    reply.push_str(&String::from_utf8(buf).unwrap());
+                }
+            }
+            b'*' => {
+                let len: i64 = line[1..].trim_end().parse().unwrap();
+                for _ in 0..len {
+                    reply.push_str(&self.read_reply());
+                }
+            }
+            _ => {}
+        }
+        reply
+    }
+}
+
+fn redis_commands(addr: &str) {
+    let mut conn = RespConn::connect(addr);
+    assert_eq!(conn.call(&["PING"]), "+PONG\r\n");
+    assert_eq!(conn.call(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(conn.call(&["get", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(conn.call(&["GET", "missing"]), "$-1\r\n");
+    assert_eq!(conn.call(&["MSET", "key2", "value2", "key3", "value3"]), "+OK\r\n");
+    assert_eq!(
+        conn.call(&["MGET", "key1", "missing", "key3"]),
+        "*3\r\n$6\r\nvalue1\r\n$-1\r\n$6\r\nvalue3\r\n"
+    );
+    assert_eq!(conn.call(&["EXISTS", "key1", "key2", "missing"]), ":2\r\n");
+    assert_eq!(conn.call(&["DEL", "key2", "missing"]), ":1\r\n");
+    assert_eq!(conn.call(&["EXISTS", "key2"]), ":0\r\n");
+    assert!(conn.call(&["GET"]).starts_with("-ERR wrong number of arguments"));
+    assert!(conn.call(&["FLUSHALL"]).starts_with("-ERR unknown command"));
+
+    // inline commands
+    conn.writer.write_all(b"PING hello\r\n").unwrap();
+    assert_eq!(conn.read_reply(), "$5\r\nhello\r\n");
+
+    assert_eq!(conn.call(&["QUIT"]), "+OK\r\n");
+}
+
+fn redis_scan(addr: &str) {
+    let mut conn = RespConn::connect(addr);
+    for i in 0..25 {
+        let key = format!("scan{:02}", i);
+        assert_eq!(conn.call(&["SET", &key, "value"]), "+OK\r\n");
+    }
+    conn.call(&["SET", "other", "value"]);
+    conn.call(&["SET", "scan", "value"]);
+
+    let mut keys = 0;
+    let mut cursor = "0".to_owned();
+    loop {
+        let reply = conn.call(&["SCAN", &cursor, "MATCH", "scan*", "COUNT", "10"]);
+        let lines: Vec<&str> = reply.split("\r\n").collect();
+        // *2, $len, cursor, *n, then two lines per key
+        cursor = lines[2].to_owned();
+        keys += lines[3][1..].parse::<usize>().unwrap();
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys, 26);
+}
+
+#[test]
+fn resp_kvs_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(KvStore::open(temp_dir.path()).unwrap(), "127.0.0.1:4201");
+    redis_commands("127.0.0.1:4201");
+    redis_scan("127.0.0.1:4201");
+}
+
+#[test]
+fn resp_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(
+        SledKvsEngine::new(sled::open(temp_dir.path()).unwrap()),
+        "127.0.0.1:4202",
+    );
+    redis_commands("127.0.0.1:4202");
+    redis_scan("127.0.0.1:4202");
+}
+
+#[test]
+fn resp_protocol_error() {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(KvStore::open(temp_dir.path()).unwrap(), "127.0.0.1:4203");
+    let mut conn = RespConn::connect("127.0.0.1:4203");
+    conn.writer.write_all(b"*1\r\n+PING\r\n").unwrap();
+    assert!(conn.read_reply().starts_with("-ERR Protocol error"));
+
+    // a null bulk string is not an argument.
+    let mut conn = RespConn::connect("127.0.0.1:4203");
+    conn.writer.write_all(b"*1\r\n$-1\r\n").unwrap();
+    assert_eq!(conn.read_reply(), "-ERR Protocol error: invalid bulk length\r\n");
+
+    let mut conn = RespConn::connect("127.0.0.1:4203");
+    conn.writer.write_all(b"*100000000\r\n$0\r\n\r\n").unwrap();
+    assert_eq!(conn.read_reply(), "-ERR Protocol error: invalid multibulk length\r\n");
+
+    // a command with an argument which is not UTF-8 fails, but the
+    // connection goes on.
+    let mut conn = RespConn::connect("127.0.0.1:4203");
+    conn.writer.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\n\xff\xfe\r\n").unwrap();
+    assert_eq!(conn.read_reply(), "-ERR invalid argument\r\n");
+    assert_eq!(conn.call(&["GET", "key"]), "$-1\r\n");
+    assert_eq!(conn.call(&["PING"]), "+PONG\r\n");
+}
+
+#[test]
+fn resp_frame_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let limits = ServerLimits {
+        max_frame_size: 1024,
+        ..ServerLimits::default()
+    };
+    start_server_with_limits(KvStore::open(temp_dir.path()).unwrap(), "127.0.0.1:4205", limits);
+
+    // empty arguments are charged for their header and terminators.
+    let mut conn = RespConn::connect("127.0.0.1:4205");
+    let mut cmd = b"*200\r\n".to_vec();
+    for _ in 0..200 {
+        cmd.extend_from_slice(b"$0\r\n\r\n");
+    }
+    conn.writer.write_all(&cmd).unwrap();
+    assert!(conn.read_reply().starts_with("-ERR command exceeds the limit of 1024 bytes"));
+
+    let mut conn = RespConn::connect("127.0.0.1:4205");
+    assert_eq!(conn.call(&["SET", "key", &"v".repeat(900)]), "+OK\r\n");
+    assert!(conn.call(&["SET", "key", &"v".repeat(1024)]).starts_with("-ERR command exceeds"));
+}
+
+#[test]
+fn resp_scan_pathological_pattern() {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(KvStore::open(temp_dir.path()).unwrap(), "127.0.0.1:4204");
+    let mut conn = RespConn::connect("127.0.0.1:4204");
+    let long = "a".repeat(200);
+    assert_eq!(conn.call(&["SET", &long, "value"]), "+OK\r\n");
+    assert_eq!(conn.call(&["SET", &format!("{}b", long), "value"]), "+OK\r\n");
+
+    // backtracking into every star would take forever.
+    let start = Instant::now();
+    let stars = "*a".repeat(20);
+    for (pattern, matches) in [(stars.clone(), 1), (stars.clone() + "*b", 1), (stars + "*c", 0)] {
+        let reply = conn.call(&["SCAN", "0", "MATCH", &pattern, "COUNT", "10"]);
+        assert!(reply.starts_with(&format!("*2\r\n$1\r\n0\r\n*{}\r\n", matches)));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let reply = conn.call(&["SCAN", "0", "MATCH", "[a-b]?\\a*[^a]", "COUNT", "10"]);
+    assert!(reply.starts_with("*2\r\n$1\r\n0\r\n*1\r\n"));
+}
@@ -0,0 +1,185 @@
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsClient, KvsError, KvsServer, ServerLimits};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start a server backed by a `KvStore` in a temporary directory.
+fn start_server(addr: &'static str, limits: ServerLimits) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::with_limits(store, pool, limits);
+        server.run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn write_raw_frame(tcp: &mut TcpStream, payload: &[u8]) {
+    tcp.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+    tcp.write_all(payload).unwrap();
+}
+
+fn read_raw_frame(tcp: &mut TcpStream) -> Value {
+    let mut len = [0; 4];
+    tcp.read_exact(&mut len).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    tcp.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+#[test]
+fn oversized_key_and_value() {
+    let limits = ServerLimits {
+        max_key_size: 8,
+        max_value_size: 16,
+        ..ServerLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4101", limits);
+    let mut client = KvsClient::connect("127.0.0.1:4101").unwrap();
+
+    let err = client.set("k".repeat(9), "value".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::LimitExceeded(_)));
+    let err = client.set("key".to_owned(), "v".repeat(17)).unwrap_err();
+    assert!(matches!(err, KvsError::LimitExceeded(_)));
+    assert!(client.get("k".repeat(9)).is_err());
+
+    // the connection is still usable
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn oversized_frame() {
+    let limits = ServerLimits {
+        max_frame_size: 64,
+        ..ServerLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4102", limits);
+    let mut client = KvsClient::connect("127.0.0.1:4102").unwrap();
+
+    let err = client.set("key".to_owned(), "v".repeat(100)).unwrap_err();
+    assert!(matches!(err, KvsError::LimitExceeded(_)));
+    assert!(err.to_string().contains("exceeds the limit of 64 bytes"));
+
+    // the oversized frame is skipped and the next one is served
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn invalid_request() {
+    let _dir = start_server("127.0.0.1:4103", ServerLimits::default());
+    let mut tcp = TcpStream::connect("127.0.0.1:4103").unwrap();
+
+    write_raw_frame(&mut tcp, b"not a request");
+    let resp = read_raw_frame(&mut tcp);
+    assert!(resp["Err"]["InvalidRequest"].is_string());
+
+    let req = json!({ "Get": { "key": "key" } });
+    write_raw_frame(&mut tcp, req.to_string().as_bytes());
+    assert_eq!(read_raw_frame(&mut tcp), json!({ "Get": null }));
+}
+
+#[test]
+fn too_many_connections() {
+    let limits = ServerLimits {
+        max_connections: 1,
+        ..ServerLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4104", limits);
+
+    let mut first = KvsClient::connect("127.0.0.1:4104").unwrap();
+    first.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    let mut second = KvsClient::connect("127.0.0.1:4104").unwrap();
+    let err = second.get("key".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::Busy(_)));
+    assert!(err.to_string().contains("too many connections"));
+
+    // the slot is released once the first client leaves
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut third = KvsClient::connect("127.0.0.1:4104").unwrap();
+    assert_eq!(third.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn idle_timeout() {
+    let limits = ServerLimits {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..ServerLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4105", limits);
+    let mut client = KvsClient::connect("127.0.0.1:4105").unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+    let err = client.get("key".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::Timeout(_)));
+}
+
+#[test]
+fn slow_request_timeout() {
+    let limits = ServerLimits {
+        idle_timeout: Some(Duration::from_millis(250)),
+        ..ServerLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4108", limits);
+    let mut tcp = TcpStream::connect("127.0.0.1:4108").unwrap();
+
+    // each byte comes well within the timeout, but the request does not.
+    let req = json!({ "Get": { "key": "key" } }).to_string();
+    tcp.write_all(&(req.len() as u32).to_be_bytes()).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut answered = false;
+    for &byte in req.as_bytes() {
+        tcp.write_all(&[byte]).unwrap();
+        if tcp.peek(&mut [0]).is_ok() {
+            answered = true;
+            break;
+        }
+    }
+    assert!(answered);
+    tcp.set_read_timeout(None).unwrap();
+    let resp = read_raw_frame(&mut tcp);
+    assert!(resp["Err"]["Timeout"].is_string());
+}
+
+#[test]
+fn remote_key_not_found() {
+    let _dir = start_server("127.0.0.1:4107", ServerLimits::default());
+    let mut client = KvsClient::connect("127.0.0.1:4107").unwrap();
+
+    let err = client.remove("key".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::KeyNotFound));
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    client.remove("key".to_owned()).unwrap();
+}
+
+#[test]
+fn pipelined_requests_in_flight() {
+    let limits = ServerLimits {
+        max_in_flight: 1,
+        ..ServerLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4106", limits);
+    let mut tcp = TcpStream::connect("127.0.0.1:4106").unwrap();
+
+    let req = json!({ "Set": { "key": "key", "value": "value" } }).to_string();
+    for _ in 0..100 {
+        write_raw_frame(&mut tcp, req.as_bytes());
+    }
+    // every request is answered in order, either executed or rejected
+    for _ in 0..100 {
+        let resp = read_raw_frame(&mut tcp);
+        if resp != json!("Set") {
+            assert!(resp["Err"]["Busy"].as_str().unwrap().contains("in flight"));
+        }
+    }
+}